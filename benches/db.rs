@@ -0,0 +1,166 @@
+//! Criterion benchmarks for the `Db` public API.
+//! 
+//! Run with `cargo bench`. Each benchmark includes a `default` case using
+//! `Options::default()`, the baseline to compare other modes and later
+//! changes against.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use docdb::db::{Db, GroupCommit, Options};
+use std::hint::black_box;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Number of records preloaded for the get, open, and compaction benchmarks.
+const RECORDS: usize = 10_000;
+
+/// A small JSON document, the typical value size.
+const VALUE: &[u8] = br#"{"name": "Alice", "age": 30, "email": "alice@example.com"}"#;
+
+/// Write modes compared by the put benchmark.
+fn write_modes() -> Vec<(&'static str, Options)> {
+    vec![
+        ("default", Options::default()),
+        (
+            "defer_flush",
+            Options {
+                defer_flush: true,
+                ..Options::default()
+            },
+        ),
+        (
+            "group_commit_64",
+            Options {
+                group_commit: Some(GroupCommit {
+                    max_writes: 64,
+                    max_delay: Duration::from_millis(10),
+                }),
+                ..Options::default()
+            },
+        ),
+        (
+            "fsync_each_write",
+            Options {
+                group_commit: Some(GroupCommit {
+                    max_writes: 1,
+                    max_delay: Duration::ZERO,
+                }),
+                ..Options::default()
+            },
+        ),
+    ]
+}
+
+/// Writes `RECORDS` documents to a new database at `dir`.
+fn populate(dir: &Path) {
+    let mut db = Db::open(dir).unwrap();
+    for i in 0..RECORDS {
+        db.put(&format!("key{:06}", i), VALUE).unwrap();
+    }
+    db.close().unwrap();
+}
+
+fn bench_put(c: &mut Criterion) {
+    let mut group = c.benchmark_group("put");
+    group.throughput(Throughput::Elements(1));
+    for (name, options) in write_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        let mut i = 0u64;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                i += 1;
+                db.put(&format!("key{}", i), black_box(VALUE)).unwrap();
+            })
+        });
+        db.close().unwrap();
+    }
+    group.finish();
+}
+
+fn bench_get(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    populate(temp_dir.path());
+    
+    let mut group = c.benchmark_group("get");
+    let modes = [
+        ("default", Options::default()),
+        (
+            "values_on_disk",
+            Options {
+                values_on_disk: true,
+                ..Options::default()
+            },
+        ),
+        (
+            "values_on_disk_cached",
+            Options {
+                values_on_disk: true,
+                value_cache_bytes: Some(64 << 20),
+                ..Options::default()
+            },
+        ),
+    ];
+    for (name, options) in modes {
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        let keys: Vec<String> = (0..RECORDS).map(|i| format!("key{:06}", i)).collect();
+        let mut i = 0;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                i = (i + 7919) % RECORDS;
+                black_box(db.get(&keys[i]).unwrap());
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_open(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    populate(temp_dir.path());
+    
+    let mut group = c.benchmark_group("open");
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    group.bench_function("default", |b| {
+        b.iter(|| Db::open(temp_dir.path()).unwrap())
+    });
+    group.bench_function("values_on_disk", |b| {
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        b.iter(|| Db::open_with_options(temp_dir.path(), options.clone()).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_compaction(c: &mut Criterion) {
+    // Overwrite every key once, so half the log is dead records
+    let temp_dir = TempDir::new().unwrap();
+    populate(temp_dir.path());
+    let mut db = Db::open(temp_dir.path()).unwrap();
+    for i in 0..RECORDS {
+        db.put(&format!("key{:06}", i), VALUE).unwrap();
+    }
+    
+    // Db has no compact method yet; snapshotting and restoring into a fresh
+    // log does the same work of rewriting only the live entries
+    let mut group = c.benchmark_group("compaction");
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    group.bench_function("default", |b| {
+        b.iter_batched(
+            || TempDir::new().unwrap(),
+            |target| {
+                let mut snapshot = Vec::new();
+                db.snapshot_to_writer(&mut snapshot).unwrap();
+                Db::restore_from_reader(target.path(), &mut snapshot.as_slice()).unwrap();
+                target
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_put, bench_get, bench_open, bench_compaction);
+criterion_main!(benches);
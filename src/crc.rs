@@ -0,0 +1,71 @@
+//! CRC-32 (IEEE 802.3) checksums.
+//! 
+//! Used to verify that bytes written to disk read back unchanged. The
+//! implementation is the standard reflected polynomial 0xEDB88320, so
+//! results match zlib's `crc32`.
+
+/// The IEEE polynomial, bit-reversed for the reflected algorithm.
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Computes the CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// A CRC-32 computed incrementally, for data read in pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    /// Starts a checksum of no data.
+    pub fn new() -> Self {
+        Crc32 { state: 0xFFFF_FFFF }
+    }
+
+    /// Adds `data` to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.state & 1).wrapping_neg();
+                self.state = (self.state >> 1) ^ (POLYNOMIAL & mask);
+            }
+        }
+    }
+
+    /// Returns the CRC-32 of all data added so far.
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414F_A339);
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), crc32(b"123456789"));
+    }
+}
@@ -1,7 +1,891 @@
-use crate::log::{Log, LogRecord};
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use crate::crc::{crc32, Crc32};
+use crate::format::FormatOptions;
+use crate::fsutil::{atomic_write, available_space, check_free_space, sync_dir, temp_path};
+use crate::glob::Glob;
+use crate::log::{Log, LogRecord, RecordSpan, RecoveryReport};
+use crate::metrics::{Counters, Metrics};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Bound, Range};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Magic bytes opening every snapshot written by `Db::snapshot_to_writer`.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DOCDBSNP";
+
+/// Version of the snapshot format.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Number of digits in keys assigned by `Db::push`, enough for any `u64`.
+const PUSH_KEY_WIDTH: usize = 20;
+
+/// Content type of values put without one.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// Subdirectory of a database directory holding external values, one file
+/// per blob named by its id.
+const BLOB_DIR: &str = "blobs";
+
+/// Name of the file recording the log's length and CRC-32 at the last clean
+/// close, under `Options::log_checksum`.
+const LOG_CHECKSUM_FILE: &str = "log.checksum";
+
+/// Longest key, in characters, that a `WriteError` quotes in full.
+const MAX_ERROR_KEY_CHARS: usize = 64;
+
+/// Minimum number of bytes read between calls to a `RecoveryProgress`.
+const PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// Number of records replayed between checks of `Options::open_deadline`.
+const DEADLINE_CHECK_INTERVAL: u32 = 256;
+
+/// Maximum number of log bytes read at a time during replay.
+const REPLAY_CHUNK_BYTES: usize = 1 << 20;
+
+/// What a `Db` does when dropped with writes that were never flushed.
+/// 
+/// Only relevant with `Options::defer_flush`. The drop always attempts a
+/// final flush first; the policy decides how loudly to report it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnflushedDropPolicy {
+    /// Flush silently.
+    Ignore,
+    /// Flush and pass a warning to `Options::warning_handler`, or print it
+    /// to stderr if none is set.
+    #[default]
+    Warn,
+    /// Flush and panic, to catch missing `flush`/`close` calls in tests.
+    Panic,
+}
+
+/// What `Db::open` does when the log doesn't match the checksum recorded at
+/// its last clean close, under `Options::log_checksum`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogChecksumPolicy {
+    /// Neither record nor check a checksum.
+    #[default]
+    Off,
+    /// Open anyway, setting `OpenReport::log_checksum_mismatch` for the
+    /// caller to report.
+    Warn,
+    /// Fail the open with `ErrorKind::InvalidData`.
+    Error,
+}
+
+/// When to fsync the log under `Options::group_commit`.
+/// 
+/// The log is fsynced once `max_writes` writes are waiting, or when a write
+/// arrives and the oldest waiting one is at least `max_delay` old, whichever
+/// comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    /// Number of writes one fsync may cover.
+    pub max_writes: usize,
+    /// How long a write may wait for an fsync, checked on the next write.
+    pub max_delay: Duration,
+}
+
+/// An application-supplied closure held in `Options`, shared by its clones.
+/// 
+/// `F` is the closure's `dyn Fn` type; `Validator`, `KeyNormalizer`,
+/// `RecoveryProgress`, `WarningHandler`, and `ConflictResolver` name the
+/// ones `Options` takes, each with a `new` and a method calling it.
+pub struct Callback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Callback(Arc::clone(&self.0))
+    }
+}
+
+impl<F: ?Sized> std::fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback(..)")
+    }
+}
+
+/// An application-supplied check run on every key or value that is put.
+/// 
+/// Returning `Err` rejects the write; the message is reported to the caller.
+pub type Validator<T> = Callback<ValidatorFn<T>>;
+
+/// The closure type wrapped by `Validator`.
+type ValidatorFn<T> = dyn Fn(&T) -> Result<(), String> + Send + Sync;
+
+impl<T: ?Sized> Validator<T> {
+    /// Wraps a validation closure.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&T) -> Result<(), String> + Send + Sync + 'static,
+    {
+        Callback(Arc::new(f))
+    }
+
+    /// Runs the check against `input`.
+    pub fn validate(&self, input: &T) -> Result<(), String> {
+        (self.0)(input)
+    }
+}
+
+/// An application-supplied mapping from the keys callers pass to the keys
+/// stored, such as lowercasing for case-insensitive keys.
+pub type KeyNormalizer = Callback<KeyNormalizerFn>;
+
+/// The closure type wrapped by `KeyNormalizer`.
+type KeyNormalizerFn = dyn Fn(&str) -> String + Send + Sync;
+
+impl KeyNormalizer {
+    /// Wraps a normalization closure.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Callback(Arc::new(f))
+    }
+
+    /// Returns the normalized form of `key`.
+    pub fn normalize(&self, key: &str) -> String {
+        (self.0)(key)
+    }
+}
+
+/// An application-supplied callback reporting log replay progress.
+/// 
+/// Called as `(bytes_read, total_bytes)`, where `total_bytes` is the size
+/// of the log when replay started.
+pub type RecoveryProgress = Callback<RecoveryProgressFn>;
+
+/// The closure type wrapped by `RecoveryProgress`.
+type RecoveryProgressFn = dyn Fn(u64, u64) + Send + Sync;
+
+impl RecoveryProgress {
+    /// Wraps a progress closure.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        Callback(Arc::new(f))
+    }
+
+    /// Reports that `bytes_read` of `total_bytes` have been read.
+    pub fn report(&self, bytes_read: u64, total_bytes: u64) {
+        (self.0)(bytes_read, total_bytes)
+    }
+}
+
+/// An application-supplied callback receiving warnings the database would
+/// otherwise print to stderr, such as a `UnflushedDropPolicy::Warn` report.
+pub type WarningHandler = Callback<WarningHandlerFn>;
+
+/// The closure type wrapped by `WarningHandler`.
+type WarningHandlerFn = dyn Fn(&str) + Send + Sync;
+
+impl WarningHandler {
+    /// Wraps a warning closure.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        Callback(Arc::new(f))
+    }
+
+    /// Passes `message` to the closure.
+    pub fn warn(&self, message: &str) {
+        (self.0)(message)
+    }
+}
+
+/// Writes one value to a sink for `Db::stream_values`, delimiting it so a
+/// reader can tell where it ends.
+/// 
+/// `newline_frame` and `length_prefixed_frame` cover the common cases.
+pub type FrameFn = fn(&mut dyn Write, &[u8]) -> std::io::Result<()>;
+
+/// Frames a value by following it with a newline, for values that contain
+/// none, such as compact JSON.
+pub fn newline_frame(writer: &mut dyn Write, value: &[u8]) -> std::io::Result<()> {
+    writer.write_all(value)?;
+    writer.write_all(b"\n")
+}
+
+/// Frames a value by preceding it with its length as a u32, little-endian,
+/// as the log frames its fields.
+pub fn length_prefixed_frame(writer: &mut dyn Write, value: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(value.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Value of {} bytes is too long to frame", value.len()),
+        )
+    })?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(value)
+}
+
+/// Outcome of `Db::lookup`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup<'a> {
+    /// The key is live, with this value.
+    Present(Cow<'a, [u8]>),
+    /// The key isn't live, and its last write in the log was a delete.
+    Deleted,
+    /// The key isn't live and was never deleted: it was never written.
+    NeverExisted,
+}
+
+/// A single-key write, as named by a `WriteError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOp {
+    /// `put` or one of its variants.
+    Put,
+    /// `delete`.
+    Delete,
+}
+
+impl std::fmt::Display for WriteOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WriteOp::Put => "PUT",
+            WriteOp::Delete => "DELETE",
+        })
+    }
+}
+
+/// Failure to write a put or delete to disk, naming the operation and key.
+/// 
+/// Returned inside an `io::Error` of the underlying error's kind, so
+/// matching on the kind keeps working; `get_ref` and `downcast_ref` recover
+/// it. Displays as `failed to PUT key "abc": <io error>`. Only failures
+/// writing the record (or an external value's file) are wrapped: writes
+/// rejected before anything is written already say why, and a failed fsync
+/// may cover other writes too.
+#[derive(Debug)]
+pub struct WriteError {
+    /// The write that failed.
+    pub op: WriteOp,
+    /// The key written, cut to its first 64 characters followed by `...`
+    /// if longer.
+    pub key: String,
+    /// The underlying error.
+    pub source: std::io::Error,
+}
+
+impl WriteError {
+    /// Wraps `source` with the operation and key, as an `io::Error` of the
+    /// same kind.
+    fn wrap(op: WriteOp, key: &str, source: std::io::Error) -> std::io::Error {
+        let key = match key.char_indices().nth(MAX_ERROR_KEY_CHARS) {
+            Some((cut, _)) => format!("{}...", &key[..cut]),
+            None => key.to_string(),
+        };
+        std::io::Error::new(source.kind(), WriteError { op, key, source })
+    }
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to {} key {:?}: {}", self.op, self.key, self.source)
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// How a `ConflictResolver` settles a key that both sides of a merge hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Keep the existing value, ignoring the incoming one.
+    Keep,
+    /// Replace the existing value with the incoming one.
+    Replace,
+    /// Store this value instead of either.
+    Value(Vec<u8>),
+}
+
+/// An application-supplied policy for keys that a merge would overwrite.
+/// 
+/// Called as `(key, existing, incoming)` with both values.
+pub type ConflictResolver = Callback<ConflictResolverFn>;
+
+/// The closure type wrapped by `ConflictResolver`.
+type ConflictResolverFn = dyn Fn(&str, &[u8], &[u8]) -> Resolution + Send + Sync;
+
+impl ConflictResolver {
+    /// Wraps a resolution closure.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&str, &[u8], &[u8]) -> Resolution + Send + Sync + 'static,
+    {
+        Callback(Arc::new(f))
+    }
+
+    /// Decides what `key` should hold, given its existing and incoming
+    /// values.
+    pub fn resolve(&self, key: &str, existing: &[u8], incoming: &[u8]) -> Resolution {
+        (self.0)(key, existing, incoming)
+    }
+}
+
+/// Configuration for opening a database.
+/// 
+/// `Options::default()` matches the behavior of `Db::open`.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Maximum key length in bytes accepted by `put` and `delete`.
+    /// 
+    /// `None` (the default) means unlimited. Keys over the limit are rejected
+    /// with `ErrorKind::InvalidInput` before anything is written to the log.
+    pub max_key_bytes: Option<usize>,
+    /// Buffer writes instead of flushing the log after every `put`/`delete`.
+    /// 
+    /// Deferred writes are only handed to the OS on `flush`, `close`, or
+    /// drop, so a crash can lose them. Defaults to false.
+    pub defer_flush: bool,
+    /// How to report a deferred-mode `Db` dropped without `flush`/`close`.
+    pub unflushed_drop: UnflushedDropPolicy,
+    /// Where warnings go instead of stderr.
+    /// 
+    /// `None` (the default) prints them to stderr; set this to route them
+    /// into the application's own logging, or to silence them.
+    pub warning_handler: Option<WarningHandler>,
+    /// Record the log's length and CRC-32 in a `log.checksum` file on
+    /// `close` and `shutdown`, and check the log against it on open.
+    /// 
+    /// A quick gate against the log being modified or truncated behind the
+    /// database's back: only the bytes present at the last clean close are
+    /// checked, so records appended since, including a tail torn by a
+    /// crash, don't count as a mismatch. Both the close and the check read
+    /// the whole log. Defaults to `LogChecksumPolicy::Off`.
+    pub log_checksum: LogChecksumPolicy,
+    /// Track the order in which keys were first inserted, enabling
+    /// `Db::iter_by_insertion`. Costs one sequence number per live key.
+    pub track_insertion_order: bool,
+    /// Record when each key was last read, enabling `Db::keys_idle_since`.
+    /// 
+    /// Reads only borrow the `Db`, so the times are kept behind a mutex
+    /// that every successful `get`, `get_versioned`, and `get_arc` locks,
+    /// at the cost of some read throughput and, for concurrent readers,
+    /// contention. Costs a key and a timestamp per key read.
+    pub track_access_time: bool,
+    /// Skip corrupt records during replay instead of failing to open.
+    /// 
+    /// See `Log::read_all_lenient` for how corrupt regions are skipped. What
+    /// was skipped is available from `Db::last_recovery_report`.
+    pub lenient_recovery: bool,
+    /// Keep values on disk, reading them from the log when requested.
+    /// 
+    /// The index holds only each value's offset and length in the log, so
+    /// memory no longer grows with value size, at the cost of a disk read
+    /// per lookup, and reads such as `Db::get` can fail with an I/O error.
+    /// Values written with `defer_flush` stay in memory until the database is
+    /// reopened. Anything that rewrites the log, such as compaction, must
+    /// rebuild these offsets.
+    pub values_on_disk: bool,
+    /// Size in bytes of an LRU cache of values read from disk.
+    /// 
+    /// Only used with `values_on_disk`. Recently fetched and written values
+    /// are kept in memory, up to this many value bytes in total, so hot keys
+    /// skip the disk read. `None` (the default) disables the cache.
+    pub value_cache_bytes: Option<usize>,
+    /// Maximum size of the log file in bytes.
+    /// 
+    /// `None` (the default) means unlimited. Writes that would grow the log
+    /// past the limit are rejected with `ErrorKind::StorageFull` before
+    /// anything is written, leaving room to compact instead of filling the
+    /// disk.
+    pub max_log_bytes: Option<u64>,
+    /// Free space, in bytes, to keep on the filesystem holding the database.
+    /// 
+    /// When set, every write first queries the filesystem and is rejected
+    /// with `ErrorKind::StorageFull`, before anything is written, if it
+    /// would leave less than this much free. That turns a full disk into a
+    /// clean error rather than an `ENOSPC` partway through a record. The
+    /// query costs a system call per write, and is skipped on platforms
+    /// without `statvfs`. `None` (the default) disables the check.
+    pub min_free_space_bytes: Option<u64>,
+    /// Store values longer than this many bytes in their own files under
+    /// `blobs/` in the database directory, logging only a reference.
+    /// 
+    /// `None` (the default) keeps every value in the log. An external value
+    /// is written and fsynced before its reference is logged, and its file
+    /// is removed once a write superseding it has been fsynced. Values are
+    /// still held in memory, read from their files on open, so lookups work
+    /// as usual. Only plain puts are externalized; typed, versioned, and
+    /// batched puts keep their values in the log. The log's references
+    /// can't be replayed elsewhere, so `apply_remote_record` rejects them,
+    /// and `open_at` and `history` fail once they reach a removed blob.
+    pub external_value_bytes: Option<usize>,
+    /// Check applied to the key of every put before it is logged.
+    /// 
+    /// A rejected key fails the put with `ErrorKind::InvalidInput` and
+    /// nothing is written. Deletes are not validated.
+    pub key_validator: Option<Validator<str>>,
+    /// Check applied to the value of every put before it is logged.
+    /// 
+    /// A rejected value fails the put with `ErrorKind::InvalidInput` and
+    /// nothing is written.
+    pub value_validator: Option<Validator<[u8]>>,
+    /// Mapping applied to the key of every put, delete, and lookup by key.
+    /// 
+    /// The normalized key is what gets checked, validated, logged, and
+    /// indexed, so with a lowercasing normalizer `Foo` and `foo` are the
+    /// same entry. Methods that list or match keys, like `keys` and
+    /// `keys_matching`, see normalized keys; `history` takes the key as
+    /// logged. Replay doesn't normalize, so opening an existing database
+    /// with a different normalizer (or none) can leave keys that lookups
+    /// can no longer reach, or two entries that now normalize alike.
+    pub key_normalizer: Option<KeyNormalizer>,
+    /// Fsync the log periodically, covering several writes with one fsync.
+    /// 
+    /// `None` (the default) never fsyncs on its own; writes reach the disk
+    /// whenever the OS gets to them, or on `sync`/`shutdown`. With group
+    /// commit, a write is durable once an fsync covers it; the write that
+    /// triggers an fsync returns only after it completes, covering every
+    /// write before it. With no background thread, writes still waiting
+    /// after the last one stay unsynced until `sync`, `shutdown`, or `close`.
+    /// A failed fsync is returned by the write that triggered it, which has
+    /// already been applied.
+    pub group_commit: Option<GroupCommit>,
+    /// Apply writes to the index only once an fsync has made them durable.
+    /// 
+    /// By default a write is visible to `get` as soon as it is logged, even
+    /// though a crash before the next fsync can still lose it. With this
+    /// set, writes are queued until the fsync covering them completes, so
+    /// readers never observe a write that could be lost. This costs
+    /// latency: without `group_commit`, every write fsyncs before it
+    /// returns; with it, writes stay invisible, even to the handle that
+    /// made them, until their group is fsynced or `sync` is called.
+    /// A failed fsync leaves the writes queued for the next one to succeed.
+    pub index_after_durable: bool,
+    /// Memory budget in bytes for the index built while replaying the log on
+    /// open.
+    /// 
+    /// `None` (the default) means unlimited. Replay counts the bytes of
+    /// live keys, plus values held in memory; with `values_on_disk` only
+    /// keys count. Once the count exceeds the budget, open fails with
+    /// `ErrorKind::OutOfMemory` instead of growing the index further, so a
+    /// dataset too large for memory gets a clear error rather than an
+    /// allocation failure. The count leaves out per-entry overhead, and
+    /// the budget isn't applied by `reopen` or to later writes.
+    pub max_index_bytes: Option<u64>,
+    /// Callback invoked while the log is replayed on open.
+    /// 
+    /// Called after roughly every megabyte read from the log, with
+    /// monotonically increasing byte counts, and once more when replay
+    /// finishes with both counts equal to the log size. `reopen` doesn't
+    /// report progress.
+    pub recovery_progress: Option<RecoveryProgress>,
+    /// Longest the log replay on open may take.
+    /// 
+    /// `None` (the default) means no limit. Replay checks the clock
+    /// periodically, from when it starts reading the log, and fails with
+    /// `ErrorKind::TimedOut` once the deadline has passed, so a service on a
+    /// huge log or a slow filesystem fails fast instead of hanging at
+    /// startup. `reopen` isn't limited.
+    pub open_deadline: Option<Duration>,
+    /// Policy for keys `merge_from` would overwrite.
+    /// 
+    /// Consulted only when both databases hold a value for the key; keys
+    /// new here are inserted, and deletes in the other database still
+    /// apply. Without a resolver the incoming value wins.
+    pub conflict_resolver: Option<ConflictResolver>,
+    /// Log each put, delete, and batch as a two-phase write (see
+    /// `Log::write_two_phase`): its records as pending, then, once an fsync
+    /// has them on disk, a commit marker.
+    /// 
+    /// Replay ignores a write whose marker never made it to disk, so a crash
+    /// between the two leaves the log opening cleanly without it, even
+    /// without `lenient_recovery`. A crash partway through the records
+    /// themselves still leaves a torn tail, as with any write. This costs
+    /// an fsync per write, plus ten bytes of framing. Defaults to false.
+    pub two_phase_commit: bool,
+}
+
+/// One entry in a key's history, as returned by `Db::history`.
+/// 
+/// The log doesn't record when writes happened, so versions carry no
+/// timestamps; their order in the history is the order they were logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoricalVersion {
+    /// The key was set to this value.
+    Put(Vec<u8>),
+    /// The key was deleted.
+    Delete,
+}
+
+/// Soft problems found while replaying the log, as returned by
+/// `Db::open_with_report`.
+/// 
+/// None of these fail an open; they are reported so tools can surface
+/// them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// Records applied to the index, counting each record of a batch.
+    pub records_replayed: usize,
+    /// Keys of records skipped because the key isn't valid UTF-8, in log
+    /// order.
+    pub skipped_keys: Vec<Vec<u8>>,
+    /// Corrupt regions skipped with `Options::lenient_recovery`, including
+    /// any truncated tail.
+    pub corrupt_regions_skipped: usize,
+    /// Bytes after the last readable record, such as a write cut off by a
+    /// crash. Only nonzero with `Options::lenient_recovery`, or after a
+    /// two-phase write that never committed.
+    pub truncated_tail_bytes: u64,
+    /// Number of live keys after replay.
+    pub live_keys: usize,
+    /// Whether the log no longer matched the checksum recorded at its last
+    /// clean close, with `LogChecksumPolicy::Warn`.
+    pub log_checksum_mismatch: bool,
+}
+
+/// Counts of keys changed by `Db::merge_from`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Keys put that didn't exist here.
+    pub inserted: usize,
+    /// Keys put that already existed here, replacing their values, either
+    /// with the incoming value or one the `ConflictResolver` chose.
+    pub overwritten: usize,
+    /// Keys put that already existed here, whose values the
+    /// `ConflictResolver` kept.
+    pub kept: usize,
+    /// Keys deleted in the other database that were removed here.
+    pub deleted: usize,
+}
+
+/// Where a `Db::export_since` export ends, and a checksum of what it wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogExport {
+    /// Log offset the exported records end at, to pass to the next export.
+    pub end: u64,
+    /// CRC-32 of the bytes written, to check a stored export against
+    /// before restoring from it.
+    pub crc: u32,
+}
+
+/// A point in a log's history to open a database at, for `Db::open_at`.
+/// 
+/// Log records carry no timestamps, so points are given by record count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checkpoint {
+    /// The state after the first N records, counting each record of a batch
+    /// separately.
+    /// 
+    /// A batch is applied whole or not at all, so a count falling inside
+    /// one gives the state before it.
+    RecordCount(u64),
+}
+
+/// Writes buffered by `Db::transaction`, committed together when the
+/// transaction's closure returns `Ok`.
+/// 
+/// Keys are checked and values validated at commit, not as they are
+/// buffered. Buffered writes aren't visible through the `Db` until the
+/// commit.
+#[derive(Debug, Default)]
+pub struct Txn {
+    /// Buffered writes in order: puts with `Some` value, deletes with `None`.
+    writes: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl Txn {
+    /// Buffers a put of `value` under `key`.
+    pub fn put(&mut self, key: &str, value: &[u8]) {
+        self.writes.push((key.to_string(), Some(value.to_vec())));
+    }
+
+    /// Buffers a delete of `key`.
+    pub fn delete(&mut self, key: &str) {
+        self.writes.push((key.to_string(), None));
+    }
+
+    /// Returns the number of buffered writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns true if no writes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+/// What a put records alongside its value, deciding the type of record
+/// logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PutTag {
+    /// Nothing: a plain Put.
+    Plain,
+    /// A content type, from `Db::put_with_content_type`.
+    ContentType(String),
+    /// A version, from `Db::put_versioned`.
+    Version(u64),
+    /// The id of the blob the value was stored in, for
+    /// `Options::external_value_bytes`. Logged in place of the value.
+    Blob(u64),
+}
+
+/// A logged write queued for the index by `Options::index_after_durable`.
+#[derive(Debug)]
+enum PendingWrite {
+    /// A put whose record ends at `record_end` in the log.
+    Put {
+        key: String,
+        value: Vec<u8>,
+        tag: PutTag,
+        record_end: u64,
+    },
+    /// A delete.
+    Delete { key: String },
+}
+
+impl PendingWrite {
+    /// Returns the key written.
+    fn key(&self) -> &str {
+        match self {
+            PendingWrite::Put { key, .. } => key,
+            PendingWrite::Delete { key } => key,
+        }
+    }
+}
+
+/// A value held by the index.
+#[derive(Debug)]
+enum StoredValue {
+    /// Value bytes held in memory, shared with handles from `Db::get_arc`.
+    Owned(Arc<[u8]>),
+    /// Byte range of the value within the log file, read on demand.
+    OnDisk(Range<u64>),
+}
+
+impl StoredValue {
+    /// Returns the length of the value in bytes, without reading it.
+    fn len(&self) -> usize {
+        match self {
+            StoredValue::Owned(bytes) => bytes.len(),
+            StoredValue::OnDisk(range) => (range.end - range.start) as usize,
+        }
+    }
+}
+
+/// Insertion sequence numbers for live keys.
+/// 
+/// Invariant: `seq_by_key` and `key_by_seq` hold exactly the same set of
+/// (key, sequence) pairs, one per live key.
+#[derive(Debug, Default)]
+struct InsertionOrder {
+    /// Sequence number assigned to each live key on its first put.
+    seq_by_key: HashMap<String, u64>,
+    /// Live keys ordered by sequence number.
+    key_by_seq: BTreeMap<u64, String>,
+    /// Next sequence number to assign.
+    next_seq: u64,
+}
+
+impl InsertionOrder {
+    /// Records a put. New keys get the next sequence number; existing keys
+    /// keep theirs.
+    fn insert(&mut self, key: &str) {
+        if self.seq_by_key.contains_key(key) {
+            return;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.seq_by_key.insert(key.to_string(), seq);
+        self.key_by_seq.insert(seq, key.to_string());
+    }
+
+    /// Records a delete, forgetting the key's sequence number.
+    fn remove(&mut self, key: &str) {
+        if let Some(seq) = self.seq_by_key.remove(key) {
+            self.key_by_seq.remove(&seq);
+        }
+    }
+
+    /// Releases excess capacity left by removed keys.
+    /// 
+    /// `key_by_seq` frees its nodes as keys are removed, so only the hash
+    /// table needs shrinking.
+    fn shrink_to_fit(&mut self) {
+        self.seq_by_key.shrink_to_fit();
+    }
+}
+
+/// Throttles calls to `Options::recovery_progress` during a replay.
+struct ProgressTracker {
+    /// The callback, if one was configured.
+    callback: Option<RecoveryProgress>,
+    /// Size of the log being replayed.
+    total: u64,
+    /// Byte count passed to the last report.
+    reported: u64,
+}
+
+impl ProgressTracker {
+    fn new(callback: Option<RecoveryProgress>, total: u64) -> Self {
+        ProgressTracker {
+            callback,
+            total,
+            reported: 0,
+        }
+    }
+
+    /// Notes that replay has read `offset` bytes, reporting it if enough
+    /// bytes have been read since the last report.
+    fn advance(&mut self, offset: u64) {
+        if let Some(callback) = &self.callback {
+            if offset - self.reported >= PROGRESS_INTERVAL && offset < self.total {
+                callback.report(offset, self.total);
+                self.reported = offset;
+            }
+        }
+    }
+
+    /// Reports that the whole log was replayed.
+    fn finish(self) {
+        if let Some(callback) = &self.callback {
+            callback.report(self.total, self.total);
+        }
+    }
+}
+
+/// Enforces `Options::open_deadline` during a replay.
+struct ReplayDeadline {
+    /// The configured deadline and when replay started, if there is one.
+    limit: Option<(Duration, Instant)>,
+    /// Records replayed since the clock was last read.
+    unchecked: u32,
+}
+
+impl ReplayDeadline {
+    /// Starts the clock, if `limit` is set.
+    fn start(limit: Option<Duration>) -> Self {
+        ReplayDeadline {
+            limit: limit.map(|limit| (limit, Instant::now())),
+            unchecked: 0,
+        }
+    }
+
+    /// Notes that a record ending at `offset` was replayed, failing with
+    /// `ErrorKind::TimedOut` if the deadline has passed.
+    /// 
+    /// Reads the clock only every `DEADLINE_CHECK_INTERVAL` records.
+    fn check(&mut self, offset: u64, total: u64) -> std::io::Result<()> {
+        if self.limit.is_none() {
+            return Ok(());
+        }
+        self.unchecked += 1;
+        if self.unchecked < DEADLINE_CHECK_INTERVAL {
+            return Ok(());
+        }
+        self.unchecked = 0;
+        self.check_now(offset, total)
+    }
+
+    /// Fails with `ErrorKind::TimedOut` if the deadline has passed, having
+    /// replayed up to `offset`.
+    fn check_now(&self, offset: u64, total: u64) -> std::io::Result<()> {
+        let Some((limit, started)) = self.limit else {
+            return Ok(());
+        };
+        if started.elapsed() <= limit {
+            return Ok(());
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!(
+                "Replay exceeded the {:?} open deadline after replaying {} of {} log bytes",
+                limit, offset, total
+            ),
+        ))
+    }
+}
+
+/// Least-recently-used cache of values, bounded by total value size.
+/// 
+/// Invariants:
+/// - `entries` and `lru` hold the same keys, `lru` ordered by last use.
+/// - `size` is the total length of the cached values and never exceeds
+///   `capacity`.
+#[derive(Debug)]
+struct ValueCache {
+    /// Maximum total size of cached values in bytes.
+    capacity: usize,
+    /// Current total size of cached values in bytes.
+    size: usize,
+    /// Cached value and last-use tick for each key.
+    entries: HashMap<String, (Vec<u8>, u64)>,
+    /// Cached keys ordered by last-use tick, least recent first.
+    lru: BTreeMap<u64, String>,
+    /// Next last-use tick to assign.
+    next_tick: u64,
+}
+
+impl ValueCache {
+    fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_tick: 0,
+        }
+    }
+
+    /// Returns a copy of the cached value, marking it most recently used.
+    fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let tick = self.next_tick;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.next_tick += 1;
+        let key = self.lru.remove(last_used).unwrap_or_else(|| key.to_string());
+        self.lru.insert(tick, key);
+        *last_used = tick;
+        Some(value.clone())
+    }
+
+    /// Caches a value, evicting least recently used ones to make room.
+    /// 
+    /// Values larger than the whole cache aren't cached.
+    fn insert(&mut self, key: &str, value: &[u8]) {
+        self.remove(key);
+        if value.len() > self.capacity {
+            return;
+        }
+        while self.size + value.len() > self.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.size -= evicted.len();
+            }
+        }
+        
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.size += value.len();
+        self.entries.insert(key.to_string(), (value.to_vec(), tick));
+        self.lru.insert(tick, key.to_string());
+    }
+
+    /// Drops a key's cached value, if any.
+    fn remove(&mut self, key: &str) {
+        if let Some((value, last_used)) = self.entries.remove(key) {
+            self.size -= value.len();
+            self.lru.remove(&last_used);
+        }
+    }
+
+    /// Releases excess capacity left by evicted values.
+    fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+    }
+}
 
 /// In-memory document database with crash-safe persistence.
 /// 
@@ -13,159 +897,5498 @@ use std::path::Path;
 pub struct Db {
     /// Append-only log for crash-safe writes.
     log: Log,
-    /// In-memory index mapping keys to values.
+    /// In-memory index mapping keys to values, ordered by key.
     /// 
     /// Invariant: A key is present in the index if and only if it has been
     /// put and not deleted (or deleted then put again).
-    index: HashMap<String, Vec<u8>>,
+    index: BTreeMap<String, StoredValue>,
+    /// Options the database was opened with.
+    options: Options,
+    /// Whether the log holds buffered writes not yet flushed (deferred mode).
+    unflushed: bool,
+    /// Insertion order of live keys, if `Options::track_insertion_order` is set.
+    insertion_order: Option<InsertionOrder>,
+    /// When each live key was last read, if `Options::track_access_time` is
+    /// set.
+    /// 
+    /// Behind a mutex since reads only borrow the `Db`. Invariant: Holds
+    /// only live keys that have been read since open.
+    access_times: Option<Mutex<HashMap<String, Instant>>>,
+    /// Report from the most recent log replay.
+    recovery_report: RecoveryReport,
+    /// Path of the log file.
+    log_path: PathBuf,
+    /// Byte offset in the log up to which records have been replayed.
+    /// 
+    /// `reopen` resumes replay from here.
+    log_offset: u64,
+    /// Operation counters since open.
+    counters: Counters,
+    /// Put and Delete records in the log, counting those replayed and those
+    /// written since open.
+    log_records: u64,
+    /// Read handle on the log, if `Options::values_on_disk` is set.
+    /// 
+    /// Behind a mutex since reads seek, and `get` only borrows the `Db`.
+    value_reader: Option<Mutex<File>>,
+    /// Cache of on-disk values, if `Options::value_cache_bytes` is set.
+    /// 
+    /// Invariant: A cached value always equals the key's current value.
+    value_cache: Option<Mutex<ValueCache>>,
+    /// Content type of each live key whose value was put with one.
+    /// 
+    /// Invariant: Holds only live keys; keys whose value was put without a
+    /// content type are absent.
+    content_types: HashMap<String, String>,
+    /// Version of each live key whose value was put with `put_versioned`.
+    /// 
+    /// Invariant: Holds only live keys; keys whose value was put any other
+    /// way are absent, and have version 0.
+    versions: HashMap<String, u64>,
+    /// Point in the log the database was opened at with `Db::open_at`.
+    /// 
+    /// Invariant: When set, the database is read-only, so the index keeps
+    /// reflecting exactly the records up to this point.
+    checkpoint: Option<Checkpoint>,
+    /// Writes not yet covered by an fsync, counted for `Options::group_commit`.
+    unsynced_writes: usize,
+    /// When the oldest write counted in `unsynced_writes` was made.
+    oldest_unsynced: Option<Instant>,
+    /// Logged writes waiting for an fsync before they are applied to the
+    /// index, under `Options::index_after_durable`. In log order.
+    pending: Vec<PendingWrite>,
+    /// Next id `push` will assign, once it has been called.
+    /// 
+    /// Invariant: Greater than the id of every key `push` assigned through
+    /// this handle.
+    next_push: Option<u64>,
+    /// Blob id of each live key whose value is stored externally.
+    /// 
+    /// Invariant: Holds only live keys, whose values are held in memory.
+    blobs: HashMap<String, u64>,
+    /// Id the next external value will be stored under.
+    /// 
+    /// Invariant: Greater than every blob id in the log, so ids are never
+    /// reused, even for blobs since removed.
+    next_blob: u64,
+    /// Keys whose last write in the log was a delete, for `lookup`.
+    /// 
+    /// Invariant: Disjoint from the index. Costs one `String` per deleted
+    /// key that hasn't been put again, for as long as the log keeps its
+    /// delete record.
+    tombstones: HashSet<String>,
 }
 
-impl Db {
-    /// Opens or creates a database at the given directory.
-    /// 
-    /// On startup, replays the log to rebuild the in-memory index.
-    /// This ensures crash recovery: the database state matches what it was
-    /// before the crash.
-    /// 
-    /// The directory is created automatically if it doesn't exist.
-    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<Self> {
-        let dir = dir.as_ref();
+impl Db {
+    /// Opens or creates a database at the given directory.
+    /// 
+    /// On startup, replays the log to rebuild the in-memory index.
+    /// This ensures crash recovery: the database state matches what it was
+    /// before the crash.
+    /// 
+    /// The directory is created automatically if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<Self> {
+        Self::open_with_options(dir, Options::default())
+    }
+
+    /// Opens or creates a database at the given directory with custom options.
+    /// 
+    /// See `Db::open` for recovery behavior.
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: Options) -> std::io::Result<Self> {
+        Self::open_inner(dir.as_ref(), options, None).map(|(db, _)| db)
+    }
+
+    /// Opens a database like `Db::open_with_options`, also returning what
+    /// replay skipped or tolerated.
+    pub fn open_with_report<P: AsRef<Path>>(
+        dir: P,
+        options: Options,
+    ) -> std::io::Result<(Self, OpenReport)> {
+        Self::open_inner(dir.as_ref(), options, None)
+    }
+
+    /// Opens a read-only view of the database at `dir` as it was at
+    /// `checkpoint`, replaying only the log records up to that point.
+    /// 
+    /// Useful for debugging and forensics. Writes, `reopen`, and `destroy`
+    /// fail with `ErrorKind::PermissionDenied`, and `verify_consistency`,
+    /// which checks against the whole log, with `ErrorKind::Unsupported`. Fails with
+    /// `ErrorKind::NotFound` if there is no log at `dir`. A checkpoint past
+    /// the end of the log gives the current state.
+    pub fn open_at<P: AsRef<Path>>(dir: P, checkpoint: Checkpoint) -> std::io::Result<Self> {
+        let dir = dir.as_ref();
+        let log_path = dir.join("log");
+        if !log_path.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No log at {}", log_path.display()),
+            ));
+        }
+        Self::open_inner(dir, Options::default(), Some(checkpoint)).map(|(db, _)| db)
+    }
+
+    /// Shared implementation of `open_with_options`, `open_with_report`,
+    /// and `open_at`.
+    fn open_inner(
+        dir: &Path,
+        options: Options,
+        checkpoint: Option<Checkpoint>,
+    ) -> std::io::Result<(Self, OpenReport)> {
+        // Create the directory if it doesn't exist
+        fs::create_dir_all(dir)?;
+        
+        let log_path = dir.join("log");
+        
+        // Open the log for appending new records. A newly created log's
+        // directory entry isn't durable until the directory is synced, and
+        // without it a crash could lose the file along with its first writes.
+        let creating_log = !log_path.exists();
+        let mut log = Log::open(&log_path)?;
+        if creating_log {
+            sync_dir(dir)?;
+        }
+        log.set_auto_flush(!options.defer_flush);
+        let value_reader = if options.values_on_disk {
+            Some(Mutex::new(File::open(&log_path)?))
+        } else {
+            None
+        };
+        let value_cache = options
+            .value_cache_bytes
+            .filter(|_| options.values_on_disk)
+            .map(|capacity| Mutex::new(ValueCache::new(capacity)));
+        
+        let mut db = Db {
+            log,
+            index: BTreeMap::new(),
+            insertion_order: options.track_insertion_order.then(InsertionOrder::default),
+            access_times: options.track_access_time.then(Default::default),
+            options,
+            unflushed: false,
+            recovery_report: RecoveryReport::default(),
+            log_path,
+            log_offset: 0,
+            counters: Counters::default(),
+            log_records: 0,
+            value_reader,
+            value_cache,
+            content_types: HashMap::new(),
+            versions: HashMap::new(),
+            checkpoint,
+            unsynced_writes: 0,
+            oldest_unsynced: None,
+            pending: Vec::new(),
+            next_push: None,
+            blobs: HashMap::new(),
+            next_blob: 0,
+            tombstones: HashSet::new(),
+        };
+        
+        let mut report = OpenReport {
+            log_checksum_mismatch: db.check_log_checksum()?,
+            ..OpenReport::default()
+        };
+        
+        // Replay the log to rebuild the index
+        db.replay_log(&mut report)?;
+        db.load_blobs()?;
+        report.corrupt_regions_skipped = db.recovery_report.records_skipped;
+        report.live_keys = db.index.len();
+        db.log_records = (report.records_replayed + report.skipped_keys.len()) as u64;
+        
+        Ok((db, report))
+    }
+
+    /// Fails if the database was opened read-only with `Db::open_at`.
+    fn check_writable(&self) -> std::io::Result<()> {
+        match self.checkpoint {
+            Some(checkpoint) => Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("Database opened read-only at {:?}", checkpoint),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies `Options::key_normalizer`, if set, to a key passed in by a
+    /// caller.
+    fn normalize_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.options.key_normalizer {
+            Some(normalizer) => Cow::Owned(normalizer.normalize(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Checks a key against the configured `max_key_bytes` limit.
+    fn check_key(&self, key: &str) -> std::io::Result<()> {
+        if let Some(max) = self.options.max_key_bytes {
+            if key.len() > max {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Key is {} bytes, exceeding the {} byte limit", key.len(), max),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the configured key and value validators against a put.
+    fn validate_put(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        if let Some(validator) = &self.options.key_validator {
+            validator.validate(key).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid key '{}': {}", key, e),
+                )
+            })?;
+        }
+        if let Some(validator) = &self.options.value_validator {
+            validator.validate(value).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid value for key '{}': {}", key, e),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Checks that appending `additional` bytes keeps the log within the
+    /// configured `max_log_bytes` limit, and leaves the filesystem the
+    /// `min_free_space_bytes` margin.
+    fn check_log_space(&self, additional: u64) -> std::io::Result<()> {
+        if let Some(margin) = self.options.min_free_space_bytes {
+            check_free_space(available_space(self.dir())?, additional, margin)?;
+        }
+        if let Some(max) = self.options.max_log_bytes {
+            let size = self.log.size_bytes()?;
+            if size + additional > max {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    format!(
+                        "Log is full: {} bytes plus a {} byte record would exceed the {} byte limit; compact the log to free space",
+                        size, additional, max
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays the log file to rebuild the in-memory index.
+    /// 
+    /// Invariant: After replay, the index contains the state that results
+    /// from applying all log records in order. Later operations overwrite
+    /// earlier ones (Put overwrites previous Put/Delete, Delete removes the key).
+    /// 
+    /// Records what was skipped in `report`.
+    fn replay_log(&mut self, report: &mut OpenReport) -> std::io::Result<()> {
+        let file = File::open(&self.log_path)?;
+        let total = file.metadata()?.len();
+        self.replay_from(file, total, report)
+    }
+
+    /// Replays a log of `total` bytes read from `reader`, as `replay_log`.
+    /// 
+    /// The log is read a chunk at a time, applying each complete entry as
+    /// soon as it has been read. Progress is reported as chunks are read,
+    /// and the deadline is checked after every chunk, so a slow read can't
+    /// hold up an open past `Options::open_deadline`.
+    fn replay_from<R: Read>(
+        &mut self,
+        mut reader: R,
+        total: u64,
+        report: &mut OpenReport,
+    ) -> std::io::Result<()> {
+        let mut deadline = ReplayDeadline::start(self.options.open_deadline);
+        let mut progress = ProgressTracker::new(self.options.recovery_progress.clone(), total);
+        let mut bytes = Vec::with_capacity(usize::try_from(total).unwrap_or(0));
+        let mut chunk = vec![0u8; REPLAY_CHUNK_BYTES];
+        let mut index_bytes = 0u64;
+        // Records are counted towards a checkpoint a whole entry at a time,
+        // so replay never stops partway through a batch
+        let limit = match self.checkpoint {
+            Some(Checkpoint::RecordCount(count)) => usize::try_from(count).unwrap_or(usize::MAX),
+            None => usize::MAX,
+        };
+        let mut recovered = 0;
+        let mut pos = 0;
+        
+        loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            bytes.extend_from_slice(&chunk[..read]);
+            progress.advance(bytes.len() as u64);
+            
+            // Apply each record read so far to rebuild the index; an entry
+            // cut off by the end of the chunk waits for the next one
+            while let Some((spans, len)) = Log::scan_entry(&bytes, pos) {
+                if recovered + spans.len() > limit {
+                    self.recovery_report.records_recovered = recovered;
+                    self.log_offset = pos as u64;
+                    progress.finish();
+                    return Ok(());
+                }
+                recovered += spans.len();
+                for span in spans {
+                    let end = span.end() as u64;
+                    self.replay_span(&bytes, span, report, &mut index_bytes)?;
+                    deadline.check(end, total)?;
+                }
+                pos += len;
+            }
+            
+            if read == 0 {
+                break;
+            }
+            deadline.check_now(pos as u64, total)?;
+        }
+        
+        // What follows the last complete entry is either corrupt, or a
+        // two-phase write that never committed
+        let mut tail_start = pos;
+        if self.options.lenient_recovery {
+            let (spans, mut recovery_report) = Log::scan_lenient_from(&bytes, pos);
+            if let Some(last) = spans.last() {
+                tail_start = Log::skip_commit_marker(&bytes, last.end());
+            }
+            for span in spans {
+                let end = span.end() as u64;
+                self.replay_span(&bytes, span, report, &mut index_bytes)?;
+                deadline.check(end, total)?;
+            }
+            recovery_report.records_recovered += recovered;
+            self.recovery_report = recovery_report;
+        } else if pos < bytes.len() && !Log::is_uncommitted(&bytes[pos..]) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Malformed or truncated record at byte {}", pos),
+            ));
+        } else {
+            self.recovery_report = RecoveryReport {
+                records_recovered: recovered,
+                ..RecoveryReport::default()
+            };
+        }
+        report.truncated_tail_bytes = (bytes.len() - tail_start) as u64;
+        progress.finish();
+        
+        self.log_offset = bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Applies one record during `replay_log`, counting it in `report` and
+    /// its effect on the index in `index_bytes`.
+    /// 
+    /// Fails with `ErrorKind::OutOfMemory` once `index_bytes` exceeds
+    /// `Options::max_index_bytes`.
+    fn replay_span(
+        &mut self,
+        bytes: &[u8],
+        span: RecordSpan,
+        report: &mut OpenReport,
+        index_bytes: &mut u64,
+    ) -> std::io::Result<()> {
+        let end = span.end();
+        let key = std::str::from_utf8(&bytes[span.key()]).ok();
+        match key {
+            Some(_) => report.records_replayed += 1,
+            None => report.skipped_keys.push(bytes[span.key()].to_vec()),
+        }
+        let before = key.map_or(0, |key| self.entry_bytes(key));
+        self.apply_span(bytes, 0, span);
+        let after = key.map_or(0, |key| self.entry_bytes(key));
+        *index_bytes = *index_bytes - before + after;
+        if let Some(max) = self.options.max_index_bytes.filter(|&max| *index_bytes > max) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                format!(
+                    "Index exceeds the {} byte memory budget after replaying {} of {} log bytes; raise max_index_bytes or open with values_on_disk",
+                    max,
+                    end,
+                    bytes.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes `key`'s entry counts against
+    /// `Options::max_index_bytes`: the key, plus the value if held in memory.
+    /// Zero if the key isn't live.
+    fn entry_bytes(&self, key: &str) -> u64 {
+        match self.index.get(key) {
+            Some(StoredValue::Owned(value)) => (key.len() + value.len()) as u64,
+            Some(_) => key.len() as u64,
+            None => 0,
+        }
+    }
+
+    /// Applies a record located in `bytes`, which were read from the log
+    /// starting at offset `base`.
+    /// 
+    /// With `Options::values_on_disk`, Put values are indexed by their
+    /// offset in the log instead of being copied.
+    fn apply_span(&mut self, bytes: &[u8], base: u64, span: RecordSpan) {
+        match span {
+            RecordSpan::Put { key, content_type, version, value } if self.options.values_on_disk => {
+                if let Ok(key_str) = std::str::from_utf8(&bytes[key]) {
+                    let range = base + value.start as u64..base + value.end as u64;
+                    self.index_insert(key_str.to_string(), StoredValue::OnDisk(range));
+                    if let Some(content_type) = content_type {
+                        self.set_content_type(key_str, &bytes[content_type]);
+                    }
+                    if let Some(version) = version {
+                        self.versions.insert(key_str.to_string(), version);
+                    }
+                }
+            }
+            span => self.apply_record(span.to_record(bytes)),
+        }
+    }
+
+    /// Applies a record read from the log to the in-memory state.
+    fn apply_record(&mut self, record: LogRecord) {
+        match record {
+            LogRecord::Put { key, value } => {
+                // Convert key from bytes to string
+                // If the key is not valid UTF-8, we skip it (could also return an error)
+                if let Ok(key_str) = String::from_utf8(key) {
+                    self.index_insert(key_str, StoredValue::Owned(value.into()));
+                }
+            }
+            LogRecord::TypedPut { key, content_type, value } => {
+                if let Ok(key_str) = String::from_utf8(key) {
+                    self.index_insert(key_str.clone(), StoredValue::Owned(value.into()));
+                    self.set_content_type(&key_str, &content_type);
+                }
+            }
+            LogRecord::VersionedPut { key, version, value } => {
+                if let Ok(key_str) = String::from_utf8(key) {
+                    self.index_insert(key_str.clone(), StoredValue::Owned(value.into()));
+                    self.versions.insert(key_str, version);
+                }
+            }
+            LogRecord::BlobPut { key, blob } => {
+                if let Ok(key_str) = String::from_utf8(key) {
+                    self.index_blob(key_str, blob);
+                }
+            }
+            LogRecord::Delete { key } => {
+                // Convert key from bytes to string and remove from index
+                if let Ok(key_str) = String::from_utf8(key) {
+                    self.index_remove(&key_str);
+                }
+            }
+        }
+    }
+
+    /// Indexes a replayed reference to an external value.
+    /// 
+    /// The value is left empty until `load_blobs` reads it, since a later
+    /// record may supersede the reference and its file be gone.
+    fn index_blob(&mut self, key: String, blob: u64) {
+        self.index_insert(key.clone(), StoredValue::Owned(Arc::from(&[][..])));
+        self.blobs.insert(key, blob);
+        self.next_blob = self.next_blob.max(blob.saturating_add(1));
+    }
+
+    /// Reads the external values of keys indexed by `index_blob` since the
+    /// last load.
+    /// 
+    /// Loaded values are never empty, since only values longer than
+    /// `Options::external_value_bytes` are stored externally, so empty ones
+    /// are the ones still to read. Fails if a file is missing.
+    fn load_blobs(&mut self) -> std::io::Result<()> {
+        let dir = self.dir().to_path_buf();
+        for (key, blob) in &self.blobs {
+            let Some(StoredValue::Owned(value)) = self.index.get_mut(key) else {
+                continue;
+            };
+            if value.is_empty() {
+                *value = fs::read(blob_path(&dir, *blob))?.into();
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the external value a `LogRecord::BlobPut` in the log of the
+    /// database at `dir` refers to, e.g. to ship the record to a follower
+    /// as a plain put.
+    /// 
+    /// Fails with `ErrorKind::NotFound` once the blob has been removed,
+    /// which happens only after a later record overwriting or deleting its
+    /// key has been fsynced.
+    pub fn read_blob<P: AsRef<Path>>(dir: P, blob: u64) -> std::io::Result<Vec<u8>> {
+        fs::read(blob_path(dir.as_ref(), blob))
+    }
+
+    /// Returns the database directory.
+    fn dir(&self) -> &Path {
+        self.log_path.parent().unwrap_or(Path::new("."))
+    }
+
+    /// Writes an external value to the file for blob `blob`, durably.
+    fn write_blob(&mut self, blob: u64, value: &[u8]) -> std::io::Result<()> {
+        let blob_dir = self.dir().join(BLOB_DIR);
+        fs::create_dir_all(&blob_dir)?;
+        atomic_write(&blob_dir, &blob.to_string(), value)?;
+        self.next_blob = blob + 1;
+        Ok(())
+    }
+
+    /// Removes the file of a blob whose key was just overwritten or
+    /// deleted.
+    /// 
+    /// The log is fsynced first, so a crash can't lose the superseding
+    /// write and leave the log referring to a removed file. If either step
+    /// fails the file is left behind, unreferenced and harmless.
+    fn remove_superseded_blob(&mut self, blob: u64) {
+        if self.log.sync().is_ok() {
+            self.counters.record_sync();
+            let _ = fs::remove_file(blob_path(self.dir(), blob));
+        }
+    }
+
+    /// Inserts a value into the index and any secondary structures.
+    /// 
+    /// Clears the key's content type and version; callers storing a typed
+    /// or versioned value set them afterwards.
+    fn index_insert(&mut self, key: String, value: StoredValue) {
+        if let Some(order) = &mut self.insertion_order {
+            order.insert(&key);
+        }
+        if let Some(cache) = &self.value_cache {
+            lock(cache).remove(&key);
+        }
+        self.content_types.remove(&key);
+        self.versions.remove(&key);
+        self.blobs.remove(&key);
+        self.tombstones.remove(&key);
+        self.index.insert(key, value);
+    }
+
+    /// Copies `value` over the key's current value if that is held in
+    /// memory, the same length, and not shared with a handle from
+    /// `get_arc`, sparing an allocation for frequently updated keys such as
+    /// counters. Returns whether it did.
+    /// 
+    /// Otherwise matches `index_insert` for a key that is already live.
+    fn overwrite_in_place(&mut self, key: &str, value: &[u8]) -> bool {
+        let Some(StoredValue::Owned(bytes)) = self.index.get_mut(key) else {
+            return false;
+        };
+        match Arc::get_mut(bytes) {
+            Some(bytes) if bytes.len() == value.len() => bytes.copy_from_slice(value),
+            _ => return false,
+        }
+        if let Some(cache) = &self.value_cache {
+            lock(cache).remove(key);
+        }
+        self.content_types.remove(key);
+        self.versions.remove(key);
+        self.blobs.remove(key);
+        true
+    }
+
+    /// Records the content type of a key just indexed from a Typed Put.
+    /// 
+    /// Content types that aren't valid UTF-8 are ignored, leaving the
+    /// default, as with keys in `apply_record`.
+    fn set_content_type(&mut self, key: &str, content_type: &[u8]) {
+        if let Ok(content_type) = std::str::from_utf8(content_type) {
+            self.content_types.insert(key.to_string(), content_type.to_string());
+        }
+    }
+
+    /// Returns the bytes of a value held in memory by the index, or `None`
+    /// for a value kept on disk, which `load_value` reads.
+    fn value_bytes<'a>(&'a self, value: &'a StoredValue) -> Option<&'a [u8]> {
+        match value {
+            StoredValue::Owned(bytes) => Some(bytes),
+            StoredValue::OnDisk(_) => None,
+        }
+    }
+
+    /// Returns the bytes of `key`'s value held by the index, reading it from
+    /// the value cache or the log if it is kept on disk.
+    fn load_value<'a>(
+        &'a self,
+        key: &str,
+        value: &'a StoredValue,
+    ) -> std::io::Result<Cow<'a, [u8]>> {
+        if let Some(bytes) = self.value_bytes(value) {
+            return Ok(Cow::Borrowed(bytes));
+        }
+        let StoredValue::OnDisk(range) = value else {
+            unreachable!("value neither in memory nor on disk");
+        };
+        let Some(reader) = &self.value_reader else {
+            unreachable!("on-disk value without a value reader");
+        };
+        
+        if let Some(cache) = &self.value_cache {
+            let cached = lock(cache).get(key);
+            self.counters.record_cache_lookup(cached.is_some());
+            if let Some(bytes) = cached {
+                return Ok(Cow::Owned(bytes));
+            }
+        }
+        
+        let bytes = Self::read_range(reader, range)?;
+        if let Some(cache) = &self.value_cache {
+            lock(cache).insert(key, &bytes);
+        }
+        Ok(Cow::Owned(bytes))
+    }
+
+    /// Reads the bytes of an on-disk value from the log.
+    fn read_range(reader: &Mutex<File>, range: &Range<u64>) -> std::io::Result<Vec<u8>> {
+        let mut bytes = vec![0u8; (range.end - range.start) as usize];
+        let mut file = lock(reader);
+        file.seek(SeekFrom::Start(range.start))?;
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Loads the values of `keys` ahead of their first read, for
+    /// predictable latency right after open. Returns how many were loaded.
+    /// 
+    /// With `Options::values_on_disk` and a value cache, reads each value
+    /// not already cached into the cache, evicting older entries as reads
+    /// do. Otherwise values are already in memory and this is a no-op.
+    /// Missing keys are skipped, and warming isn't counted as reads in
+    /// `metrics`.
+    pub fn warm(&self, keys: &[&str]) -> std::io::Result<usize> {
+        let mut loaded = 0;
+        for key in keys {
+            let key = self.normalize_key(key);
+            let Some(StoredValue::OnDisk(range)) = self.index.get(key.as_ref()) else {
+                continue;
+            };
+            let (Some(cache), Some(reader)) = (&self.value_cache, &self.value_reader) else {
+                continue;
+            };
+            if lock(cache).entries.contains_key(key.as_ref()) {
+                continue;
+            }
+            let bytes = Self::read_range(reader, range)?;
+            lock(cache).insert(&key, &bytes);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Removes a key from the index and any secondary structures.
+    fn index_remove(&mut self, key: &str) {
+        if let Some(order) = &mut self.insertion_order {
+            order.remove(key);
+        }
+        if let Some(cache) = &self.value_cache {
+            lock(cache).remove(key);
+        }
+        if let Some(access_times) = &self.access_times {
+            lock(access_times).remove(key);
+        }
+        self.content_types.remove(key);
+        self.versions.remove(key);
+        self.blobs.remove(key);
+        self.index.remove(key);
+        self.tombstones.insert(key.to_string());
+    }
+
+    /// Stores a key-value pair in the database.
+    /// 
+    /// The value is stored as raw bytes (JSON documents should be serialized
+    /// to bytes before calling this method).
+    /// 
+    /// Invariant: The operation is logged before the index is updated,
+    /// ensuring crash safety.
+    pub fn put(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.put_value(key.to_string(), Cow::Borrowed(value), PutTag::Plain)
+    }
+
+    /// Stores a JSON document given as a string, after checking that it
+    /// parses.
+    /// 
+    /// Fails with `ErrorKind::InvalidInput`, wrapping the parse error, if
+    /// `json` isn't valid JSON; nothing is written in that case. The text is
+    /// stored as given, not re-serialized.
+    pub fn put_json_str(&mut self, key: &str, json: &str) -> std::io::Result<()> {
+        Self::validate_json(json.as_bytes())?;
+        self.put(key, json.as_bytes())
+    }
+
+    /// Checks that `value` is a single valid JSON document.
+    /// 
+    /// Fails with `ErrorKind::InvalidInput` wrapping the `serde_json` parse
+    /// error, whose message says where parsing stopped.
+    pub fn validate_json(value: &[u8]) -> std::io::Result<()> {
+        serde_json::from_slice::<serde::de::IgnoredAny>(value)
+            .map(|_| ())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Stores a key-value pair tagged with a content type, such as
+    /// `application/octet-stream` for raw bytes.
+    /// 
+    /// Values put any other way have `DEFAULT_CONTENT_TYPE`. The tag lasts
+    /// until the key is next put or deleted; see `Db::content_type`.
+    pub fn put_with_content_type(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        content_type: &str,
+    ) -> std::io::Result<()> {
+        let tag = PutTag::ContentType(content_type.to_string());
+        self.put_value(key.to_string(), Cow::Borrowed(value), tag)
+    }
+
+    /// Stores a key-value pair, moving the value into the index.
+    /// 
+    /// Same as `put`, but takes ownership of the key and value, so callers
+    /// who already own them can pass them without borrowing.
+    pub fn put_owned(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<Vec<u8>>,
+    ) -> std::io::Result<()> {
+        self.put_value(key.into(), Cow::Owned(value.into()), PutTag::Plain)
+    }
+
+    /// Shared implementation of `put`, `put_owned`, `put_with_content_type`,
+    /// and `put_versioned`. The value is copied only if kept in memory.
+    fn put_value(
+        &mut self,
+        key: String,
+        value: Cow<'_, [u8]>,
+        tag: PutTag,
+    ) -> std::io::Result<()> {
+        self.check_writable()?;
+        let key = match &self.options.key_normalizer {
+            Some(normalizer) => normalizer.normalize(&key),
+            None => key,
+        };
+        self.check_key(&key)?;
+        self.validate_put(&key, &value)?;
+        let external = self.options.external_value_bytes.is_some_and(|max| value.len() > max);
+        let tag = match tag {
+            PutTag::Plain if external => PutTag::Blob(self.next_blob),
+            tag => tag,
+        };
+        let record_len = match &tag {
+            PutTag::Plain => Log::put_len(key.as_bytes(), &value),
+            PutTag::ContentType(content_type) => {
+                Log::typed_put_len(key.as_bytes(), &value, content_type.as_bytes())
+            }
+            PutTag::Version(_) => Log::versioned_put_len(key.as_bytes(), &value),
+            PutTag::Blob(_) => Log::blob_put_len(key.as_bytes()),
+        };
+        // A two-phase record follows a 9-byte pending header and precedes
+        // a 1-byte commit marker
+        let (header, marker) = if self.options.two_phase_commit { (9, 1) } else { (0, 0) };
+        self.check_log_space(header + record_len + marker)?;
+        
+        // Write to log first (crash safety)
+        let start = self
+            .log_put(key.as_bytes(), &value, &tag)
+            .map_err(|e| WriteError::wrap(WriteOp::Put, &key, e))?;
+        self.unflushed = self.options.defer_flush;
+        
+        // Update in-memory index
+        self.stage_put(key, value, tag, start + header + record_len);
+        self.counters.record_puts(1);
+        self.log_records += 1;
+        
+        self.commit_write()
+    }
+
+    /// Writes a put's value file, if it is stored externally, then its
+    /// record. Returns the offset the record was written at, as
+    /// `write_offset` gives it.
+    fn log_put(&mut self, key: &[u8], value: &[u8], tag: &PutTag) -> std::io::Result<u64> {
+        if let PutTag::Blob(blob) = *tag {
+            self.write_blob(blob, value)?;
+        }
+        let start = self.write_offset()?;
+        if self.options.two_phase_commit {
+            let key = key.to_vec();
+            let record = match tag {
+                PutTag::Plain => LogRecord::Put { key, value: value.to_vec() },
+                PutTag::ContentType(content_type) => LogRecord::TypedPut {
+                    key,
+                    content_type: content_type.as_bytes().to_vec(),
+                    value: value.to_vec(),
+                },
+                PutTag::Version(version) => LogRecord::VersionedPut {
+                    key,
+                    version: *version,
+                    value: value.to_vec(),
+                },
+                PutTag::Blob(blob) => LogRecord::BlobPut { key, blob: *blob },
+            };
+            self.log.write_two_phase(&[record])?;
+            return Ok(start);
+        }
+        match tag {
+            PutTag::Plain => self.log.put(key, value)?,
+            PutTag::ContentType(content_type) => {
+                self.log.put_with_content_type(key, value, content_type.as_bytes())?
+            }
+            PutTag::Version(version) => self.log.put_versioned(key, value, *version)?,
+            PutTag::Blob(blob) => self.log.put_blob(key, *blob)?,
+        }
+        Ok(start)
+    }
+
+    /// Stores a value if the key is still at `expected_version`, returning
+    /// the new version, or `None` without writing anything if it isn't.
+    /// 
+    /// Versions start at 0 for keys that don't exist and go up by one with
+    /// each versioned put. The version is logged with the value, so it
+    /// survives reopening. Any other write to the key, such as `put` or
+    /// `delete`, resets its version to 0. With
+    /// `Options::index_after_durable`, the check also sees writes still
+    /// waiting for an fsync.
+    pub fn put_versioned(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        expected_version: u64,
+    ) -> std::io::Result<Option<u64>> {
+        self.check_writable()?;
+        if self.current_version(&self.normalize_key(key)) != expected_version {
+            return Ok(None);
+        }
+        let Some(version) = expected_version.checked_add(1) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Key '{}' is at the maximum version", key),
+            ));
+        };
+        self.put_value(key.to_string(), Cow::Borrowed(value), PutTag::Version(version))?;
+        Ok(Some(version))
+    }
+
+    /// Returns the version a put to `key` would be checked against,
+    /// counting writes queued by `Options::index_after_durable`.
+    fn current_version(&self, key: &str) -> u64 {
+        match self.last_pending(key) {
+            Some(PendingWrite::Put { tag: PutTag::Version(version), .. }) => *version,
+            Some(_) => 0,
+            None => self.versions.get(key).copied().unwrap_or(0),
+        }
+    }
+
+    /// Returns true if `key` is live, counting writes queued by
+    /// `Options::index_after_durable`.
+    fn current_contains(&self, key: &str) -> bool {
+        match self.last_pending(key) {
+            Some(write) => matches!(write, PendingWrite::Put { .. }),
+            None => self.index.contains_key(key),
+        }
+    }
+
+    /// Returns the latest write to `key` queued by
+    /// `Options::index_after_durable`, if any.
+    fn last_pending(&self, key: &str) -> Option<&PendingWrite> {
+        self.pending.iter().rev().find(|write| write.key() == key)
+    }
+
+    /// Stores a value computed by `f` if the key doesn't exist, returning
+    /// whether it did.
+    /// 
+    /// `f` is called only when the value will be stored, so an expensive
+    /// value isn't computed for a key that is already present. With
+    /// `Options::index_after_durable`, writes still waiting for an fsync
+    /// count as present.
+    pub fn put_if_absent_with<F: FnOnce() -> Vec<u8>>(
+        &mut self,
+        key: &str,
+        f: F,
+    ) -> std::io::Result<bool> {
+        self.check_writable()?;
+        if self.current_contains(&self.normalize_key(key)) {
+            return Ok(false);
+        }
+        self.put_owned(key, f())?;
+        Ok(true)
+    }
+
+    /// Applies a record received from another database, such as a primary
+    /// this one follows, logging and indexing it like a local write.
+    /// 
+    /// Versioned puts keep the version they had on the primary. Blob Put
+    /// records fail with `ErrorKind::InvalidData`, since their values are in
+    /// files of the database that logged them. Records are
+    /// applied in the order they arrive and the last write to a key wins:
+    /// there is no conflict resolution, so databases that all accept writes
+    /// and follow each other can diverge. Keys that aren't UTF-8 fail with
+    /// `ErrorKind::InvalidData`.
+    pub fn apply_remote_record(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let key = String::from_utf8(record.key().to_vec()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Record key is not valid UTF-8")
+        })?;
+        match record {
+            LogRecord::Put { value, .. } => {
+                self.put_value(key, Cow::Borrowed(value), PutTag::Plain)
+            }
+            LogRecord::TypedPut { content_type, value, .. } => {
+                let content_type = String::from_utf8_lossy(content_type).into_owned();
+                self.put_value(key, Cow::Borrowed(value), PutTag::ContentType(content_type))
+            }
+            LogRecord::VersionedPut { version, value, .. } => {
+                self.put_value(key, Cow::Borrowed(value), PutTag::Version(*version))
+            }
+            LogRecord::BlobPut { .. } => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Record for key '{}' refers to an external value in another database", key),
+            )),
+            LogRecord::Delete { .. } => self.delete(&key),
+        }
+    }
+
+    /// Merges the final state of the database at `other_dir` into this
+    /// one, with the other database's writes winning conflicts unless
+    /// `Options::conflict_resolver` decides otherwise.
+    /// 
+    /// Each key the other log wrote is applied once, as its last write
+    /// there: live keys are put here with their content type or version,
+    /// and deleted keys are deleted here if present. A value the resolver
+    /// supplies is stored with a plain put. This is a logical
+    /// merge; the other log isn't copied. Keys that aren't UTF-8 are
+    /// skipped, as on open. Fails with `ErrorKind::NotFound` if
+    /// `other_dir` has no log, or `ErrorKind::InvalidData` if the log is
+    /// corrupt. A write failing partway leaves the keys applied so far
+    /// merged.
+    pub fn merge_from(&mut self, other_dir: &Path) -> std::io::Result<MergeReport> {
+        self.check_writable()?;
+        let mut last_writes = BTreeMap::new();
+        for record in Log::read_all(other_dir.join("log"))? {
+            if let Ok(key) = String::from_utf8(record.key().to_vec()) {
+                last_writes.insert(key, record);
+            }
+        }
+        // Only the last reference to a key's blob is sure to have a file
+        for record in last_writes.values_mut() {
+            if let LogRecord::BlobPut { key, blob } = record {
+                let value = fs::read(blob_path(other_dir, *blob))?;
+                *record = LogRecord::Put { key: std::mem::take(key), value };
+            }
+        }
+        
+        let mut report = MergeReport::default();
+        for (key, record) in last_writes {
+            let existed = self.current_contains(&self.normalize_key(&key));
+            match (&record, existed) {
+                (LogRecord::Delete { .. }, false) => continue,
+                (LogRecord::Delete { .. }, true) => report.deleted += 1,
+                (_, false) => report.inserted += 1,
+                (_, true) => match self.resolve_conflict(&key, &record)? {
+                    Resolution::Keep => {
+                        report.kept += 1;
+                        continue;
+                    }
+                    Resolution::Replace => report.overwritten += 1,
+                    Resolution::Value(value) => {
+                        report.overwritten += 1;
+                        self.put(&key, &value)?;
+                        continue;
+                    }
+                },
+            }
+            self.apply_remote_record(&record)?;
+        }
+        Ok(report)
+    }
+
+    /// Asks `Options::conflict_resolver` how to merge the put `record` into
+    /// the existing value of `key`, defaulting to replacing it.
+    fn resolve_conflict(&self, key: &str, record: &LogRecord) -> std::io::Result<Resolution> {
+        let Some(resolver) = &self.options.conflict_resolver else {
+            return Ok(Resolution::Replace);
+        };
+        let incoming = match record {
+            LogRecord::Put { value, .. }
+            | LogRecord::TypedPut { value, .. }
+            | LogRecord::VersionedPut { value, .. } => value,
+            LogRecord::BlobPut { .. } | LogRecord::Delete { .. } => {
+                return Ok(Resolution::Replace)
+            }
+        };
+        let key = self.normalize_key(key);
+        let existing = match self.last_pending(&key) {
+            Some(PendingWrite::Put { value, .. }) => Cow::Borrowed(&value[..]),
+            _ => match self.index.get(key.as_ref()) {
+                Some(value) => self.load_value(&key, value)?,
+                None => return Ok(Resolution::Replace),
+            },
+        };
+        Ok(resolver.resolve(&key, &existing, incoming))
+    }
+
+    /// Stores a value under the next numeric id, returning the id.
+    /// 
+    /// Ids count up from 0, and are stored as keys zero-padded to a fixed
+    /// width (see `Db::push_key`) so they sort in numeric order. The first
+    /// `push` on a handle continues after the highest id-shaped key in the
+    /// database, so numbering carries over across reopens; ids deleted from
+    /// the top can then be assigned again.
+    pub fn push(&mut self, value: &[u8]) -> std::io::Result<u64> {
+        let next = match self.next_push {
+            Some(id) => Some(id),
+            None => self.last_pushed_id().map_or(Some(0), |id| id.checked_add(1)),
+        };
+        let Some(id) = next else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "Push ids exhausted",
+            ));
+        };
+        self.put(&Self::push_key(id), value)?;
+        self.next_push = id.checked_add(1);
+        Ok(id)
+    }
+
+    /// Returns the key `push` stores the value for `id` under.
+    pub fn push_key(id: u64) -> String {
+        format!("{:0width$}", id, width = PUSH_KEY_WIDTH)
+    }
+
+    /// Returns the highest id among keys shaped like those `push` assigns.
+    fn last_pushed_id(&self) -> Option<u64> {
+        let max_key = "9".repeat(PUSH_KEY_WIDTH);
+        self.index
+            .range::<str, _>((Bound::Unbounded, Bound::Included(max_key.as_str())))
+            .rev()
+            .map(|(key, _)| key)
+            .filter(|key| key.len() == PUSH_KEY_WIDTH && key.bytes().all(|b| b.is_ascii_digit()))
+            .find_map(|key| key.parse().ok())
+    }
+
+    /// Stores many key-value pairs so that all or none of them survive a crash.
+    /// 
+    /// Unlike a sequence of `put` calls, where a crash can leave any prefix
+    /// of the writes in the log, the pairs are written as a single batch
+    /// record. A batch torn by a crash fails a strict open; with
+    /// `Options::lenient_recovery` it is skipped entirely. Every key is
+    /// checked and validated before anything is written.
+    pub fn put_all_atomic<I: IntoIterator<Item = (String, Vec<u8>)>>(
+        &mut self,
+        items: I,
+    ) -> std::io::Result<()> {
+        let writes = items.into_iter().map(|(key, value)| (key, Some(value))).collect();
+        self.write_atomic(writes)
+    }
+
+    /// Stores each field of a JSON object as a key holding the field's
+    /// value, serialized compactly, all in one batch like `put_all_atomic`.
+    /// 
+    /// Returns the number of entries stored. Suits bulk ingestion of a
+    /// `{ "key": value, ... }` document: a crash keeps all of it or none.
+    pub fn put_batch_json(
+        &mut self,
+        obj: &serde_json::Map<String, serde_json::Value>,
+    ) -> std::io::Result<usize> {
+        let items = obj
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), serde_json::to_vec(value)?)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        self.put_all_atomic(items)?;
+        Ok(obj.len())
+    }
+
+    /// Runs `f` on a transaction, then commits the writes it buffered if
+    /// it returns `Ok`.
+    /// 
+    /// The writes are committed like `put_all_atomic`, as a single batch
+    /// record, so all or none of them survive a crash. If `f` returns `Err`,
+    /// nothing is written and the error is returned as is. Errors from the
+    /// commit itself are converted into `E`; a failed commit writes nothing
+    /// either, unless the log write itself fails partway.
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Txn) -> Result<T, E>,
+        E: From<std::io::Error>,
+    {
+        let mut txn = Txn::default();
+        let result = f(&mut txn)?;
+        self.write_atomic(txn.writes)?;
+        Ok(result)
+    }
+
+    /// Shared implementation of `put_all_atomic` and `transaction`: writes
+    /// puts (`Some` value) and deletes (`None`) as one batch record, then
+    /// applies them in order.
+    fn write_atomic(&mut self, mut writes: Vec<(String, Option<Vec<u8>>)>) -> std::io::Result<()> {
+        self.check_writable()?;
+        if let Some(normalizer) = &self.options.key_normalizer {
+            for (key, _) in &mut writes {
+                *key = normalizer.normalize(key);
+            }
+        }
+        for (key, value) in &writes {
+            self.check_key(key)?;
+            if let Some(value) = value {
+                self.validate_put(key, value)?;
+            }
+        }
+        
+        // Write to log first (crash safety)
+        let records: Vec<LogRecord> = writes
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => LogRecord::Put {
+                    key: key.as_bytes().to_vec(),
+                    value: value.clone(),
+                },
+                None => LogRecord::Delete {
+                    key: key.as_bytes().to_vec(),
+                },
+            })
+            .collect();
+        let start = self.write_offset()?;
+        if self.options.two_phase_commit {
+            self.check_log_space(Log::two_phase_len(&records))?;
+            self.log.write_two_phase(&records)?;
+        } else {
+            self.check_log_space(Log::batch_len(&records))?;
+            self.log.write_batch(&records)?;
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.log_records += records.len() as u64;
+        self.unflushed = self.options.defer_flush;
+        
+        // Update in-memory index; records follow the 9-byte batch or pending
+        // header
+        let mut end = start + 9;
+        for (key, value) in writes {
+            match value {
+                Some(value) => {
+                    end += Log::put_len(key.as_bytes(), &value);
+                    self.stage_put(key, Cow::Owned(value), PutTag::Plain, end);
+                    self.counters.record_puts(1);
+                }
+                None => {
+                    end += Log::delete_len(key.as_bytes());
+                    self.stage_delete(&key);
+                    self.counters.record_delete();
+                }
+            }
+        }
+        
+        self.commit_write()
+    }
+
+    /// Counts a write for `Options::group_commit`, fsyncing the log if it
+    /// completes a group.
+    /// 
+    /// With `Options::index_after_durable` but no group commit, every write
+    /// is fsynced so it can be applied to the index before returning.
+    fn commit_write(&mut self) -> std::io::Result<()> {
+        let Some(group) = self.options.group_commit else {
+            if self.options.index_after_durable {
+                return self.sync();
+            }
+            return Ok(());
+        };
+        self.unsynced_writes += 1;
+        let oldest = *self.oldest_unsynced.get_or_insert_with(Instant::now);
+        if self.unsynced_writes >= group.max_writes || oldest.elapsed() >= group.max_delay {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the offset the next record will be written at, when needed to
+    /// index values on disk. Zero otherwise, to skip the `fstat`.
+    fn write_offset(&self) -> std::io::Result<u64> {
+        if self.options.values_on_disk {
+            self.log.size_bytes()
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Indexes a put just written to the log, or with
+    /// `Options::index_after_durable` queues it until an fsync covers it.
+    fn stage_put(&mut self, key: String, value: Cow<'_, [u8]>, tag: PutTag, record_end: u64) {
+        if self.options.index_after_durable {
+            self.pending.push(PendingWrite::Put {
+                key,
+                value: value.into_owned(),
+                tag,
+                record_end,
+            });
+            return;
+        }
+        self.index_put(key, value, tag, record_end);
+    }
+
+    /// Removes a key just deleted in the log from the index, or with
+    /// `Options::index_after_durable` queues the removal until an fsync
+    /// covers it.
+    fn stage_delete(&mut self, key: &str) {
+        if self.options.index_after_durable {
+            self.pending.push(PendingWrite::Delete { key: key.to_string() });
+        } else {
+            self.index_delete(key);
+        }
+    }
+
+    /// Indexes a put just written to the log, along with its content type,
+    /// version, or blob, removing any blob it supersedes.
+    fn index_put(&mut self, key: String, value: Cow<'_, [u8]>, tag: PutTag, record_end: u64) {
+        let superseded = self.blobs.get(&key).copied();
+        match tag {
+            PutTag::Plain => self.index_written(key, value, record_end),
+            PutTag::ContentType(content_type) => {
+                self.index_written(key.clone(), value, record_end);
+                self.content_types.insert(key, content_type);
+            }
+            PutTag::Version(version) => {
+                self.index_written(key.clone(), value, record_end);
+                self.versions.insert(key, version);
+            }
+            PutTag::Blob(blob) => {
+                // Always in memory: the value isn't in the log to read back
+                self.index_insert(key.clone(), StoredValue::Owned(value.into()));
+                self.blobs.insert(key, blob);
+            }
+        }
+        if let Some(blob) = superseded {
+            self.remove_superseded_blob(blob);
+        }
+    }
+
+    /// Removes a key just deleted in the log from the index, along with any
+    /// blob holding its value.
+    fn index_delete(&mut self, key: &str) {
+        let superseded = self.blobs.get(key).copied();
+        self.index_remove(key);
+        if let Some(blob) = superseded {
+            self.remove_superseded_blob(blob);
+        }
+    }
+
+    /// Applies writes queued by `Options::index_after_durable`, once an
+    /// fsync has covered them.
+    fn apply_pending(&mut self) {
+        for write in std::mem::take(&mut self.pending) {
+            match write {
+                PendingWrite::Put {
+                    key,
+                    value,
+                    tag,
+                    record_end,
+                } => self.index_put(key, Cow::Owned(value), tag, record_end),
+                PendingWrite::Delete { key } => self.index_delete(&key),
+            }
+        }
+    }
+
+    /// Indexes a value just written to the log, whose record ends at offset
+    /// `record_end`.
+    /// 
+    /// Values go on disk with `Options::values_on_disk`, unless the write is
+    /// still buffered in deferred mode and so can't be read back yet; those
+    /// are also put in the value cache. Otherwise the value is copied into
+    /// the index, in place if it can be.
+    fn index_written(&mut self, key: String, value: Cow<'_, [u8]>, record_end: u64) {
+        if !self.options.values_on_disk || self.options.defer_flush {
+            if !self.overwrite_in_place(&key, &value) {
+                self.index_insert(key, StoredValue::Owned(value.into()));
+            }
+            return;
+        }
+        
+        // The value is the last field of a Put record
+        let range = record_end - value.len() as u64..record_end;
+        // Caching must follow indexing, since indexing evicts the key
+        self.index_insert(key.clone(), StoredValue::OnDisk(range));
+        if let Some(cache) = &self.value_cache {
+            lock(cache).insert(&key, &value);
+        }
+    }
+
+    /// Retrieves a value by key.
+    /// 
+    /// Returns None if the key doesn't exist or was deleted. Values held in
+    /// memory are borrowed; values kept on disk (`Options::values_on_disk`)
+    /// are read from the value cache or the log, which can fail.
+    pub fn get(&self, key: &str) -> std::io::Result<Option<Cow<'_, [u8]>>> {
+        let key = self.normalize_key(key);
+        let value = self.index.get(key.as_ref()).map(|v| self.load_value(&key, v)).transpose()?;
+        self.record_get(&key, value.is_some());
+        Ok(value)
+    }
+
+    /// Retrieves a value by key like `get`, but tells a key that was deleted
+    /// from one that was never written.
+    /// 
+    /// Deleted keys are tracked in memory from replay onward, so this only
+    /// reads the log for a live value kept on disk, as `get` does.
+    pub fn lookup(&self, key: &str) -> std::io::Result<Lookup<'_>> {
+        if let Some(value) = self.get(key)? {
+            return Ok(Lookup::Present(value));
+        }
+        if self.tombstones.contains(self.normalize_key(key).as_ref()) {
+            Ok(Lookup::Deleted)
+        } else {
+            Ok(Lookup::NeverExisted)
+        }
+    }
+
+    /// Counts a read of `key`, and with `Options::track_access_time` records
+    /// when it found the key.
+    fn record_get(&self, key: &str, hit: bool) {
+        self.counters.record_get(hit);
+        if let Some(access_times) = self.access_times.as_ref().filter(|_| hit) {
+            lock(access_times).insert(key.to_string(), Instant::now());
+        }
+    }
+
+    /// Returns the live keys not read since `cutoff`, in sorted order.
+    /// 
+    /// Keys never read since open count as idle, so without
+    /// `Options::track_access_time` every key is returned. Writes don't
+    /// count as reads. Useful for finding cold keys to archive.
+    pub fn keys_idle_since(&self, cutoff: Instant) -> Vec<&String> {
+        let Some(access_times) = &self.access_times else {
+            return self.index.keys().collect();
+        };
+        let access_times = lock(access_times);
+        self.index
+            .keys()
+            .filter(|key| access_times.get(*key).is_none_or(|&read| read < cutoff))
+            .collect()
+    }
+
+    /// Retrieves a JSON value formatted as `format` says, e.g. with sorted
+    /// keys for output that can be diffed.
+    /// 
+    /// Returns `Ok(None)` if the key doesn't exist, and fails with
+    /// `ErrorKind::InvalidData` if its value isn't valid JSON. Reads values
+    /// kept on disk, like `get`.
+    pub fn get_json_formatted(
+        &self,
+        key: &str,
+        format: &FormatOptions,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        self.get(key)?.map(|value| format.format(&value)).transpose()
+    }
+
+    /// Retrieves a value by key along with its version, as set by
+    /// `put_versioned`.
+    /// 
+    /// Values put any other way have version 0. Returns None if the key
+    /// doesn't exist or was deleted. Reads values kept on disk, like `get`.
+    pub fn get_versioned(&self, key: &str) -> std::io::Result<Option<(Cow<'_, [u8]>, u64)>> {
+        let key = self.normalize_key(key);
+        let value = self.index.get(key.as_ref()).map(|v| self.load_value(&key, v)).transpose()?;
+        self.record_get(&key, value.is_some());
+        let version = self.versions.get(key.as_ref()).copied().unwrap_or(0);
+        Ok(value.map(|value| (value, version)))
+    }
+
+    /// Retrieves a value by key, checking that it is well-formed JSON.
+    /// 
+    /// The value is checked without being deserialized into a
+    /// `serde_json::Value`, so this is cheaper than parsing it. Returns None
+    /// if the key doesn't exist or was deleted, and an
+    /// `ErrorKind::InvalidData` error if the value isn't JSON. Reads values
+    /// kept on disk, like `get`, failing if they can't be read.
+    pub fn get_validated_json(&self, key: &str) -> Option<std::io::Result<Cow<'_, [u8]>>> {
+        let value = match self.get(key).transpose()? {
+            Ok(value) => value,
+            Err(e) => return Some(Err(e)),
+        };
+        match serde_json::from_slice::<serde::de::IgnoredAny>(&value) {
+            Ok(_) => Some(Ok(value)),
+            Err(e) => Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Value for key '{}' is not valid JSON: {}", key, e),
+            ))),
+        }
+    }
+
+    /// Retrieves a value by key and deserializes it from JSON, or returns
+    /// `default` if the key doesn't exist or was deleted.
+    /// 
+    /// Values that don't deserialize into `T` also give `default`, as do
+    /// on-disk values (`Options::values_on_disk`) that can't be read; use
+    /// `get` to tell those apart from a missing key.
+    pub fn get_json_or<T: serde::de::DeserializeOwned>(&self, key: &str, default: T) -> T {
+        match self.get(key) {
+            Ok(Some(value)) => serde_json::from_slice(&value).unwrap_or(default),
+            _ => default,
+        }
+    }
+
+    /// Retrieves a value by key as a reference-counted handle.
+    /// 
+    /// Unlike the borrow returned by `get`, the handle stays valid across
+    /// later writes, and keeps the value it was taken from even if the key
+    /// is overwritten or deleted. Values held in memory are shared without
+    /// copying; values kept on disk (`Options::values_on_disk`) are read
+    /// and copied, as `get` does. Returns None if the key doesn't exist
+    /// or was deleted.
+    pub fn get_arc(&self, key: &str) -> std::io::Result<Option<Arc<[u8]>>> {
+        let key = self.normalize_key(key);
+        let value = match self.index.get(key.as_ref()) {
+            Some(StoredValue::Owned(bytes)) => Some(Arc::clone(bytes)),
+            Some(value) => Some(Arc::from(&*self.load_value(&key, value)?)),
+            None => None,
+        };
+        self.record_get(&key, value.is_some());
+        Ok(value)
+    }
+
+    /// Returns the content type of a key's value, or None if the key
+    /// doesn't exist or was deleted.
+    /// 
+    /// Values not put with `put_with_content_type` have
+    /// `DEFAULT_CONTENT_TYPE`.
+    pub fn content_type(&self, key: &str) -> Option<&str> {
+        let key = self.normalize_key(key);
+        if !self.index.contains_key(key.as_ref()) {
+            return None;
+        }
+        let content_type = self.content_types.get(key.as_ref()).map(String::as_str);
+        Some(content_type.unwrap_or(DEFAULT_CONTENT_TYPE))
+    }
+
+    /// Returns the length in bytes of a key's value, or None if the key
+    /// doesn't exist or was deleted.
+    /// 
+    /// The value itself isn't read, so this is cheap even for values kept
+    /// on disk.
+    pub fn get_size(&self, key: &str) -> Option<usize> {
+        self.index.get(self.normalize_key(key).as_ref()).map(StoredValue::len)
+    }
+
+    /// Returns true if the key exists and its value byte-matches `expected`.
+    /// 
+    /// Missing keys never match, not even an empty `expected` value, and
+    /// neither do on-disk values that can't be read.
+    pub fn value_equals(&self, key: &str, expected: &[u8]) -> bool {
+        matches!(self.get(key), Ok(Some(value)) if *value == *expected)
+    }
+
+    /// Returns a checksum of the key's value, or None if the key doesn't
+    /// exist or was deleted.
+    /// 
+    /// The checksum is the value's CRC-32 (as computed by `crc::crc32`),
+    /// so it is stable across processes and versions and can be compared
+    /// with one computed elsewhere. Equal values always have equal
+    /// checksums; different values collide with a chance of about one in
+    /// four billion, so a match means "probably unchanged". On-disk values
+    /// that can't be read have no checksum.
+    pub fn value_checksum(&self, key: &str) -> Option<u64> {
+        let value = self.get(key).ok()??;
+        Some(u64::from(crc32(&value)))
+    }
+
+    /// Deletes a key from the database.
+    /// 
+    /// Invariant: The deletion is logged before the index is updated,
+    /// ensuring crash safety.
+    pub fn delete(&mut self, key: &str) -> std::io::Result<()> {
+        self.check_writable()?;
+        let key = self.normalize_key(key);
+        self.check_key(&key)?;
+        // Two-phase framing adds a 9-byte header and a 1-byte commit marker
+        let framing = if self.options.two_phase_commit { 10 } else { 0 };
+        self.check_log_space(Log::delete_len(key.as_bytes()) + framing)?;
+        
+        // Write to log first (crash safety)
+        let logged = if self.options.two_phase_commit {
+            self.log.write_two_phase(&[LogRecord::Delete { key: key.as_bytes().to_vec() }])
+        } else {
+            self.log.delete(key.as_bytes())
+        };
+        logged.map_err(|e| WriteError::wrap(WriteOp::Delete, &key, e))?;
+        self.unflushed = self.options.defer_flush;
+        
+        // Update in-memory index
+        self.stage_delete(&key);
+        self.counters.record_delete();
+        self.log_records += 1;
+        
+        self.commit_write()
+    }
+
+    /// Returns an iterator over every key with its value parsed as JSON, in
+    /// sorted key order.
+    /// 
+    /// Each value is parsed only when its entry is reached. Values that
+    /// aren't JSON, or with `Options::values_on_disk` can't be read, yield
+    /// an error for that entry and iteration continues.
+    pub fn iter_json(
+        &self,
+    ) -> impl Iterator<Item = (&String, Result<serde_json::Value, serde_json::Error>)> {
+        self.index.iter().map(|(key, value)| {
+            let json = self
+                .load_value(key, value)
+                .map_err(serde_json::Error::io)
+                .and_then(|bytes| serde_json::from_slice(&bytes));
+            (key, json)
+        })
+    }
+
+    /// Returns up to `limit` parsed JSON documents with keys in the given range.
+    /// 
+    /// Entries are yielded in sorted key order. Values that don't parse as
+    /// JSON (or, with `Options::values_on_disk`, can't be read) are skipped
+    /// and don't count toward `limit`. A `limit` of `None` returns every
+    /// document in the range. A range whose start lies after its end is
+    /// empty.
+    pub fn range_json(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: Option<usize>,
+    ) -> Vec<(String, serde_json::Value)> {
+        if is_empty_range(start, end) {
+            return Vec::new();
+        }
+        self.index
+            .range::<str, _>((start, end))
+            .filter_map(|(key, value)| {
+                let bytes = self.load_value(key, value).ok()?;
+                serde_json::from_slice(&bytes)
+                    .ok()
+                    .map(|json| (key.clone(), json))
+            })
+            .take(limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Returns the keys whose value, parsed as JSON, satisfies `pred`, in
+    /// sorted order.
+    /// 
+    /// Scans and parses every value, so it suits ad-hoc queries over small
+    /// and medium databases rather than hot paths. Values that aren't JSON
+    /// (or, with `Options::values_on_disk`, can't be read) are skipped.
+    pub fn find<F: Fn(&serde_json::Value) -> bool>(&self, pred: F) -> Vec<&String> {
+        self.index
+            .iter()
+            .filter(|(key, value)| {
+                let Ok(bytes) = self.load_value(key, value) else {
+                    return false;
+                };
+                serde_json::from_slice(&bytes).is_ok_and(|json| pred(&json))
+            })
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Returns an iterator over all entries in the order their keys were first
+    /// inserted.
+    /// 
+    /// Overwriting a key keeps its original position; deleting it and putting
+    /// it again moves it to the end. Returns `None` unless the database was
+    /// opened with `Options::track_insertion_order`.
+    /// 
+    /// Values kept on disk (`Options::values_on_disk`) are read as each
+    /// entry is reached; one that can't be read yields an error for that
+    /// entry and iteration continues.
+    #[allow(clippy::type_complexity)]
+    pub fn iter_by_insertion(
+        &self,
+    ) -> Option<impl Iterator<Item = (&String, std::io::Result<Cow<'_, [u8]>>)>> {
+        let order = self.insertion_order.as_ref()?;
+        Some(order.key_by_seq.values().filter_map(move |key| {
+            self.index
+                .get_key_value(key)
+                .map(|(key, value)| (key, self.load_value(key, value)))
+        }))
+    }
+
+    /// Replays records appended to the log since it was last read, such as
+    /// writes made by another process.
+    /// 
+    /// Replay resumes from the offset reached by the previous open or
+    /// `reopen`, so only new records are read. Records this handle wrote
+    /// itself are replayed again in log order, which leaves their effect
+    /// unchanged. Deferred writes are flushed first. A malformed or partially
+    /// written record at the tail is left for a later `reopen`.
+    pub fn reopen(&mut self) -> std::io::Result<()> {
+        self.check_writable()?;
+        // Writes waiting for an fsync would otherwise become visible by replay
+        if self.pending.is_empty() {
+            self.flush()?;
+        } else {
+            self.sync()?;
+        }
+        
+        let mut file = File::open(&self.log_path)?;
+        file.seek(SeekFrom::Start(self.log_offset))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        
+        let (spans, consumed) = Log::scan_complete(&bytes);
+        self.log_records += spans.len() as u64;
+        for span in spans {
+            self.apply_span(&bytes, self.log_offset, span);
+        }
+        
+        self.log_offset += consumed as u64;
+        self.load_blobs()
+    }
+
+    /// Streams a snapshot of every live entry to `writer`.
+    /// 
+    /// Snapshot format (binary):
+    /// - Magic: 8 bytes (`DOCDBSNP`)
+    /// - Snapshot version: 4 bytes (u32, little-endian)
+    /// - Entry count: 8 bytes (u64, little-endian)
+    /// - Entries: one Put record per live key (a Typed Put for values with a
+    ///   content type, a Versioned Put for versioned values), in key order,
+    ///   in the log's record format
+    /// 
+    /// The snapshot is logical: it holds the current contents, not the log's
+    /// history. Restore it with `Db::restore_from_reader`.
+    pub fn snapshot_to_writer<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.index.len() as u64).to_le_bytes())?;
+        
+        for (key, value) in &self.index {
+            let key_bytes = key.as_bytes().to_vec();
+            let value = self.load_value(key, value)?.into_owned();
+            let record = match (self.content_types.get(key), self.versions.get(key)) {
+                (Some(content_type), _) => LogRecord::TypedPut {
+                    key: key_bytes,
+                    content_type: content_type.as_bytes().to_vec(),
+                    value,
+                },
+                (None, Some(&version)) => LogRecord::VersionedPut {
+                    key: key_bytes,
+                    version,
+                    value,
+                },
+                (None, None) => LogRecord::Put { key: key_bytes, value },
+            };
+            record.write_to(writer)?;
+        }
+        
+        writer.flush()
+    }
+
+    /// Writes every live entry as one JSON object mapping each key to its
+    /// value, in key order, with the values formatted as `format` says.
+    /// 
+    /// With sorted keys, the same data always exports to the same bytes, so
+    /// exports can be diffed. Fails with `ErrorKind::InvalidData` at the
+    /// first value that isn't valid JSON.
+    pub fn export_json<W: Write>(
+        &self,
+        writer: &mut W,
+        format: &FormatOptions,
+    ) -> std::io::Result<()> {
+        writer.write_all(b"{")?;
+        for (i, (key, value)) in self.index.iter().enumerate() {
+            let value = format.format_nested(&self.load_value(key, value)?, 1)?;
+            if i > 0 {
+                writer.write_all(b",")?;
+            }
+            if format.pretty {
+                writer.write_all(b"\n  ")?;
+            }
+            serde_json::to_writer(&mut *writer, key)?;
+            writer.write_all(if format.pretty { b": " } else { b":" })?;
+            writer.write_all(&value)?;
+        }
+        if format.pretty && !self.index.is_empty() {
+            writer.write_all(b"\n")?;
+        }
+        writer.write_all(b"}\n")?;
+        writer.flush()
+    }
+
+    /// Writes the value of every live key starting with `prefix` (every
+    /// key, if `None`) to `writer`, in key order, each delimited by `frame`.
+    /// 
+    /// Returns the number of values written. Keys aren't written, only
+    /// values, for tools that reprocess documents. Fails at the first
+    /// value that can't be read or framed, with the values before it
+    /// already written.
+    pub fn stream_values<W: Write>(
+        &self,
+        prefix: Option<&str>,
+        writer: &mut W,
+        frame: FrameFn,
+    ) -> std::io::Result<usize> {
+        let prefix = prefix.unwrap_or("");
+        let mut count = 0;
+        for (key, value) in self
+            .index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+        {
+            frame(writer, &self.load_value(key, value)?)?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Writes every live entry as CSV, with a `key,value` header row and one
+    /// row per key in sorted order.
+    /// 
+    /// Values are written as their raw bytes (the JSON text, for JSON
+    /// values), with invalid UTF-8 replaced. Fields containing commas,
+    /// quotes, or line breaks are quoted, with quotes doubled; rows end in
+    /// `\n`.
+    pub fn export_csv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(b"key,value\n")?;
+        for (key, value) in &self.index {
+            let value = self.load_value(key, value)?;
+            let value = String::from_utf8_lossy(&value);
+            writeln!(writer, "{},{}", csv_field(key), csv_field(&value))?;
+        }
+        writer.flush()
+    }
+
+    /// Creates a database at `dir` from a snapshot written by
+    /// `snapshot_to_writer`, and opens it with default options.
+    /// 
+    /// The whole snapshot is validated before the log is written, and the
+    /// log is written with `atomic_write`, so a failed restore leaves no
+    /// database behind. Fails with `ErrorKind::AlreadyExists` if `dir`
+    /// already holds a non-empty log.
+    pub fn restore_from_reader<P: AsRef<Path>, R: Read>(
+        dir: P,
+        reader: &mut R,
+    ) -> std::io::Result<Self> {
+        let dir = dir.as_ref();
+        let invalid = |message: &str| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+        };
+        
+        let log_path = dir.join("log");
+        if fs::metadata(&log_path).is_ok_and(|metadata| metadata.len() > 0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Refusing to restore over existing log {}", log_path.display()),
+            ));
+        }
+        
+        // Read and check the header
+        let mut header = [0u8; 20];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != SNAPSHOT_MAGIC {
+            return Err(invalid("Not a docdb snapshot"));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(&format!("Unsupported snapshot version: {}", version)));
+        }
+        let count = u64::from_le_bytes(header[12..].try_into().unwrap());
+        
+        // Re-encode the entries as the new log
+        let mut log_bytes = Vec::new();
+        for _ in 0..count {
+            match LogRecord::read_from(reader)? {
+                Some(LogRecord::Delete { .. }) => return Err(invalid("Snapshot holds a delete record")),
+                Some(record) => record.write_to(&mut log_bytes)?,
+                None => return Err(invalid("Snapshot ended before its last entry")),
+            }
+        }
+        if LogRecord::read_from(reader)?.is_some() {
+            return Err(invalid("Snapshot has data past its last entry"));
+        }
+        
+        fs::create_dir_all(dir)?;
+        // A checksum of the replaced log would flag the new one
+        match fs::remove_file(dir.join(LOG_CHECKSUM_FILE)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        atomic_write(dir, "log", &log_bytes)?;
+        Self::open(dir)
+    }
+
+    /// Returns every version of `key` recorded in the log at `dir`, oldest
+    /// first, including deletes.
+    /// 
+    /// Reads the log directly rather than an open database's index, so it
+    /// sees overwritten values too. Writes still buffered by an open `Db` in
+    /// deferred mode aren't included. Fails with `ErrorKind::InvalidData` if
+    /// the log is corrupt, or `ErrorKind::NotFound` if an external value
+    /// (see `Options::external_value_bytes`) has since been overwritten and
+    /// its file removed.
+    pub fn history<P: AsRef<Path>>(dir: P, key: &str) -> std::io::Result<Vec<HistoricalVersion>> {
+        let dir = dir.as_ref();
+        let bytes = fs::read(dir.join("log"))?;
+        Log::scan(&bytes)?
+            .into_iter()
+            .filter(|span| &bytes[span.key()] == key.as_bytes())
+            .map(|span| match span {
+                RecordSpan::Put { value, .. } => Ok(HistoricalVersion::Put(bytes[value].to_vec())),
+                RecordSpan::BlobPut { blob, .. } => {
+                    fs::read(blob_path(dir, blob)).map(HistoricalVersion::Put)
+                }
+                RecordSpan::Delete { .. } => Ok(HistoricalVersion::Delete),
+            })
+            .collect()
+    }
+
+    /// Returns the keys whose last record in the log at `dir` is a delete,
+    /// in sorted order.
+    /// 
+    /// Reads the log directly, like `history`, so it sees tombstones the
+    /// index has forgotten. Keys that aren't UTF-8 are skipped, as on open.
+    /// A database with no log has no deleted keys. Fails with
+    /// `ErrorKind::InvalidData` if the log is corrupt.
+    pub fn deleted_keys<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<String>> {
+        let bytes = match fs::read(dir.as_ref().join("log")) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut deleted = BTreeMap::new();
+        for span in Log::scan(&bytes)? {
+            let is_delete = matches!(span, RecordSpan::Delete { .. });
+            deleted.insert(&bytes[span.key()], is_delete);
+        }
+        Ok(deleted
+            .into_iter()
+            .filter(|&(_, is_delete)| is_delete)
+            .filter_map(|(key, _)| String::from_utf8(key.to_vec()).ok())
+            .collect())
+    }
+
+    /// Writes the log records of the database at `dir` from byte `offset` to
+    /// the end of the log, for incremental backups, returning the offset
+    /// they end at and a CRC-32 of the bytes written.
+    /// 
+    /// Pass 0 for a full backup, then each returned `end` to the next call:
+    /// concatenated in order, the exports form a log that opens to the
+    /// database's state as of the last one. Only complete records are
+    /// written, each parsed first, so a record partway through being
+    /// appended waits for the next export. Records carry no checksum, so
+    /// parsing is the only check on the log: the export stops before a
+    /// malformed record, but damage that still parses is copied as is.
+    /// Store the returned `crc` with the export, and check the export
+    /// against it before restoring, to catch damage to the backup itself.
+    /// 
+    /// `offset` must be 0 or an offset this returned; past the end of the
+    /// log it fails with `ErrorKind::InvalidInput`. Offsets are only valid
+    /// for the log they came from: `compact_keeping_versions`,
+    /// `restore_from_reader`, and anything else that rewrites the log
+    /// invalidate them, so start again from 0 afterwards. Values stored in
+    /// their own files (see `Options::external_value_bytes`) aren't in the
+    /// log, so back up `blobs/` alongside.
+    pub fn export_since<P: AsRef<Path>, W: Write>(
+        dir: P,
+        offset: u64,
+        writer: &mut W,
+    ) -> std::io::Result<LogExport> {
+        let mut file = match File::open(dir.as_ref().join("log")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && offset == 0 => {
+                return Ok(LogExport { end: 0, crc: crc32(&[]) });
+            }
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        if offset > len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Offset {} is past the end of the {} byte log", offset, len),
+            ));
+        }
+        
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (_, consumed) = Log::scan_complete(&bytes);
+        let exported = &bytes[..consumed];
+        writer.write_all(exported)?;
+        writer.flush()?;
+        Ok(LogExport {
+            end: offset + consumed as u64,
+            crc: crc32(exported),
+        })
+    }
+
+    /// Removes files left in `dir` by interrupted docdb operations,
+    /// returning the paths removed.
+    /// 
+    /// Only files docdb creates and can abandon are candidates: the temp
+    /// files an atomic replacement of the log (such as
+    /// `restore_from_reader`), of its checksum file, or of a blob writes
+    /// before renaming them into place, and blob files no live key in the
+    /// log refers to, such as one written for a put that failed before its
+    /// record was logged. Blobs are only judged when the log reads cleanly,
+    /// so a damaged log never costs a value. The log and any file docdb
+    /// didn't create are never touched, since the directory may hold other
+    /// data. Run this only while no process has the database open, or a
+    /// replacement in progress could lose its temp file.
+    pub fn cleanup_orphans<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        let blob_dir = dir.join(BLOB_DIR);
+        // Every temp file docdb writes in a database directory
+        let mut candidates = vec![temp_path(dir, "log"), temp_path(dir, LOG_CHECKSUM_FILE)];
+        candidates.extend(orphaned_blobs(dir, &blob_dir)?);
+        
+        let mut removed = Vec::new();
+        for path in candidates {
+            match fs::remove_file(&path) {
+                Ok(()) => removed.push(path),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        
+        // Make the removals durable
+        if removed.iter().any(|path| path.parent() == Some(dir)) {
+            sync_dir(dir)?;
+        }
+        if removed.iter().any(|path| path.parent() == Some(&*blob_dir)) {
+            sync_dir(&blob_dir)?;
+        }
+        Ok(removed)
+    }
+
+    /// Checks that the in-memory index matches a from-scratch replay of the
+    /// log.
+    /// 
+    /// Re-reads the whole log (including writes still buffered in deferred
+    /// mode), rebuilds a reference index, and compares keys and values.
+    /// Writes `Options::index_after_durable` still holds back are left out,
+    /// since the index doesn't have them yet either. Returns `Ok(false)` on
+    /// any mismatch, which indicates a bug in how the index is maintained.
+    /// Corruption in the log is handled as on open: skipped with
+    /// `Options::lenient_recovery`, an error otherwise.
+    pub fn verify_consistency(&self) -> std::io::Result<bool> {
+        if let Some(checkpoint) = self.checkpoint {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Can't verify a database opened at {:?}", checkpoint),
+            ));
+        }
+        let mut bytes = fs::read(&self.log_path)?;
+        bytes.extend_from_slice(self.log.buffered());
+        let spans = if self.options.lenient_recovery {
+            Log::scan_lenient(&bytes).0
+        } else {
+            Log::scan(&bytes)?
+        };
+        
+        // External values are read only for live keys, since the files of
+        // superseded ones are gone
+        let mut reference: BTreeMap<String, Result<&[u8], u64>> = BTreeMap::new();
+        // Queued writes are the log's last records, one each
+        let applied = spans.len().saturating_sub(self.pending.len());
+        for span in spans.into_iter().take(applied) {
+            // Same key handling as `apply_record`
+            let Ok(key) = std::str::from_utf8(&bytes[span.key()]) else {
+                continue;
+            };
+            match span {
+                RecordSpan::Put { value, .. } => {
+                    reference.insert(key.to_string(), Ok(&bytes[value]));
+                }
+                RecordSpan::BlobPut { blob, .. } => {
+                    reference.insert(key.to_string(), Err(blob));
+                }
+                RecordSpan::Delete { .. } => {
+                    reference.remove(key);
+                }
+            }
+        }
+        
+        if reference.len() != self.index.len() {
+            return Ok(false);
+        }
+        for ((ref_key, ref_value), (key, value)) in reference.iter().zip(&self.index) {
+            let ref_value = match ref_value {
+                Ok(value) => Cow::Borrowed(*value),
+                Err(blob) => Cow::Owned(fs::read(blob_path(self.dir(), *blob))?),
+            };
+            if ref_key != key || *ref_value != *self.load_value(key, value)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the report from the most recent log replay.
+    /// 
+    /// Records can only be skipped when opened with
+    /// `Options::lenient_recovery`; otherwise corruption fails the open.
+    pub fn last_recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    /// Returns a snapshot of operation counters and current size gauges.
+    pub fn metrics(&self) -> std::io::Result<Metrics> {
+        let live_keys = self.index.len() as u64;
+        Ok(Metrics::new(&self.counters, live_keys, self.log.size_bytes()?))
+    }
+
+    /// Estimates the fraction of the log's records that still hold a live
+    /// key's current value.
+    /// 
+    /// Counts records rather than bytes, so it costs nothing to compute but
+    /// ignores differences in value size. Every superseded put and every
+    /// delete lowers the ratio; a low ratio means compaction would reclaim
+    /// most of the log. An empty log counts as fully live.
+    pub fn estimate_live_ratio(&self) -> f64 {
+        if self.log_records == 0 {
+            return 1.0;
+        }
+        (self.index.len() as f64 / self.log_records as f64).min(1.0)
+    }
+
+    /// Rewrites the log keeping only each key's last `n` puts, plus the
+    /// deletes between them and the delete if that is the key's last write,
+    /// then reopens the database on the new log. Returns the number of
+    /// records dropped.
+    /// 
+    /// With `n = 1` the log keeps each live key's current value, and each
+    /// deleted key's last value and delete. Larger `n` keeps that many
+    /// versions per key for `Db::history`, so the log holds up to `n` times
+    /// the live data, plus up to `n` values per deleted key. Kept records
+    /// stay in log order. Batches are rewritten as separate records, and
+    /// experimental records and deletes older than a key's oldest kept put
+    /// are dropped.
+    /// 
+    /// With `Options::external_value_bytes`, a superseded external value's
+    /// file is normally removed, so its reference no longer counts as a
+    /// version and is dropped. One whose file survived is rewritten with
+    /// its value inline, leaving only live keys referring to blob files.
+    /// 
+    /// The new log replaces the old with `atomic_write`, so a crash leaves
+    /// one or the other. Other handles on the database must be reopened
+    /// afterwards. Fails with `ErrorKind::InvalidInput` if `n` is 0, which
+    /// would drop live values, or `ErrorKind::InvalidData` if the log is
+    /// corrupt.
+    pub fn compact_keeping_versions(&mut self, n: usize) -> std::io::Result<usize> {
+        self.check_writable()?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Compaction must keep at least one version of each key",
+            ));
+        }
+        self.sync()?;
+        
+        let dir = self.dir().to_path_buf();
+        let bytes = fs::read(&self.log_path)?;
+        let spans = Log::scan(&bytes)?;
+        let is_live = |key: &[u8], blob: u64| {
+            std::str::from_utf8(key).ok().and_then(|key| self.blobs.get(key)) == Some(&blob)
+        };
+        // Walk back from the newest record, counting each key's puts. A
+        // delete before the key's last write waits in `deletes` until an
+        // older put is kept, which puts it between two kept versions
+        let mut puts_kept: HashMap<&[u8], usize> = HashMap::new();
+        let mut deletes: HashMap<&[u8], Vec<usize>> = HashMap::new();
+        let mut keep = vec![false; spans.len()];
+        for (i, span) in spans.iter().enumerate().rev() {
+            let key = &bytes[span.key()];
+            if let RecordSpan::BlobPut { blob, .. } = span {
+                // A superseded value whose file is gone can't be kept
+                if !is_live(key, *blob) && !blob_path(&dir, *blob).exists() {
+                    continue;
+                }
+            }
+            if let RecordSpan::Delete { .. } = span {
+                match puts_kept.get(key) {
+                    None => {
+                        puts_kept.insert(key, 0);
+                        keep[i] = true;
+                    }
+                    Some(&kept) if kept < n => deletes.entry(key).or_default().push(i),
+                    Some(_) => {}
+                }
+                continue;
+            }
+            let kept = puts_kept.entry(key).or_insert(0);
+            if *kept < n {
+                *kept += 1;
+                keep[i] = true;
+                for delete in deletes.remove(key).unwrap_or_default() {
+                    keep[delete] = true;
+                }
+            }
+        }
+        
+        let mut log_bytes = Vec::new();
+        for (span, _) in spans.iter().zip(&keep).filter(|(_, &keep)| keep) {
+            let record = match span {
+                // Only live keys keep referring to blob files
+                RecordSpan::BlobPut { key, blob } if !is_live(&bytes[key.clone()], *blob) => {
+                    LogRecord::Put {
+                        key: bytes[key.clone()].to_vec(),
+                        value: fs::read(blob_path(&dir, *blob))?,
+                    }
+                }
+                span => span.to_record(&bytes),
+            };
+            record.write_to(&mut log_bytes)?;
+        }
+        let dropped = keep.iter().filter(|&&keep| !keep).count();
+        
+        // A checksum of the replaced log would flag the new one
+        match fs::remove_file(dir.join(LOG_CHECKSUM_FILE)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        atomic_write(&dir, "log", &log_bytes)?;
+        
+        // Values on disk point into the old log, so rebuild from
+        // scratch, carrying the operation counters over
+        let mut reopened = Self::open_with_options(&dir, self.options.clone())?;
+        reopened.counters = std::mem::take(&mut self.counters);
+        *self = reopened;
+        Ok(dropped)
+    }
+
+    /// Releases memory the index and its secondary structures hold beyond
+    /// what the live keys need.
+    /// 
+    /// Worth calling after deleting many keys in a long-running process.
+    /// The ordered index frees its nodes as keys are removed; the hash
+    /// tables behind insertion order, access times, content types,
+    /// versions, blob references, tombstones, and the value cache keep
+    /// their peak capacity until shrunk.
+    pub fn shrink_to_fit(&mut self) {
+        if let Some(order) = &mut self.insertion_order {
+            order.shrink_to_fit();
+        }
+        if let Some(access_times) = &self.access_times {
+            lock(access_times).shrink_to_fit();
+        }
+        if let Some(cache) = &self.value_cache {
+            lock(cache).shrink_to_fit();
+        }
+        self.content_types.shrink_to_fit();
+        self.versions.shrink_to_fit();
+        self.blobs.shrink_to_fit();
+        self.tombstones.shrink_to_fit();
+    }
+
+    /// Returns the smallest live key, or `None` if the database is empty.
+    pub fn first_key(&self) -> Option<&String> {
+        self.index.first_key_value().map(|(key, _)| key)
+    }
+
+    /// Returns the largest live key, or `None` if the database is empty.
+    pub fn last_key(&self) -> Option<&String> {
+        self.index.last_key_value().map(|(key, _)| key)
+    }
+
+    /// Returns true if any live key starts with `prefix`.
+    /// 
+    /// Looks only at the first key at or after `prefix` in sorted order, so
+    /// the cost doesn't grow with the number of matches. Every key starts
+    /// with the empty prefix.
+    pub fn contains_prefix(&self, prefix: &str) -> bool {
+        self.index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .next()
+            .is_some_and(|(key, _)| key.starts_with(prefix))
+    }
+
+    /// Counts the live keys in each namespace: the part of the key before
+    /// the first `delimiter`.
+    /// 
+    /// A key without the delimiter is its own namespace, so `user:1`,
+    /// `user:2`, and `user` all count towards `user`.
+    pub fn prefix_histogram(&self, delimiter: char) -> BTreeMap<String, usize> {
+        let mut histogram = BTreeMap::new();
+        for key in self.index.keys() {
+            let namespace = key.split(delimiter).next().unwrap_or(key);
+            // Keys are sorted, so most keys land in the last namespace counted
+            match histogram.last_entry() {
+                Some(mut entry) if entry.key() == namespace => *entry.get_mut() += 1,
+                _ => *histogram.entry(namespace.to_string()).or_insert(0) += 1,
+            }
+        }
+        histogram
+    }
+
+    /// Returns the keys matching a shell-style glob such as
+    /// `session:*:active`, in sorted order.
+    /// 
+    /// See `Glob` for the supported syntax.
+    pub fn keys_matching(&self, pattern: &str) -> Vec<&String> {
+        let glob = Glob::new(pattern);
+        self.index.keys().filter(|key| glob.matches(key)).collect()
+    }
+
+    /// Returns an iterator over all keys in the database.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.index.keys()
+    }
+
+    /// Returns an iterator over all keys in sorted order.
+    /// 
+    /// The index is ordered, so keys are yielded straight from it, with no
+    /// collecting or sorting, whatever the size of the database.
+    pub fn keys_sorted(&self) -> impl Iterator<Item = &String> {
+        self.index.keys()
+    }
+
+    /// Returns up to `limit` keys sorting strictly after `after`, or from
+    /// the first key if `after` is `None`, in sorted order.
+    /// 
+    /// The keys are owned, so a page can outlive a borrow of the `Db`, such
+    /// as a lock guard. Pass the last key of a page as `after` to get the
+    /// next one; an empty page means there are no more.
+    pub fn keys_page(&self, after: Option<&str>, limit: usize) -> Vec<String> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        self.range_keys(start, Bound::Unbounded, Some(limit))
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns up to `limit` keys within the given range, in sorted order.
+    /// 
+    /// A `limit` of `None` returns every key in the range. A range whose
+    /// start lies after its end is empty.
+    pub fn range_keys(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: Option<usize>,
+    ) -> Vec<&String> {
+        if is_empty_range(start, end) {
+            return Vec::new();
+        }
+        self.index
+            .range::<str, _>((start, end))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Flushes writes buffered in deferred mode to the OS.
+    /// 
+    /// A no-op when every write is already flushed.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.log.flush()?;
+        self.unflushed = false;
+        Ok(())
+    }
+
+    /// Flushes buffered writes and fsyncs the log.
+    /// 
+    /// `flush` only hands writes to the OS, which can still lose them on
+    /// power failure; after `sync` returns they are on disk.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.log.sync()?;
+        self.unflushed = false;
+        self.unsynced_writes = 0;
+        self.oldest_unsynced = None;
+        self.counters.record_sync();
+        self.apply_pending();
+        Ok(())
+    }
+
+    /// Fsyncs the log if writes are waiting for a group commit, returning
+    /// whether it did.
+    /// 
+    /// `GroupCommit::max_delay` is only checked when a write arrives, so a
+    /// server should call this once its write queue goes idle (or at
+    /// `sync_deadline`) and only then acknowledge the waiting writes.
+    pub fn sync_waiting(&mut self) -> std::io::Result<bool> {
+        if self.unsynced_writes == 0 {
+            return Ok(false);
+        }
+        self.sync()?;
+        Ok(true)
+    }
+
+    /// Returns when the oldest write waiting for a group commit reaches
+    /// `GroupCommit::max_delay`, or `None` if no write is waiting.
+    pub fn sync_deadline(&self) -> Option<Instant> {
+        let group = self.options.group_commit?;
+        self.oldest_unsynced.map(|oldest| oldest + group.max_delay)
+    }
+
+    /// Shuts the database down for process exit.
+    /// 
+    /// Like `close`, but also fsyncs the log, so nothing acknowledged is lost
+    /// even if the machine goes down right after. Intended for signal and
+    /// shutdown handlers.
+    pub fn shutdown(mut self) -> std::io::Result<()> {
+        self.sync()?;
+        self.write_log_checksum()
+    }
+
+    /// Closes the database.
+    /// 
+    /// Flushes any writes buffered in deferred mode, reporting errors that a
+    /// plain drop would have to swallow. With `Options::group_commit`, also
+    /// fsyncs any writes still waiting for one.
+    pub fn close(mut self) -> std::io::Result<()> {
+        if self.unsynced_writes > 0 || !self.pending.is_empty() {
+            self.sync()?;
+        } else {
+            self.flush()?;
+        }
+        self.write_log_checksum()
+    }
+
+    /// Records the log's length and CRC-32 for the next open to check, under
+    /// `Options::log_checksum`.
+    fn write_log_checksum(&self) -> std::io::Result<()> {
+        if self.options.log_checksum == LogChecksumPolicy::Off {
+            return Ok(());
+        }
+        let (len, crc) = checksum_file(&self.log_path, u64::MAX)?;
+        let mut contents = len.to_le_bytes().to_vec();
+        contents.extend_from_slice(&crc.to_le_bytes());
+        atomic_write(self.dir(), LOG_CHECKSUM_FILE, &contents)
+    }
+
+    /// Checks the log against the checksum recorded at its last clean
+    /// close, under `Options::log_checksum`.
+    /// 
+    /// Returns whether it mismatched, or fails with `LogChecksumPolicy::Error`.
+    /// A database never cleanly closed with checksums on has nothing to
+    /// check.
+    fn check_log_checksum(&self) -> std::io::Result<bool> {
+        let policy = self.options.log_checksum;
+        if policy == LogChecksumPolicy::Off {
+            return Ok(false);
+        }
+        let recorded = match fs::read(self.dir().join(LOG_CHECKSUM_FILE)) {
+            Ok(recorded) => recorded,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        
+        // A malformed checksum file counts as a mismatch
+        let matches = match <[u8; 12]>::try_from(&recorded[..]) {
+            Ok(recorded) => {
+                let len = u64::from_le_bytes(recorded[..8].try_into().unwrap());
+                let crc = u32::from_le_bytes(recorded[8..].try_into().unwrap());
+                checksum_file(&self.log_path, len)? == (len, crc)
+            }
+            Err(_) => false,
+        };
+        if matches {
+            return Ok(false);
+        }
+        if policy == LogChecksumPolicy::Warn {
+            return Ok(true);
+        }
+        
+        let message = format!(
+            "Log {} doesn't match the checksum recorded at its last clean close; it was truncated or modified since",
+            self.log_path.display()
+        );
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message))
+    }
+
+    /// Closes the database and deletes its files from disk.
+    /// 
+    /// Removes the log, its checksum file, and any temp file left by an
+    /// interrupted atomic write. The directory itself is kept, along with
+    /// any files docdb didn't create.
+    pub fn destroy(mut self) -> std::io::Result<()> {
+        self.check_writable()?;
+        self.flush()?;
+        let log_path = self.log_path.clone();
+        // Release the log handle before deleting
+        drop(self);
+        
+        let dir = log_path.parent().unwrap_or(Path::new("."));
+        let checksum_paths = [dir.join(LOG_CHECKSUM_FILE), temp_path(dir, LOG_CHECKSUM_FILE)];
+        for path in [temp_path(dir, "log"), log_path.clone()].into_iter().chain(checksum_paths) {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match fs::remove_dir_all(dir.join(BLOB_DIR)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        
+        // Make the removal durable
+        sync_dir(dir)
+    }
+}
+
+/// Returns the length and CRC-32 of the first `limit` bytes of the file at
+/// `path`, or of all of it if shorter.
+fn checksum_file(path: &Path, limit: u64) -> std::io::Result<(u64, u32)> {
+    let mut file = File::open(path)?.take(limit);
+    let mut crc = Crc32::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok((len, crc.finish()));
+        }
+        crc.update(&buf[..n]);
+        len += n as u64;
+    }
+}
+
+/// Returns the path of the file holding blob `blob` in the database at
+/// `dir`.
+fn blob_path(dir: &Path, blob: u64) -> PathBuf {
+    dir.join(BLOB_DIR).join(blob.to_string())
+}
+
+/// Returns the files in `blob_dir` that `Db::cleanup_orphans` may remove:
+/// blob temp files, and blobs no live key in the log at `dir` refers to.
+/// 
+/// Blobs are left alone when there is no log or it fails to read, since
+/// then there is nothing trustworthy to judge them by.
+fn orphaned_blobs(dir: &Path, blob_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(blob_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    
+    // Replay the log's references, keeping only the last one per key
+    let live: Option<HashSet<u64>> = Log::read_all(dir.join("log")).ok().map(|records| {
+        let mut blobs = HashMap::new();
+        for record in records {
+            match record {
+                LogRecord::BlobPut { key, blob } => {
+                    blobs.insert(key, blob);
+                }
+                LogRecord::Put { key, .. }
+                | LogRecord::TypedPut { key, .. }
+                | LogRecord::VersionedPut { key, .. }
+                | LogRecord::Delete { key } => {
+                    blobs.remove(&key);
+                }
+            }
+        }
+        blobs.into_values().collect()
+    });
+    
+    let mut orphans = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let orphaned = if name.ends_with(".tmp") {
+            true
+        } else if let (Ok(blob), Some(live)) = (name.parse::<u64>(), &live) {
+            !live.contains(&blob)
+        } else {
+            false
+        };
+        if orphaned && entry.file_type()?.is_file() {
+            orphans.push(entry.path());
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// Escapes a CSV field, quoting it if it contains a comma, quote, or line
+/// break.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Returns true if no key can fall between `start` and `end`, because the
+/// start lies after the end or they exclude the one key they share.
+/// 
+/// `BTreeMap::range` panics on such ranges, so check this first.
+fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    let (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) =
+        (start, end)
+    else {
+        return false;
+    };
+    let both_included = matches!((start, end), (Bound::Included(_), Bound::Included(_)));
+    s > e || (s == e && !both_included)
+}
+
+/// Locks a mutex, ignoring poisoning.
+/// 
+/// The mutexes in `Db` guard a seek position, which every read resets, and
+/// the value cache, whose updates can't panic partway through.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Drop for Db {
+    /// Attempts a final flush, then applies `Options::unflushed_drop` if the
+    /// database held deferred writes that were never explicitly flushed.
+    /// 
+    /// Writes waiting for a group commit fsync get one attempt too.
+    fn drop(&mut self) {
+        if self.unsynced_writes > 0 {
+            let _ = self.log.sync();
+        }
+        if !self.unflushed {
+            return;
+        }
+        let result = self.log.flush();
+        let message = match result {
+            Ok(()) => "Warning: deferred-mode database dropped without flush or close; flushed on drop".to_string(),
+            Err(e) => format!("Warning: deferred-mode database dropped without flush or close; final flush failed: {}", e),
+        };
+        match self.options.unflushed_drop {
+            UnflushedDropPolicy::Ignore => {}
+            UnflushedDropPolicy::Warn => match &self.options.warning_handler {
+                Some(handler) => handler.warn(&message),
+                None => eprintln!("{}", message),
+            },
+            UnflushedDropPolicy::Panic => {
+                if !std::thread::panicking() {
+                    panic!("{}", message);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_put_and_get() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        db.put("key1", b"value1").unwrap();
+        
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+        assert_eq!(db.get("nonexistent").unwrap().as_deref(), None);
+    }
+
+    #[test]
+    fn test_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        db.put("key1", b"value1").unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+        
+        db.delete("key1").unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), None);
+    }
+
+    #[test]
+    fn test_get_arc_outlives_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("key1", b"value1").unwrap();
+        
+        let held = db.get_arc("key1").unwrap().unwrap();
+        // Writes are allowed while the handle is held
+        db.put("key1", b"value2").unwrap();
+        db.put("key2", b"other").unwrap();
+        assert_eq!(&*held, b"value1");
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&b"value2"[..]));
+        
+        let latest = db.get_arc("key1").unwrap().unwrap();
+        db.delete("key1").unwrap();
+        assert_eq!(&*latest, b"value2");
+        assert_eq!(&*held, b"value1");
+        assert!(db.get_arc("key1").unwrap().is_none());
+        
+        // Handles share the stored value rather than copying it
+        let first = db.get_arc("key2").unwrap().unwrap();
+        let second = db.get_arc("key2").unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        
+        // Values kept on disk are read into a fresh handle
+        drop(db);
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(&*db.get_arc("key2").unwrap().unwrap(), b"other");
+        assert!(db.get_arc("key1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_validated_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("doc", br#"{"tags": ["a", "b"], "n": 1.5}"#).unwrap();
+        db.put("raw", b"{not json").unwrap();
+        db.put("trailing", b"{} {}").unwrap();
+        
+        let doc = db.get_validated_json("doc").unwrap().unwrap();
+        assert_eq!(&*doc, br#"{"tags": ["a", "b"], "n": 1.5}"#);
+        
+        let err = db.get_validated_json("raw").unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("'raw'"));
+        assert!(db.get_validated_json("trailing").unwrap().is_err());
+        
+        assert!(db.get_validated_json("missing").is_none());
+        
+        // Values kept on disk are read and checked the same way
+        drop(db);
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        let doc = db.get_validated_json("doc").unwrap().unwrap();
+        assert_eq!(&*doc, br#"{"tags": ["a", "b"], "n": 1.5}"#);
+        assert!(db.get_validated_json("raw").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_value_equals() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        db.put("key1", b"value1").unwrap();
+        db.put("empty", b"").unwrap();
+        
+        assert!(db.value_equals("key1", b"value1"));
+        assert!(!db.value_equals("key1", b"value2"));
+        assert!(!db.value_equals("key1", b"value"));
+        assert!(db.value_equals("empty", b""));
+        assert!(!db.value_equals("missing", b""));
+        assert!(!db.value_equals("missing", b"value1"));
+    }
+
+    #[test]
+    fn test_value_checksum() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        db.put("a", br#"{"n": 1}"#).unwrap();
+        db.put("b", br#"{"n": 1}"#).unwrap();
+        let checksum = db.value_checksum("a").unwrap();
+        assert_eq!(checksum, u64::from(crc32(br#"{"n": 1}"#)));
+        // Identical values match, whatever the key
+        assert_eq!(db.value_checksum("b"), Some(checksum));
+        
+        db.put("a", br#"{"n": 2}"#).unwrap();
+        assert_ne!(db.value_checksum("a"), Some(checksum));
+        db.put("a", br#"{"n": 1}"#).unwrap();
+        assert_eq!(db.value_checksum("a"), Some(checksum));
+        
+        db.delete("a").unwrap();
+        assert_eq!(db.value_checksum("a"), None);
+        assert_eq!(db.value_checksum("missing"), None);
+        
+        // Stable across reopens, including with values kept on disk
+        drop(db);
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.value_checksum("b"), Some(checksum));
+    }
+
+    #[test]
+    fn test_deleted_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(Db::deleted_keys(temp_dir.path()).unwrap().is_empty());
+        
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("b", b"1").unwrap();
+        db.delete("b").unwrap();
+        db.put("a", b"1").unwrap();
+        db.delete("a").unwrap();
+        db.put("revived", b"1").unwrap();
+        db.delete("revived").unwrap();
+        db.put("revived", b"2").unwrap();
+        db.put("live", b"1").unwrap();
+        db.delete("never_put").unwrap();
+        
+        let deleted = Db::deleted_keys(temp_dir.path()).unwrap();
+        assert_eq!(deleted, vec!["a", "b", "never_put"]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("live", b"1").unwrap();
+        db.put("gone", b"1").unwrap();
+        db.delete("gone").unwrap();
+        db.put("revived", b"1").unwrap();
+        db.delete("revived").unwrap();
+        db.put("revived", b"2").unwrap();
+        
+        assert_eq!(db.lookup("live").unwrap(), Lookup::Present(Cow::from(&b"1"[..])));
+        assert_eq!(db.lookup("gone").unwrap(), Lookup::Deleted);
+        assert_eq!(db.lookup("revived").unwrap(), Lookup::Present(Cow::from(&b"2"[..])));
+        assert_eq!(db.lookup("missing").unwrap(), Lookup::NeverExisted);
+        
+        // Rebuilt from the log on replay, including with values kept on disk
+        drop(db);
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.lookup("live").unwrap(), Lookup::Present(Cow::from(&b"1"[..])));
+        assert_eq!(db.lookup("gone").unwrap(), Lookup::Deleted);
+        assert_eq!(db.lookup("revived").unwrap(), Lookup::Present(Cow::from(&b"2"[..])));
+        assert_eq!(db.lookup("missing").unwrap(), Lookup::NeverExisted);
+    }
+
+    #[test]
+    fn test_export_since() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("db");
+        let mut backup = Vec::new();
+        let export = Db::export_since(&dir, 0, &mut backup).unwrap();
+        assert_eq!(export, LogExport { end: 0, crc: 0 });
+        
+        let mut db = Db::open(&dir).unwrap();
+        db.put("a", b"1").unwrap();
+        db.put("b", b"2").unwrap();
+        let full = Db::export_since(&dir, 0, &mut backup).unwrap();
+        let full_end = full.end;
+        assert_eq!(full_end, backup.len() as u64);
+        assert_eq!(full.crc, crc32(&backup));
+        
+        db.put("a", b"3").unwrap();
+        db.delete("b").unwrap();
+        db.put("c", b"4").unwrap();
+        let incremental = Db::export_since(&dir, full_end, &mut backup).unwrap();
+        let end = incremental.end;
+        assert_eq!(end, fs::metadata(dir.join("log")).unwrap().len());
+        // Each export's checksum covers only what it wrote
+        assert_eq!(incremental.crc, crc32(&backup[full_end as usize..]));
+        // Nothing new since the last export
+        let mut empty = Vec::new();
+        assert_eq!(Db::export_since(&dir, end, &mut empty).unwrap(), LogExport { end, crc: 0 });
+        assert!(empty.is_empty());
+        
+        // A record partway through being appended waits for the next export
+        let mut log = fs::OpenOptions::new().append(true).open(dir.join("log")).unwrap();
+        let mut record = Vec::new();
+        let put = LogRecord::Put { key: b"d".to_vec(), value: b"5".to_vec() };
+        put.write_to(&mut record).unwrap();
+        log.write_all(&record[..record.len() - 1]).unwrap();
+        assert_eq!(Db::export_since(&dir, end, &mut empty).unwrap().end, end);
+        
+        let err = Db::export_since(&dir, end + 100, &mut empty).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        
+        // Full plus incremental restores the state as of the last export
+        let restored_dir = temp_dir.path().join("restored");
+        fs::create_dir(&restored_dir).unwrap();
+        fs::write(restored_dir.join("log"), &backup).unwrap();
+        let restored = Db::open(&restored_dir).unwrap();
+        assert_eq!(restored.keys().collect::<Vec<_>>(), vec!["a", "c"]);
+        assert_eq!(restored.get("a").unwrap().as_deref(), Some(&b"3"[..]));
+        assert_eq!(restored.get("c").unwrap().as_deref(), Some(&b"4"[..]));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_write_error_context() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("abc", b"1").unwrap();
+        // Every write to /dev/full fails with ENOSPC
+        db.log = Log::open("/dev/full").unwrap();
+        
+        let err = db.put("abc", b"2").unwrap_err();
+        assert!(err.to_string().starts_with("failed to PUT key \"abc\": "), "{}", err);
+        let write_error = err.get_ref().unwrap().downcast_ref::<WriteError>().unwrap();
+        assert_eq!(write_error.op, WriteOp::Put);
+        assert_eq!(err.kind(), write_error.source.kind());
+        
+        let err = db.delete("abc").unwrap_err();
+        assert!(err.to_string().starts_with("failed to DELETE key \"abc\": "), "{}", err);
+        assert_eq!(db.get("abc").unwrap().as_deref(), Some(&b"1"[..]));
+        
+        // Huge keys are cut short
+        let long_key = "k".repeat(1000);
+        let err = db.put(&long_key, b"1").unwrap_err();
+        let expected = format!("failed to PUT key \"{}...\": ", "k".repeat(64));
+        assert!(err.to_string().starts_with(&expected), "{}", err);
+        
+        // Writes rejected before reaching the log aren't wrapped
+        db.options.max_key_bytes = Some(4);
+        let err = db.put("too long", b"1").unwrap_err();
+        assert!(err.get_ref().is_none_or(|inner| !inner.is::<WriteError>()));
+    }
+
+    #[test]
+    fn test_compact_keeping_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        for i in 1..=10 {
+            db.put("doc", format!("v{}", i).as_bytes()).unwrap();
+        }
+        db.put_all_atomic(vec![
+            ("gone".to_string(), b"g1".to_vec()),
+            ("other".to_string(), b"o1".to_vec()),
+        ])
+        .unwrap();
+        db.delete("gone").unwrap();
+        db.put("gone", b"g2").unwrap();
+        db.put("gone", b"g3").unwrap();
+        db.delete("gone").unwrap();
+        let put = |value: &str| HistoricalVersion::Put(value.as_bytes().to_vec());
+        
+        let err = db.compact_keeping_versions(0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        
+        // Of 16 records, keeps 3 of doc's 10, gone's 3 puts and both its
+        // deletes, and other's one
+        assert_eq!(db.compact_keeping_versions(3).unwrap(), 7);
+        let history = Db::history(temp_dir.path(), "doc").unwrap();
+        assert_eq!(history, vec![put("v8"), put("v9"), put("v10")]);
+        let history = Db::history(temp_dir.path(), "gone").unwrap();
+        let delete = HistoricalVersion::Delete;
+        assert_eq!(history, vec![put("g1"), delete.clone(), put("g2"), put("g3"), delete]);
+        assert_eq!(db.get("doc").unwrap().as_deref(), Some(&b"v10"[..]));
+        assert_eq!(db.get("gone").unwrap().as_deref(), None);
+        assert_eq!(db.get("other").unwrap().as_deref(), Some(&b"o1"[..]));
+        
+        // The compacted database keeps working and reopens to the same state
+        db.put("doc", b"v11").unwrap();
+        assert_eq!(db.compact_keeping_versions(1).unwrap(), 6);
+        drop(db);
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["doc", "other"]);
+        assert_eq!(db.get("doc").unwrap().as_deref(), Some(&b"v11"[..]));
+        let history = Db::history(temp_dir.path(), "gone").unwrap();
+        assert_eq!(history, vec![put("g3"), HistoricalVersion::Delete]);
+        
+        // A delete between kept puts stays; one older than them goes
+        db.delete("again").unwrap();
+        db.put("again", b"1").unwrap();
+        db.delete("again").unwrap();
+        db.put("again", b"2").unwrap();
+        db.compact_keeping_versions(5).unwrap();
+        let history = Db::history(temp_dir.path(), "again").unwrap();
+        assert_eq!(history, vec![put("1"), HistoricalVersion::Delete, put("2")]);
+    }
+
+    #[test]
+    fn test_compact_keeping_versions_external_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let options = Options { external_value_bytes: Some(4), ..Options::default() };
+        let mut db = Db::open_with_options(dir, options.clone()).unwrap();
+        for key in ["a", "b"] {
+            db.put(key, b"v1").unwrap();
+            db.put(key, b"value 2").unwrap();
+            db.put(key, b"value 3").unwrap();
+        }
+        // b's superseded blob survived, as if its removal had failed
+        fs::write(dir.join(BLOB_DIR).join("2"), b"value 2").unwrap();
+        let put = |value: &str| HistoricalVersion::Put(value.as_bytes().to_vec());
+        
+        // a's second value is gone, so an older one is kept in its place;
+        // b's second is moved into the log
+        assert_eq!(db.compact_keeping_versions(2).unwrap(), 2);
+        assert_eq!(Db::history(dir, "a").unwrap(), vec![put("v1"), put("value 3")]);
+        assert_eq!(Db::history(dir, "b").unwrap(), vec![put("value 2"), put("value 3")]);
+        
+        // The surviving blob is no longer referenced
+        drop(db);
+        assert_eq!(Db::cleanup_orphans(dir).unwrap(), vec![dir.join(BLOB_DIR).join("2")]);
+        assert_eq!(Db::history(dir, "b").unwrap(), vec![put("value 2"), put("value 3")]);
+        let db = Db::open_with_options(dir, options).unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some(&b"value 3"[..]));
+        assert_eq!(db.get("b").unwrap().as_deref(), Some(&b"value 3"[..]));
+    }
+
+    #[test]
+    fn test_max_key_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            max_key_bytes: Some(8),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        
+        db.put("12345678", b"value").unwrap();
+        assert_eq!(db.get("12345678").unwrap().as_deref(), Some(b"value".as_slice()));
+        
+        let err = db.put("123456789", b"value").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = db.delete("123456789").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        db.delete("12345678").unwrap();
+        db.close().unwrap();
+        
+        // Rejected operations never reach the log
+        let records = Log::read_all(temp_dir.path().join("log")).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn test_max_log_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let record_len = Log::put_len(b"key1", b"value1");
+        let options = Options {
+            max_log_bytes: Some(3 * record_len),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        
+        db.put("key1", b"value1").unwrap();
+        db.put("key2", b"value2").unwrap();
+        db.put("key3", b"value3").unwrap();
+        
+        let err = db.put("key4", b"value4").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        let err = db.delete("key1").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        let err = db
+            .put_all_atomic(vec![("key5".to_string(), b"value5".to_vec())])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+        assert_eq!(db.get("key4").unwrap().as_deref(), None);
+        db.close().unwrap();
+        
+        // Rejected operations never reach the log, and the limit holds
+        // across reopens
+        let len = fs::metadata(temp_dir.path().join("log")).unwrap().len();
+        assert_eq!(len, 3 * record_len);
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        let err = db.put("k", b"").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn test_put_json_str() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        db.put_json_str("doc", r#"{"name": "Ada", "tags": [1, 2]}"#).unwrap();
+        assert_eq!(
+            db.get("doc").unwrap().as_deref(),
+            Some(&br#"{"name": "Ada", "tags": [1, 2]}"#[..])
+        );
+        db.put_json_str("number", "42").unwrap();
+        assert_eq!(db.get("number").unwrap().as_deref(), Some(&b"42"[..]));
+        
+        for invalid in ["{not json", "", "1 2", "[1,]"] {
+            let err = db.put_json_str("bad", invalid).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", invalid);
+        }
+        assert_eq!(db.get("bad").unwrap().as_deref(), None);
+        
+        // The parse error's position comes through
+        let err = db.put_json_str("bad", "{\"a\": }").unwrap_err();
+        assert!(err.to_string().contains("line 1 column 7"), "{}", err);
+    }
+
+    #[test]
+    fn test_validators() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            key_validator: Some(Validator::new(|key: &str| {
+                if key.starts_with("user:") {
+                    Ok(())
+                } else {
+                    Err("keys must start with 'user:'".to_string())
+                }
+            })),
+            value_validator: Some(Validator::new(|value: &[u8]| {
+                serde_json::from_slice::<serde_json::Value>(value)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            })),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        
+        db.put("user:1", br#"{"name": "a"}"#).unwrap();
+        
+        let err = db.put("admin", br#"{"name": "b"}"#).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("keys must start with 'user:'"));
+        let err = db.put("user:2", b"not json").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = db
+            .put_all_atomic(vec![
+                ("user:3".to_string(), b"{}".to_vec()),
+                ("user:4".to_string(), b"{".to_vec()),
+            ])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(db.get("user:3").unwrap().as_deref(), None);
+        db.close().unwrap();
+        
+        // Rejected writes never reach the log
+        let records = Log::read_all(temp_dir.path().join("log")).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn test_range_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        db.put("c", br#"{"n": 3}"#).unwrap();
+        db.put("a", br#"{"n": 1}"#).unwrap();
+        db.put("d", br#"{"n": 4}"#).unwrap();
+        db.put("b", b"not json").unwrap();
+        db.put("e", br#"{"n": 5}"#).unwrap();
+        
+        let keys = |entries: Vec<(String, serde_json::Value)>| -> Vec<String> {
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+        
+        // Sorted order, non-JSON values skipped
+        let all = db.range_json(Bound::Unbounded, Bound::Unbounded, None);
+        assert_eq!(keys(all.clone()), vec!["a", "c", "d", "e"]);
+        assert_eq!(all[0].1, serde_json::json!({"n": 1}));
+        
+        // Limit cuts off after the first matches in the range
+        let limited = db.range_json(Bound::Included("b"), Bound::Excluded("e"), Some(1));
+        assert_eq!(keys(limited), vec!["c"]);
+        
+        // Open-ended range
+        let tail = db.range_json(Bound::Excluded("c"), Bound::Unbounded, None);
+        assert_eq!(keys(tail), vec!["d", "e"]);
+        
+        let empty = db.range_json(Bound::Included("x"), Bound::Unbounded, Some(10));
+        assert!(empty.is_empty());
+        
+        // Inverted ranges are empty rather than a panic
+        assert!(db.range_json(Bound::Included("d"), Bound::Included("a"), None).is_empty());
+        assert!(db.range_json(Bound::Excluded("c"), Bound::Excluded("c"), None).is_empty());
+        let single = db.range_json(Bound::Included("c"), Bound::Included("c"), None);
+        assert_eq!(keys(single), vec!["c"]);
+    }
+
+    #[test]
+    fn test_find() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("order3", br#"{"status": "open", "total": 5}"#).unwrap();
+        db.put("order1", br#"{"status": "open", "total": 12}"#).unwrap();
+        db.put("order2", br#"{"status": "shipped"}"#).unwrap();
+        db.put("note", b"status: open").unwrap();
+        db.put("count", b"42").unwrap();
+        
+        let open = db.find(|json| json["status"] == "open");
+        assert_eq!(open, vec!["order1", "order3"]);
+        
+        let large = db.find(|json| json["total"].as_u64().is_some_and(|total| total > 10));
+        assert_eq!(large, vec!["order1"]);
+        
+        assert!(db.find(|json| json["status"] == "cancelled").is_empty());
+        // The non-JSON value is never offered to the predicate
+        assert_eq!(db.find(|_| true).len(), 4);
+    }
+
+    #[test]
+    fn test_iter_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("user2", br#"{"name": "Bob", "tags": []}"#).unwrap();
+        db.put("user1", br#"{"name": "Alice", "age": 30}"#).unwrap();
+        db.put("count", b"42").unwrap();
+        db.put("broken", b"{oops").unwrap();
+        
+        let entries: Vec<_> = db.iter_json().collect();
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["broken", "count", "user1", "user2"]);
+        
+        assert!(entries[0].1.as_ref().unwrap_err().is_syntax());
+        assert_eq!(*entries[1].1.as_ref().unwrap(), serde_json::json!(42));
+        assert_eq!(
+            *entries[2].1.as_ref().unwrap(),
+            serde_json::json!({"name": "Alice", "age": 30})
+        );
+        assert_eq!(
+            *entries[3].1.as_ref().unwrap(),
+            serde_json::json!({"name": "Bob", "tags": []})
+        );
+        
+        // Works with values kept on disk too
+        drop(db);
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        let parsed = db.iter_json().filter(|(_, json)| json.is_ok()).count();
+        assert_eq!(parsed, 3);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            track_insertion_order: true,
+            track_access_time: true,
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            db.put_with_content_type(&key, b"value", "text/plain").unwrap();
+            db.put_versioned(&format!("ver{}", i), b"value", 0).unwrap().unwrap();
+            db.get(&key).unwrap();
+        }
+        for i in 10..1000 {
+            db.delete(&format!("key{}", i)).unwrap();
+            db.delete(&format!("ver{}", i)).unwrap();
+        }
+        
+        let capacities = |db: &Db| {
+            let order = db.insertion_order.as_ref().unwrap();
+            [
+                order.seq_by_key.capacity(),
+                lock(db.access_times.as_ref().unwrap()).capacity(),
+                db.content_types.capacity(),
+                db.versions.capacity(),
+            ]
+        };
+        let before = capacities(&db);
+        db.shrink_to_fit();
+        let after = capacities(&db);
+        assert!(after.iter().zip(before).all(|(after, before)| *after < before));
+        
+        // Putting deleted keys again clears their tombstones
+        let tombstones_before = db.tombstones.capacity();
+        for i in 10..1000 {
+            db.put(&format!("key{}", i), b"value").unwrap();
+        }
+        db.shrink_to_fit();
+        assert!(db.tombstones.capacity() < tombstones_before);
+        
+        // Shrinking doesn't lose anything
+        assert_eq!(db.keys().count(), 1010);
+        assert_eq!(db.iter_by_insertion().unwrap().count(), 1010);
+        assert_eq!(db.content_type("key9"), Some("text/plain"));
+        assert_eq!(db.get_versioned("ver9").unwrap().map(|(_, version)| version), Some(1));
+        assert_eq!(db.keys_idle_since(Instant::now()), db.keys().collect::<Vec<_>>());
+        assert!(db.verify_consistency().unwrap());
+    }
+
+    #[test]
+    fn test_keys_matching() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        for key in ["session:2:active", "session:1:active", "session:3:idle", "user1", "user22"] {
+            db.put(key, b"{}").unwrap();
+        }
+        
+        assert_eq!(db.keys_matching("session:*:active"), vec!["session:1:active", "session:2:active"]);
+        assert_eq!(db.keys_matching("user?"), vec!["user1"]);
+        assert_eq!(db.keys_matching("session:[13]:*"), vec!["session:1:active", "session:3:idle"]);
+        assert!(db.keys_matching("account:*").is_empty());
+        assert_eq!(db.keys_matching("*").len(), 5);
+    }
+
+    #[test]
+    fn test_range_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        for key in ["user:3", "user:1", "admin", "user:2", "zebra"] {
+            db.put(key, b"{}").unwrap();
+        }
+        
+        let users = db.range_keys(Bound::Included("user:"), Bound::Excluded("user;"), None);
+        assert_eq!(users, vec!["user:1", "user:2", "user:3"]);
+        let first_two = db.range_keys(Bound::Included("user:"), Bound::Excluded("user;"), Some(2));
+        assert_eq!(first_two, vec!["user:1", "user:2"]);
+        
+        assert_eq!(
+            db.range_keys(Bound::Excluded("user:1"), Bound::Included("user:3"), None),
+            vec!["user:2", "user:3"]
+        );
+        assert_eq!(db.range_keys(Bound::Unbounded, Bound::Excluded("user:1"), None), vec!["admin"]);
+        assert_eq!(db.range_keys(Bound::Unbounded, Bound::Unbounded, None).len(), 5);
+        assert!(db.range_keys(Bound::Unbounded, Bound::Unbounded, Some(0)).is_empty());
+        
+        // Empty results, including inverted ranges
+        assert!(db.range_keys(Bound::Included("b"), Bound::Excluded("u"), None).is_empty());
+        assert!(db.range_keys(Bound::Included("z"), Bound::Included("a"), None).is_empty());
+        assert!(db.range_keys(Bound::Excluded("admin"), Bound::Excluded("admin"), None).is_empty());
+        assert_eq!(
+            db.range_keys(Bound::Included("admin"), Bound::Included("admin"), None),
+            vec!["admin"]
+        );
+    }
+
+    #[test]
+    fn test_keys_sorted() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        for i in [17, 3, 250, 42, 8, 100, 1] {
+            db.put(&format!("key{}", i), b"{}").unwrap();
+        }
+        db.put("Key", b"{}").unwrap();
+        db.put("é", b"{}").unwrap();
+        db.delete("key42").unwrap();
+        
+        let mut collected: Vec<&String> = db.keys().collect();
+        collected.sort();
+        let sorted: Vec<&String> = db.keys_sorted().collect();
+        assert_eq!(sorted, collected);
+        assert_eq!(sorted.first().unwrap().as_str(), "Key");
+        assert_eq!(sorted.last().unwrap().as_str(), "é");
+        assert_eq!(sorted.len(), 8);
+    }
+
+    #[test]
+    fn test_keys_page() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        for key in ["d", "b", "e", "a", "c"] {
+            db.put(key, b"{}").unwrap();
+        }
+        
+        // Paging with the last key of each page visits every key once
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = db.keys_page(after.as_deref(), 2);
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().cloned();
+            pages.push(page);
+        }
+        assert_eq!(pages, vec![vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+        
+        // The cursor is exclusive and needn't be a live key
+        assert_eq!(db.keys_page(Some("b"), 10), vec!["c", "d", "e"]);
+        assert_eq!(db.keys_page(Some("bb"), 1), vec!["c"]);
+        assert_eq!(db.keys_page(Some("e"), 10), Vec::<String>::new());
+        assert_eq!(db.keys_page(None, 0), Vec::<String>::new());
+        
+        // Pages don't borrow the database
+        let page = db.keys_page(None, 1);
+        db.delete("a").unwrap();
+        assert_eq!(page, vec!["a"]);
+    }
+
+    #[test]
+    fn test_prefix_histogram() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        assert!(db.prefix_histogram(':').is_empty());
+        
+        for key in ["user:1", "user:2", "user", "user2", "session:a:b", "session:c", "cfg"] {
+            db.put(key, b"1").unwrap();
+        }
+        db.put("order:1", b"1").unwrap();
+        db.delete("order:1").unwrap();
+        
+        let histogram = db.prefix_histogram(':');
+        let expected = [("cfg", 1), ("session", 2), ("user", 3), ("user2", 1)];
+        assert_eq!(
+            histogram,
+            expected.map(|(namespace, count)| (namespace.to_string(), count)).into()
+        );
+        assert_eq!(db.prefix_histogram('/').len(), 7);
+    }
+
+    #[test]
+    fn test_contains_prefix() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        assert!(!db.contains_prefix(""));
+        assert!(!db.contains_prefix("user:"));
+        
+        db.put("session:1", b"1").unwrap();
+        db.put("user:alice", b"2").unwrap();
+        db.put("users", b"3").unwrap();
+        assert!(db.contains_prefix(""));
+        assert!(db.contains_prefix("user:"));
+        assert!(db.contains_prefix("user:alice"));
+        assert!(!db.contains_prefix("user:bob"));
+        assert!(!db.contains_prefix("admin:"));
+        assert!(!db.contains_prefix("zzz"));
+        
+        db.delete("user:alice").unwrap();
+        assert!(!db.contains_prefix("user:"));
+        assert!(db.contains_prefix("user"));
+    }
+
+    #[test]
+    fn test_first_and_last_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.first_key(), None);
+        assert_eq!(db.last_key(), None);
+        
+        db.put("m", b"1").unwrap();
+        assert_eq!(db.first_key().map(String::as_str), Some("m"));
+        assert_eq!(db.last_key().map(String::as_str), Some("m"));
+        
+        db.put("z", b"2").unwrap();
+        db.put("a", b"3").unwrap();
+        db.put("q", b"4").unwrap();
+        assert_eq!(db.first_key().map(String::as_str), Some("a"));
+        assert_eq!(db.last_key().map(String::as_str), Some("z"));
+        
+        // Bounds track deletes
+        db.delete("a").unwrap();
+        db.delete("z").unwrap();
+        assert_eq!(db.first_key().map(String::as_str), Some("m"));
+        assert_eq!(db.last_key().map(String::as_str), Some("q"));
+        
+        db.delete("m").unwrap();
+        db.delete("q").unwrap();
+        assert_eq!(db.first_key(), None);
+        assert_eq!(db.last_key(), None);
+    }
+
+    #[test]
+    fn test_deferred_flush_on_close() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            defer_flush: true,
+            unflushed_drop: UnflushedDropPolicy::Panic,
+            ..Options::default()
+        };
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key1", b"value1").unwrap();
+        db.close().unwrap();
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+    }
+
+    #[test]
+    fn test_deferred_unflushed_drop_reports_and_flushes() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            defer_flush: true,
+            unflushed_drop: UnflushedDropPolicy::Panic,
+            ..Options::default()
+        };
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key1", b"value1").unwrap();
+        let dropped = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(db)));
+        assert!(dropped.is_err(), "Dropping an unflushed deferred db should be reported");
+        
+        // The drop still flushed the buffered write
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+    }
+
+    #[test]
+    fn test_deferred_unflushed_drop_warns_through_handler() {
+        let temp_dir = TempDir::new().unwrap();
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let captured = Arc::clone(&warnings);
+        let options = Options {
+            defer_flush: true,
+            warning_handler: Some(WarningHandler::new(move |message| {
+                captured.lock().unwrap().push(message.to_string());
+            })),
+            ..Options::default()
+        };
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        db.put("key1", b"value1").unwrap();
+        drop(db);
+        let logged = warnings.lock().unwrap().clone();
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].contains("dropped without flush or close"), "{}", logged[0]);
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&b"value1"[..]));
+        drop(db);
+        
+        // A flushed db has nothing to warn about
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key2", b"value2").unwrap();
+        db.flush().unwrap();
+        drop(db);
+        assert_eq!(warnings.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_deferred_flushed_drop_is_silent() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            defer_flush: true,
+            unflushed_drop: UnflushedDropPolicy::Panic,
+            ..Options::default()
+        };
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key1", b"value1").unwrap();
+        db.flush().unwrap();
+        drop(db);
+    }
+
+    #[test]
+    fn test_shutdown_syncs_deferred_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            defer_flush: true,
+            unflushed_drop: UnflushedDropPolicy::Panic,
+            ..Options::default()
+        };
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key1", b"value1").unwrap();
+        db.delete("key1").unwrap();
+        db.put("key2", b"value2").unwrap();
+        assert_eq!(fs::metadata(temp_dir.path().join("log")).unwrap().len(), 0);
+        
+        // No unflushed-drop panic: shutdown counts as an explicit flush
+        db.shutdown().unwrap();
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), None);
+        assert_eq!(db.get("key2").unwrap().as_deref(), Some(b"value2".as_slice()));
+    }
+
+    #[test]
+    fn test_iter_by_insertion() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            track_insertion_order: true,
+            ..Options::default()
+        };
+        
+        let insertion_keys = |db: &Db| -> Vec<String> {
+            db.iter_by_insertion()
+                .unwrap()
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        
+        {
+            let mut db = Db::open_with_options(temp_dir.path(), options.clone()).unwrap();
+            db.put("c", b"1").unwrap();
+            db.put("a", b"2").unwrap();
+            db.put("d", b"3").unwrap();
+            db.put("b", b"4").unwrap();
+            
+            // Overwrite keeps position, delete + re-put moves to the end
+            db.put("c", b"5").unwrap();
+            db.delete("a").unwrap();
+            db.put("a", b"6").unwrap();
+            
+            let sorted: Vec<String> = db.keys().cloned().collect();
+            assert_eq!(sorted, vec!["a", "b", "c", "d"]);
+            assert_eq!(insertion_keys(&db), vec!["c", "d", "b", "a"]);
+            
+            let (key, value) = db.iter_by_insertion().unwrap().next().unwrap();
+            assert_eq!((key.as_str(), &*value.unwrap()), ("c", b"5".as_slice()));
+            db.close().unwrap();
+        }
+        
+        // Order is rebuilt from the log on reopen, with values kept on disk
+        // read as they are reached
+        let on_disk = Options {
+            values_on_disk: true,
+            ..options
+        };
+        let db = Db::open_with_options(temp_dir.path(), on_disk).unwrap();
+        assert_eq!(insertion_keys(&db), vec!["c", "d", "b", "a"]);
+        let values: Vec<_> = db.iter_by_insertion().unwrap().map(|(_, v)| v.unwrap()).collect();
+        assert_eq!(values, vec![&b"5"[..], b"3", b"4", b"6"]);
+        
+        // Tracking is opt-in
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert!(db.iter_by_insertion().is_none());
+    }
+
+    #[test]
+    fn test_lenient_recovery() {
+        use std::io::Write;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        
+        {
+            let mut db = Db::open(temp_dir.path()).unwrap();
+            db.put("key1", b"value1").unwrap();
+            db.close().unwrap();
+        }
+        {
+            let mut file = fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+            file.write_all(&[0xAB; 7]).unwrap();
+        }
+        {
+            let mut log = Log::open(&log_path).unwrap();
+            log.put(b"key2", b"value2").unwrap();
+        }
+        
+        assert!(Db::open(temp_dir.path()).is_err());
+        
+        let options = Options {
+            lenient_recovery: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+        assert_eq!(db.get("key2").unwrap().as_deref(), Some(b"value2".as_slice()));
+        assert_eq!(
+            db.last_recovery_report(),
+            &RecoveryReport {
+                records_recovered: 2,
+                records_skipped: 1,
+                bytes_skipped: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn test_put_all_atomic() {
+        let temp_dir = TempDir::new().unwrap();
+        let items = || {
+            vec![
+                ("a".to_string(), b"1".to_vec()),
+                ("b".to_string(), b"2".to_vec()),
+                ("a".to_string(), b"3".to_vec()),
+            ]
+        };
+        
+        {
+            let mut db = Db::open(temp_dir.path()).unwrap();
+            db.put_all_atomic(items()).unwrap();
+            assert_eq!(db.get("a").unwrap().as_deref(), Some(b"3".as_slice()));
+            assert_eq!(db.get("b").unwrap().as_deref(), Some(b"2".as_slice()));
+            db.close().unwrap();
+        }
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some(b"3".as_slice()));
+        assert_eq!(db.get("b").unwrap().as_deref(), Some(b"2".as_slice()));
+        
+        // A rejected key aborts the whole batch before it is logged
+        let options = Options {
+            max_key_bytes: Some(1),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        let err = db
+            .put_all_atomic(vec![
+                ("c".to_string(), b"4".to_vec()),
+                ("too_long".to_string(), b"5".to_vec()),
+            ])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(db.get("c").unwrap().as_deref(), None);
+    }
+
+    #[test]
+    fn test_two_phase_commit_ignores_uncommitted_write() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        let options = Options {
+            two_phase_commit: true,
+            values_on_disk: true,
+            ..Options::default()
+        };
+        
+        {
+            let mut db = Db::open_with_options(temp_dir.path(), options.clone()).unwrap();
+            db.put("a", b"1").unwrap();
+            db.put_with_content_type("b", b"<b/>", "application/xml").unwrap();
+            db.put_all_atomic(vec![
+                ("c".to_string(), b"3".to_vec()),
+                ("d".to_string(), b"4".to_vec()),
+            ])
+            .unwrap();
+            db.delete("d").unwrap();
+            assert_eq!(db.get("b").unwrap().as_deref(), Some(&b"<b/>"[..]));
+            assert_eq!(db.get("c").unwrap().as_deref(), Some(&b"3"[..]));
+            db.put("e", b"5").unwrap();
+            db.close().unwrap();
+        }
+        
+        // Simulate a crash after the last put's record was fsynced but
+        // before its commit marker was written
+        let len = fs::metadata(&log_path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        
+        let (mut db, report) = Db::open_with_report(temp_dir.path(), options.clone()).unwrap();
+        assert_eq!(report.records_replayed, 5);
+        assert_eq!(report.truncated_tail_bytes, 9 + Log::put_len(b"e", b"5"));
+        assert_eq!(db.get("a").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get("c").unwrap().as_deref(), Some(&b"3"[..]));
+        assert_eq!(db.get("d").unwrap(), None);
+        assert_eq!(db.get("e").unwrap(), None);
+        
+        // Writes after the abandoned record are replayed past it
+        db.put("f", b"6").unwrap();
+        assert_eq!(db.get("f").unwrap().as_deref(), Some(&b"6"[..]));
+        db.close().unwrap();
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["a", "b", "c", "f"]);
+        assert_eq!(db.get("b").unwrap().as_deref(), Some(&b"<b/>"[..]));
+        assert_eq!(db.get("e").unwrap().as_deref(), None);
+    }
+
+    #[test]
+    fn test_put_all_atomic_torn_batch_recovers_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        
+        {
+            let mut db = Db::open(temp_dir.path()).unwrap();
+            db.put("before", b"value").unwrap();
+            db.put_all_atomic(vec![
+                ("key1".to_string(), b"value1".to_vec()),
+                ("key2".to_string(), b"value2".to_vec()),
+                ("key3".to_string(), b"value3".to_vec()),
+            ])
+            .unwrap();
+            db.close().unwrap();
+        }
+        
+        // Simulate a crash midway through the batch: the first two items are
+        // fully on disk, the third is cut short
+        let len = fs::metadata(&log_path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        
+        assert!(Db::open(temp_dir.path()).is_err());
+        
+        let options = Options {
+            lenient_recovery: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.get("before").unwrap().as_deref(), Some(b"value".as_slice()));
+        assert_eq!(db.get("key1").unwrap().as_deref(), None);
+        assert_eq!(db.get("key2").unwrap().as_deref(), None);
+        assert_eq!(db.get("key3").unwrap().as_deref(), None);
+        assert_eq!(db.last_recovery_report().records_recovered, 1);
+        assert_eq!(db.last_recovery_report().records_skipped, 1);
+    }
+
+    #[test]
+    fn test_put_batch_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        let serde_json::Value::Object(entries) = serde_json::json!({
+            "user:1": {"name": "Ada", "tags": ["admin"]},
+            "user:2": {"name": "Grace"},
+            "count": 2,
+        }) else {
+            unreachable!();
+        };
+        
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("before", b"value").unwrap();
+        assert_eq!(db.put_batch_json(&serde_json::Map::new()).unwrap(), 0);
+        assert_eq!(db.put_batch_json(&entries).unwrap(), 3);
+        assert_eq!(
+            db.get("user:1").unwrap().as_deref(),
+            Some(&br#"{"name":"Ada","tags":["admin"]}"#[..])
+        );
+        assert_eq!(db.get("user:2").unwrap().as_deref(), Some(&br#"{"name":"Grace"}"#[..]));
+        assert_eq!(db.get("count").unwrap().as_deref(), Some(&b"2"[..]));
+        db.close().unwrap();
+        
+        // A crash partway through the batch loses every entry in it
+        let len = fs::metadata(&log_path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let options = Options {
+            lenient_recovery: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["before"]);
+    }
+
+    #[test]
+    fn test_values_on_disk() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let large = vec![9u8; 1 << 20];
+        let fetch = |db: &Db, key: &str| db.get(key).unwrap().map(Cow::into_owned);
+        
+        {
+            let mut db = Db::open_with_options(temp_dir.path(), options.clone()).unwrap();
+            db.put("key1", b"value1").unwrap();
+            db.put("large", &large).unwrap();
+            db.put_all_atomic(vec![
+                ("doc".to_string(), br#"{"n": 1}"#.to_vec()),
+                ("key2".to_string(), b"value2".to_vec()),
+            ])
+            .unwrap();
+            db.delete("key2").unwrap();
+            
+            // Writes made through this handle are read back from disk too
+            assert!(matches!(db.index.get("doc"), Some(StoredValue::OnDisk(_))));
+            assert_eq!(fetch(&db, "key1"), Some(b"value1".to_vec()));
+            assert_eq!(fetch(&db, "doc"), Some(br#"{"n": 1}"#.to_vec()));
+            assert_eq!(fetch(&db, "key2"), None);
+            db.close().unwrap();
+        }
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert!(matches!(db.index.get("large"), Some(StoredValue::OnDisk(_))));
+        assert_eq!(fetch(&db, "key1"), Some(b"value1".to_vec()));
+        assert_eq!(fetch(&db, "large"), Some(large.clone()));
+        assert_eq!(fetch(&db, "key2"), None);
+        assert!(db.value_equals("key1", b"value1"));
+        let docs = db.range_json(Bound::Unbounded, Bound::Unbounded, None);
+        assert_eq!(docs, vec![("doc".to_string(), serde_json::json!({"n": 1}))]);
+        assert!(db.verify_consistency().unwrap());
+        
+        db.put("key1", b"value3").unwrap();
+        assert_eq!(fetch(&db, "key1"), Some(b"value3".to_vec()));
+        
+        // get reads on-disk values too
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&b"value3"[..]));
+        
+        // Other modes serve get from memory
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert!(matches!(db.get("key1").unwrap(), Some(Cow::Borrowed(b"value3"))));
+    }
+
+    #[test]
+    fn test_warm() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("a", b"1").unwrap();
+        db.put("b", b"2").unwrap();
+        db.put("c", b"3").unwrap();
+        // Values in memory have nothing to load
+        assert_eq!(db.warm(&["a", "b"]).unwrap(), 0);
+        db.close().unwrap();
+        
+        let options = Options {
+            values_on_disk: true,
+            value_cache_bytes: Some(100),
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.warm(&["a", "b", "missing"]).unwrap(), 2);
+        // Already cached
+        assert_eq!(db.warm(&["a"]).unwrap(), 0);
+        let metrics = db.metrics().unwrap();
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (0, 0));
+        
+        assert_eq!(db.get("a").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get("b").unwrap().as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get("c").unwrap().as_deref(), Some(&b"3"[..]));
+        let metrics = db.metrics().unwrap();
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (2, 1));
+    }
+
+    #[test]
+    fn test_value_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        {
+            let mut db = Db::open(temp_dir.path()).unwrap();
+            db.put("a", &[1u8; 40]).unwrap();
+            db.put("b", &[2u8; 40]).unwrap();
+            db.put("c", &[3u8; 40]).unwrap();
+            db.close().unwrap();
+        }
+        
+        let options = Options {
+            values_on_disk: true,
+            value_cache_bytes: Some(100),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        let cache_counts = |db: &Db| {
+            let metrics = db.metrics().unwrap();
+            (metrics.cache_hits, metrics.cache_misses)
+        };
+        let fetch = |db: &Db, key: &str| db.get(key).unwrap().unwrap().into_owned();
+        
+        // First read goes to disk, the second is served from the cache
+        assert_eq!(fetch(&db, "a"), vec![1u8; 40]);
+        assert_eq!(cache_counts(&db), (0, 1));
+        assert_eq!(fetch(&db, "a"), vec![1u8; 40]);
+        assert_eq!(cache_counts(&db), (1, 1));
+        
+        // Caching a third 40-byte value evicts the least recently used one
+        fetch(&db, "b");
+        fetch(&db, "a");
+        fetch(&db, "c");
+        assert_eq!(cache_counts(&db), (2, 3));
+        let cache = lock(db.value_cache.as_ref().unwrap());
+        assert!(cache.entries.contains_key("a"));
+        assert!(!cache.entries.contains_key("b"));
+        assert_eq!(cache.size, 80);
+        drop(cache);
+        fetch(&db, "b");
+        assert_eq!(cache_counts(&db), (2, 4));
+        
+        // Puts populate the cache; deletes evict
+        db.put("d", &[4u8; 10]).unwrap();
+        assert_eq!(fetch(&db, "d"), vec![4u8; 10]);
+        assert_eq!(cache_counts(&db), (3, 4));
+        db.delete("d").unwrap();
+        assert!(db.get("d").unwrap().is_none());
+        assert!(!lock(db.value_cache.as_ref().unwrap()).entries.contains_key("d"));
+        
+        // Values bigger than the whole cache are never cached
+        db.put("huge", &[5u8; 101]).unwrap();
+        fetch(&db, "huge");
+        fetch(&db, "huge");
+        assert_eq!(cache_counts(&db), (3, 6));
+    }
+
+    #[test]
+    fn test_recovery_progress() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        let value = vec![b'x'; 1000];
+        for i in 0..5000 {
+            db.put(&format!("key{}", i), &value).unwrap();
+        }
+        db.close().unwrap();
+        let log_size = fs::metadata(temp_dir.path().join("log")).unwrap().len();
+        
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&calls);
+        let options = Options {
+            recovery_progress: Some(RecoveryProgress::new(move |read, total| {
+                recorded.lock().unwrap().push((read, total));
+            })),
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.keys().count(), 5000);
+        
+        let calls = calls.lock().unwrap();
+        assert!(calls.len() > 2, "expected periodic reports, got {:?}", calls);
+        assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(calls.iter().all(|&(_, total)| total == log_size));
+        assert_eq!(calls.last(), Some(&(log_size, log_size)));
+    }
+
+    #[test]
+    fn test_recovery_progress_tracks_reads() {
+        /// Serves a log a page at a time, counting the bytes handed out.
+        struct CountingReader(std::io::Cursor<Vec<u8>>, Arc<Mutex<u64>>);
+        
+        impl Read for CountingReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(4096);
+                let read = self.0.read(&mut buf[..len])?;
+                *self.1.lock().unwrap() += read as u64;
+                Ok(read)
+            }
+        }
+        
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        let value = vec![b'x'; 1000];
+        for i in 0..5000 {
+            db.put(&format!("key{}", i), &value).unwrap();
+        }
+        db.close().unwrap();
+        let log = fs::read(temp_dir.path().join("log")).unwrap();
+        let total = log.len() as u64;
+        
+        // Record how much had been read when each report was made
+        let served = Arc::new(Mutex::new(0));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (recorded, counted) = (Arc::clone(&calls), Arc::clone(&served));
+        let options = Options {
+            recovery_progress: Some(RecoveryProgress::new(move |read, _| {
+                recorded.lock().unwrap().push((read, *counted.lock().unwrap()));
+            })),
+            ..Options::default()
+        };
+        let empty_dir = TempDir::new().unwrap();
+        let mut db = Db::open_with_options(empty_dir.path(), options).unwrap();
+        calls.lock().unwrap().clear();
+        let reader = CountingReader(std::io::Cursor::new(log), Arc::clone(&served));
+        db.replay_from(reader, total, &mut OpenReport::default()).unwrap();
+        assert_eq!(db.keys().count(), 5000);
+        
+        // Reports arrive while the log is still being read, and never run
+        // ahead of it
+        let calls = calls.lock().unwrap();
+        assert!(calls.len() > 2, "expected periodic reports, got {:?}", calls);
+        assert!(calls.iter().all(|&(read, served)| read <= served));
+        assert!(calls[..calls.len() - 1].iter().all(|&(_, served)| served < total));
+        assert_eq!(calls.last(), Some(&(total, total)));
+    }
+
+    #[test]
+    fn test_open_deadline() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        let value = vec![b'x'; 1000];
+        for i in 0..3000 {
+            db.put(&format!("key{}", i), &value).unwrap();
+        }
+        db.close().unwrap();
+        
+        // A slow filesystem: every megabyte replayed takes 50ms
+        let options = Options {
+            recovery_progress: Some(RecoveryProgress::new(|_, _| {
+                std::thread::sleep(Duration::from_millis(50));
+            })),
+            open_deadline: Some(Duration::from_millis(20)),
+            ..Options::default()
+        };
+        let Err(err) = Db::open_with_options(temp_dir.path(), options) else {
+            panic!("open should time out");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("open deadline"), "{}", err);
+        
+        let options = Options {
+            open_deadline: Some(Duration::from_secs(60)),
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.keys().count(), 3000);
+    }
+
+    #[test]
+    fn test_open_deadline_slow_read() {
+        /// Serves a log a little at a time, like a slow disk.
+        struct SlowReader(std::io::Cursor<Vec<u8>>);
+        
+        impl Read for SlowReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                std::thread::sleep(Duration::from_millis(5));
+                let len = buf.len().min(4096);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        let value = vec![b'x'; 1000];
+        for i in 0..3000 {
+            db.put(&format!("key{}", i), &value).unwrap();
+        }
+        db.close().unwrap();
+        let log = fs::read(temp_dir.path().join("log")).unwrap();
+        let total = log.len() as u64;
+        
+        // Reading the whole log would take seconds; the deadline cuts it off
+        let empty_dir = TempDir::new().unwrap();
+        let options = Options {
+            open_deadline: Some(Duration::from_millis(20)),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(empty_dir.path(), options).unwrap();
+        let started = Instant::now();
+        let reader = SlowReader(std::io::Cursor::new(log));
+        let err = db.replay_from(reader, total, &mut OpenReport::default()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn test_open_at_record_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("a", b"1").unwrap();
+        db.put("b", b"2").unwrap();
+        db.put("a", b"3").unwrap();
+        db.delete("b").unwrap();
+        db.put("c", b"4").unwrap();
+        db.put_all_atomic(vec![
+            ("x".to_string(), b"5".to_vec()),
+            ("y".to_string(), b"6".to_vec()),
+        ])
+        .unwrap();
+        db.close().unwrap();
+        
+        let entries = |db: &Db| -> Vec<(String, Vec<u8>)> {
+            db.keys()
+                .map(|key| (key.clone(), db.get(key).unwrap().unwrap().to_vec()))
+                .collect()
+        };
+        let pair = |key: &str, value: &[u8]| (key.to_string(), value.to_vec());
+        
+        let empty = Db::open_at(temp_dir.path(), Checkpoint::RecordCount(0)).unwrap();
+        assert!(entries(&empty).is_empty());
+        
+        // Later writes aren't visible
+        let early = Db::open_at(temp_dir.path(), Checkpoint::RecordCount(2)).unwrap();
+        assert_eq!(entries(&early), vec![pair("a", b"1"), pair("b", b"2")]);
+        
+        let later = Db::open_at(temp_dir.path(), Checkpoint::RecordCount(4)).unwrap();
+        assert_eq!(entries(&later), vec![pair("a", b"3")]);
+        
+        // A batch is never split
+        let mid_batch = Db::open_at(temp_dir.path(), Checkpoint::RecordCount(6)).unwrap();
+        assert_eq!(entries(&mid_batch), vec![pair("a", b"3"), pair("c", b"4")]);
+        
+        let past_end = Db::open_at(temp_dir.path(), Checkpoint::RecordCount(100)).unwrap();
+        assert_eq!(
+            entries(&past_end),
+            vec![pair("a", b"3"), pair("c", b"4"), pair("x", b"5"), pair("y", b"6")]
+        );
+        
+        // Verifying would compare against records the view left out
+        let err = mid_batch.verify_consistency().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_open_at_is_read_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let Err(err) = Db::open_at(temp_dir.path(), Checkpoint::RecordCount(1)) else {
+            panic!("Opening a missing log at a checkpoint should fail");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(!temp_dir.path().join("log").exists());
+        
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("a", b"1").unwrap();
+        db.put("b", b"2").unwrap();
+        db.close().unwrap();
+        let log_before = fs::read(temp_dir.path().join("log")).unwrap();
+        
+        let mut db = Db::open_at(temp_dir.path(), Checkpoint::RecordCount(1)).unwrap();
+        let denied = std::io::ErrorKind::PermissionDenied;
+        assert_eq!(db.put("c", b"3").unwrap_err().kind(), denied);
+        assert_eq!(db.delete("a").unwrap_err().kind(), denied);
+        let batch = vec![("c".to_string(), b"3".to_vec())];
+        assert_eq!(db.put_all_atomic(batch).unwrap_err().kind(), denied);
+        assert_eq!(db.reopen().unwrap_err().kind(), denied);
+        assert_eq!(db.get("b").unwrap().as_deref(), None);
+        assert_eq!(db.destroy().unwrap_err().kind(), denied);
+        
+        assert_eq!(fs::read(temp_dir.path().join("log")).unwrap(), log_before);
+    }
+
+    #[test]
+    fn test_reopen_sees_external_writes() {
+        use std::io::Write;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let mut writer = Db::open(temp_dir.path()).unwrap();
+        writer.put("key1", b"value1").unwrap();
+        
+        let mut reader = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(reader.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+        
+        writer.put("key2", b"value2").unwrap();
+        writer.delete("key1").unwrap();
+        assert_eq!(reader.get("key2").unwrap().as_deref(), None);
+        
+        reader.reopen().unwrap();
+        assert_eq!(reader.get("key1").unwrap().as_deref(), None);
+        assert_eq!(reader.get("key2").unwrap().as_deref(), Some(b"value2".as_slice()));
+        
+        // The reader's own writes interleave with the writer's in log order
+        reader.put("key3", b"reader").unwrap();
+        writer.put("key3", b"writer").unwrap();
+        reader.reopen().unwrap();
+        assert_eq!(reader.get("key3").unwrap().as_deref(), Some(b"writer".as_slice()));
+        
+        // A partially appended record is picked up once complete
+        let mut record = vec![0u8, 4, 0, 0, 0];
+        record.extend_from_slice(b"key4");
+        record.extend_from_slice(&[6, 0, 0, 0]);
+        record.extend_from_slice(b"value4");
+        let log_path = temp_dir.path().join("log");
+        let mut file = fs::OpenOptions::new().append(true).open(log_path).unwrap();
+        file.write_all(&record[..7]).unwrap();
+        reader.reopen().unwrap();
+        assert_eq!(reader.get("key4").unwrap().as_deref(), None);
+        file.write_all(&record[7..]).unwrap();
+        reader.reopen().unwrap();
+        assert_eq!(reader.get("key4").unwrap().as_deref(), Some(b"value4".as_slice()));
+        
+        // Nothing new to read
+        reader.reopen().unwrap();
+        assert_eq!(reader.keys().count(), 3);
+    }
+
+    #[test]
+    fn test_estimate_live_ratio() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.estimate_live_ratio(), 1.0);
+        
+        db.put("a", b"1").unwrap();
+        db.put("b", b"2").unwrap();
+        assert_eq!(db.estimate_live_ratio(), 1.0);
+        
+        // 4 keys overwritten 50 times each, then one deleted
+        for i in 0..50 {
+            for key in ["a", "b", "c", "d"] {
+                db.put(key, format!("{}", i).as_bytes()).unwrap();
+            }
+        }
+        db.delete("d").unwrap();
+        let ratio = db.estimate_live_ratio();
+        assert!(ratio < 0.1, "ratio {}", ratio);
+        assert_eq!(ratio, 3.0 / 203.0);
+        
+        // Replay counts the same records
+        drop(db);
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.estimate_live_ratio(), ratio);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        db.put("key1", b"value1").unwrap();
+        db.put_all_atomic(vec![
+            ("key2".to_string(), b"value2".to_vec()),
+            ("key3".to_string(), b"value3".to_vec()),
+        ])
+        .unwrap();
+        db.delete("key3").unwrap();
+        db.get("key1").unwrap();
+        db.get("key3").unwrap();
+        assert!(db.value_equals("key2", b"value2"));
+        
+        let metrics = db.metrics().unwrap();
+        assert_eq!(
+            metrics,
+            Metrics {
+                puts: 3,
+                deletes: 1,
+                gets: 3,
+                hits: 2,
+                misses: 1,
+                cache_hits: 0,
+                cache_misses: 0,
+                syncs: 0,
+                live_keys: 2,
+                log_bytes: fs::metadata(temp_dir.path().join("log")).unwrap().len(),
+            }
+        );
+        assert!(metrics.to_prometheus().contains("docdb_live_keys 2\n"));
+    }
+
+    #[test]
+    fn test_group_commit_by_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            group_commit: Some(GroupCommit {
+                max_writes: 4,
+                max_delay: Duration::from_secs(3600),
+            }),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        
+        for i in 0..9 {
+            db.put(&format!("key{}", i), b"value").unwrap();
+        }
+        db.delete("key0").unwrap();
+        // A batch counts as one write
+        db.put_all_atomic(vec![
+            ("a".to_string(), b"1".to_vec()),
+            ("b".to_string(), b"2".to_vec()),
+        ])
+        .unwrap();
+        // 11 writes: fsyncs after the 4th and 8th, 3 still waiting
+        assert_eq!(db.metrics().unwrap().syncs, 2);
+        assert_eq!(db.unsynced_writes, 3);
+        db.close().unwrap();
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().count(), 10);
+        assert_eq!(db.get("key0").unwrap().as_deref(), None);
+        assert_eq!(db.get("b").unwrap().as_deref(), Some(&b"2"[..]));
+    }
+
+    #[test]
+    fn test_group_commit_by_delay() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            group_commit: Some(GroupCommit {
+                max_writes: 1000,
+                max_delay: Duration::from_millis(50),
+            }),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        
+        db.put("key1", b"value1").unwrap();
+        db.put("key2", b"value2").unwrap();
+        assert_eq!(db.metrics().unwrap().syncs, 0);
+        std::thread::sleep(Duration::from_millis(60));
+        // The next write finds the oldest waiting one overdue
+        db.put("key3", b"value3").unwrap();
+        assert_eq!(db.metrics().unwrap().syncs, 1);
+        assert_eq!(db.unsynced_writes, 0);
+        drop(db);
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().count(), 3);
+    }
+
+    #[test]
+    fn test_group_commit_sync_waiting() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            group_commit: Some(GroupCommit {
+                max_writes: 1000,
+                max_delay: Duration::from_secs(3600),
+            }),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(db.sync_deadline(), None);
+        assert!(!db.sync_waiting().unwrap());
+        
+        let before = Instant::now();
+        db.put("key1", b"value1").unwrap();
+        db.put("key2", b"value2").unwrap();
+        let deadline = db.sync_deadline().unwrap();
+        assert!(deadline >= before + Duration::from_secs(3600));
+        
+        // Idle: fsync what is waiting without another write
+        assert!(db.sync_waiting().unwrap());
+        assert_eq!(db.metrics().unwrap().syncs, 1);
+        assert_eq!(db.sync_deadline(), None);
+        assert!(!db.sync_waiting().unwrap());
+        drop(db);
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().count(), 2);
+    }
+
+    #[test]
+    fn test_index_after_durable_with_group_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("key1", b"old").unwrap();
+        drop(db);
+        
+        let options = Options {
+            group_commit: Some(GroupCommit {
+                max_writes: 3,
+                max_delay: Duration::from_secs(3600),
+            }),
+            index_after_durable: true,
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key1", b"new").unwrap();
+        db.put_with_content_type("key2", b"text", "text/plain").unwrap();
+        // Logged but not yet fsynced, so not visible
+        assert_eq!(db.metrics().unwrap().syncs, 0);
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&b"old"[..]));
+        assert_eq!(db.get("key2").unwrap().as_deref(), None);
+        
+        // The third write completes the group
+        db.delete("key1").unwrap();
+        assert_eq!(db.metrics().unwrap().syncs, 1);
+        assert_eq!(db.get("key1").unwrap().as_deref(), None);
+        assert_eq!(db.get("key2").unwrap().as_deref(), Some(&b"text"[..]));
+        assert_eq!(db.content_type("key2"), Some("text/plain"));
+        
+        db.put("key3", b"value3").unwrap();
+        assert_eq!(db.get("key3").unwrap().as_deref(), None);
+        // The queued write is in the log but not the index, as intended
+        assert!(db.verify_consistency().unwrap());
+        db.sync().unwrap();
+        assert_eq!(db.get("key3").unwrap().as_deref(), Some(&b"value3"[..]));
+        assert!(db.verify_consistency().unwrap());
+    }
+
+    #[test]
+    fn test_index_after_durable_without_group_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            index_after_durable: true,
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        
+        // Every write fsyncs before it is applied
+        db.put("key1", b"value1").unwrap();
+        assert_eq!(db.metrics().unwrap().syncs, 1);
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&b"value1"[..]));
+        db.put_all_atomic(vec![("key2".to_string(), b"value2".to_vec())]).unwrap();
+        db.delete("key1").unwrap();
+        assert_eq!(db.metrics().unwrap().syncs, 3);
+        assert_eq!(db.get("key1").unwrap().as_deref(), None);
+        assert_eq!(db.get("key2").unwrap().as_deref(), Some(&b"value2"[..]));
+        drop(db);
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["key2"]);
+    }
+
+    #[test]
+    fn test_transaction_commits() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("old", b"1").unwrap();
+        
+        let buffered = db
+            .transaction(|txn| {
+                txn.put("key1", b"value1");
+                txn.put("key2", b"value2");
+                txn.delete("old");
+                txn.put("key1", b"value1b");
+                Ok::<_, std::io::Error>(txn.len())
+            })
+            .unwrap();
+        assert_eq!(buffered, 4);
+        
+        let check = |db: &Db| {
+            assert_eq!(db.get("key1").unwrap().as_deref(), Some(&b"value1b"[..]));
+            assert_eq!(db.get("key2").unwrap().as_deref(), Some(&b"value2"[..]));
+            assert_eq!(db.get("old").unwrap().as_deref(), None);
+        };
+        check(&db);
+        drop(db);
+        
+        // The batch reads back as its four records, after the first put
+        let records = Log::read_all(temp_dir.path().join("log")).unwrap();
+        assert_eq!(records.len(), 5);
+        check(&Db::open(temp_dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_transaction_rolls_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            max_key_bytes: Some(8),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key1", b"value1").unwrap();
+        let log_len = fs::metadata(temp_dir.path().join("log")).unwrap().len();
+        
+        let result: std::io::Result<()> = db.transaction(|txn| {
+            txn.put("key1", b"changed");
+            txn.delete("key1");
+            txn.put("key2", b"value2");
+            Err(std::io::Error::other("abort"))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "abort");
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&b"value1"[..]));
+        assert_eq!(db.get("key2").unwrap().as_deref(), None);
+        
+        // An invalid key fails the commit without writing anything either
+        let result = db.transaction(|txn| {
+            txn.put("key3", b"value3");
+            txn.put("much-too-long", b"value");
+            Ok::<_, std::io::Error>(())
+        });
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(db.get("key3").unwrap().as_deref(), None);
+        drop(db);
+        
+        assert_eq!(fs::metadata(temp_dir.path().join("log")).unwrap().len(), log_len);
+    }
+
+    #[test]
+    fn test_get_json_or() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("retries", b"5").unwrap();
+        db.put("hosts", br#"["a", "b"]"#).unwrap();
+        db.put("name", br#""docdb""#).unwrap();
+        db.put_with_content_type("raw", b"not json", "text/plain").unwrap();
+        
+        // Present
+        assert_eq!(db.get_json_or("retries", 3u32), 5);
+        assert_eq!(db.get_json_or("hosts", Vec::<String>::new()), vec!["a", "b"]);
+        // Absent
+        assert_eq!(db.get_json_or("timeout", 30u32), 30);
+        // Unparseable, or JSON of the wrong type
+        assert_eq!(db.get_json_or("raw", 1u32), 1);
+        assert_eq!(db.get_json_or("name", 2u32), 2);
+    }
+
+    #[test]
+    fn test_key_normalizer() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            key_normalizer: Some(KeyNormalizer::new(|key: &str| key.trim().to_lowercase())),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        
+        db.put("Foo", b"1").unwrap();
+        db.put(" FOO ", b"2").unwrap();
+        assert_eq!(db.get("foo").unwrap().as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get("fOO").unwrap().as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get_size("FOO"), Some(1));
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["foo"]);
+        
+        db.put_all_atomic(vec![("Bar".to_string(), b"3".to_vec())]).unwrap();
+        assert_eq!(db.get("BAR").unwrap().as_deref(), Some(&b"3"[..]));
+        db.delete("FoO").unwrap();
+        assert_eq!(db.get("foo").unwrap().as_deref(), None);
+        drop(db);
+        
+        // The normalized form is what was logged
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["bar"]);
+        assert_eq!(Db::history(temp_dir.path(), "foo").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_get_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("key1", b"{\"a\": 1}").unwrap();
+        db.put("empty", b"").unwrap();
+        db.put("deleted", b"value").unwrap();
+        db.delete("deleted").unwrap();
+        
+        assert_eq!(db.get_size("key1"), Some(8));
+        assert_eq!(db.get_size("empty"), Some(0));
+        assert_eq!(db.get_size("deleted"), None);
+        assert_eq!(db.get_size("missing"), None);
+        drop(db);
+        
+        let on_disk = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        for options in [Options::default(), on_disk] {
+            let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+            assert_eq!(db.get_size("key1"), Some(8));
+            assert_eq!(db.get_size("missing"), None);
+        }
+    }
+
+    #[test]
+    fn test_put_versioned() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        // Missing keys are at version 0
+        assert_eq!(db.put_versioned("doc", b"v1", 0).unwrap(), Some(1));
+        assert_eq!(db.put_versioned("doc", b"v2", 1).unwrap(), Some(2));
+        assert_eq!(db.get_versioned("doc").unwrap(), Some((Cow::from(&b"v2"[..]), 2)));
+        
+        // A stale version is rejected without writing
+        let log_len = fs::metadata(temp_dir.path().join("log")).unwrap().len();
+        assert_eq!(db.put_versioned("doc", b"stale", 1).unwrap(), None);
+        assert_eq!(db.put_versioned("doc", b"ahead", 5).unwrap(), None);
+        assert_eq!(db.get_versioned("doc").unwrap(), Some((Cow::from(&b"v2"[..]), 2)));
+        assert_eq!(fs::metadata(temp_dir.path().join("log")).unwrap().len(), log_len);
+        
+        // Unversioned writes reset the version
+        db.put("plain", b"x").unwrap();
+        assert_eq!(db.get_versioned("plain").unwrap(), Some((Cow::from(&b"x"[..]), 0)));
+        db.put_versioned("reset", b"1", 0).unwrap();
+        db.put("reset", b"2").unwrap();
+        assert_eq!(db.get_versioned("reset").unwrap(), Some((Cow::from(&b"2"[..]), 0)));
+        assert_eq!(db.get_versioned("missing").unwrap(), None);
+        drop(db);
+        
+        // Versions survive reopening, in every replay mode and via snapshots
+        let on_disk = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        for options in [Options::default(), on_disk] {
+            let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+            assert_eq!(db.get_versioned("doc").unwrap(), Some((Cow::from(&b"v2"[..]), 2)));
+            assert_eq!(db.get_versioned("reset").unwrap(), Some((Cow::from(&b"2"[..]), 0)));
+            assert_eq!(db.put_versioned("doc", b"v3", 1).unwrap(), None);
+            drop(db);
+        }
+        let db = Db::open(temp_dir.path()).unwrap();
+        let mut snapshot = Vec::new();
+        db.snapshot_to_writer(&mut snapshot).unwrap();
+        let restore_dir = TempDir::new().unwrap();
+        let restored = Db::restore_from_reader(restore_dir.path(), &mut &snapshot[..]).unwrap();
+        assert_eq!(restored.get_versioned("doc").unwrap(), Some((Cow::from(&b"v2"[..]), 2)));
+    }
+
+    #[test]
+    fn test_put_versioned_sees_pending_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            group_commit: Some(GroupCommit {
+                max_writes: 100,
+                max_delay: Duration::from_secs(3600),
+            }),
+            index_after_durable: true,
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        
+        assert_eq!(db.put_versioned("doc", b"v1", 0).unwrap(), Some(1));
+        assert_eq!(db.get_versioned("doc").unwrap(), None);
+        // The unsynced write already moved the version on
+        assert_eq!(db.put_versioned("doc", b"conflict", 0).unwrap(), None);
+        assert_eq!(db.put_versioned("doc", b"v2", 1).unwrap(), Some(2));
+        db.sync().unwrap();
+        assert_eq!(db.get_versioned("doc").unwrap(), Some((Cow::from(&b"v2"[..]), 2)));
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("b", br#"{"say": "hi, \"you\""}"#).unwrap();
+        db.put("a", b"1").unwrap();
+        db.put("multi\nline", b"[1,\n2]").unwrap();
         
-        // Create the directory if it doesn't exist
-        fs::create_dir_all(dir)?;
+        let mut csv = Vec::new();
+        db.export_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            [
+                "key,value",
+                "a,1",
+                r#"b,"{""say"": ""hi, \""you\""""}""#,
+                "\"multi\nline\",\"[1,\n2]\"",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_stream_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("user:2", br#"{"name":"bo"}"#).unwrap();
+        db.put("user:1", br#"{"name":"al"}"#).unwrap();
+        db.put("order:1", b"[1,2]").unwrap();
+        db.put("users", b"0").unwrap();
         
-        let log_path = dir.join("log");
+        let mut sink = Vec::new();
+        assert_eq!(db.stream_values(Some("user:"), &mut sink, newline_frame).unwrap(), 2);
+        assert_eq!(sink, b"{\"name\":\"al\"}\n{\"name\":\"bo\"}\n");
         
-        // Replay the log to rebuild the index
-        let index = Self::replay_log(&log_path)?;
+        let mut sink = Vec::new();
+        assert_eq!(db.stream_values(None, &mut sink, newline_frame).unwrap(), 4);
+        assert_eq!(sink.iter().filter(|&&b| b == b'\n').count(), 4);
+        assert!(sink.starts_with(b"[1,2]\n"));
+        
+        let mut sink = Vec::new();
+        assert_eq!(db.stream_values(Some("order"), &mut sink, length_prefixed_frame).unwrap(), 1);
+        assert_eq!(sink, b"\x05\0\0\0[1,2]");
+        assert_eq!(db.stream_values(Some("none"), &mut sink, newline_frame).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_export_json_sorted_keys() {
+        let export = |entries: &[(&str, &str)], format: &FormatOptions| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut db = Db::open(temp_dir.path()).unwrap();
+            for (key, value) in entries {
+                db.put(key, value.as_bytes()).unwrap();
+            }
+            let mut out = Vec::new();
+            db.export_json(&mut out, format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
         
-        // Open the log for appending new records
-        let log = Log::open(&log_path)?;
+        // Same data, put in a different order with differently ordered keys
+        let first = [("b", r#"{"y": 1, "x": [2, {"q": 3, "p": 4}]}"#), ("a", "[]")];
+        let second = [("a", "[ ]"), ("b", r#"{"x":[2,{"p":4,"q":3}],"y":1}"#)];
+        let compact = FormatOptions::default();
+        assert_eq!(export(&first, &compact), export(&second, &compact));
+        let expected = r#"{"a":[],"b":{"x":[2,{"p":4,"q":3}],"y":1}}"#;
+        assert_eq!(export(&first, &compact), format!("{}\n", expected));
         
-        Ok(Db {
-            log,
-            index,
-        })
+        let pretty = FormatOptions { pretty: true, ..FormatOptions::default() };
+        let exported = export(&first, &pretty);
+        assert_eq!(exported, export(&second, &pretty));
+        let json: serde_json::Value = serde_json::from_str(&exported).unwrap();
+        assert_eq!(exported, serde_json::to_string_pretty(&json).unwrap() + "\n");
+        assert_eq!(export(&[], &pretty), "{}\n");
+        
+        // Unsorted output keeps each value's stored key order
+        let unsorted = FormatOptions { sort_keys: false, ..FormatOptions::default() };
+        assert_ne!(export(&first, &unsorted), export(&second, &unsorted));
     }
 
-    /// Replays the log file to rebuild the in-memory index.
-    /// 
-    /// Invariant: After replay, the index contains the state that results
-    /// from applying all log records in order. Later operations overwrite
-    /// earlier ones (Put overwrites previous Put/Delete, Delete removes the key).
-    fn replay_log<P: AsRef<Path>>(log_path: P) -> std::io::Result<HashMap<String, Vec<u8>>> {
-        let mut index = HashMap::new();
+    #[test]
+    fn test_get_json_formatted() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("doc", br#"{"b": 1, "a": 2}"#).unwrap();
+        db.put("raw", b"not json").unwrap();
         
-        // If the log file doesn't exist yet, return an empty index
-        if !log_path.as_ref().exists() {
-            return Ok(index);
+        let format = FormatOptions::default();
+        let formatted = db.get_json_formatted("doc", &format).unwrap();
+        assert_eq!(formatted, Some(br#"{"a":2,"b":1}"#.to_vec()));
+        assert_eq!(db.get_json_formatted("missing", &format).unwrap(), None);
+        let err = db.get_json_formatted("raw", &format).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_max_index_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        for i in 0..10 {
+            // 4-byte keys with 16-byte values: 20 bytes per entry
+            db.put(&format!("key{}", i), &[b'x'; 16]).unwrap();
         }
+        // The overwrite doesn't add to the count, so it peaks at 200 bytes
+        // before the delete
+        db.put("key0", &[b'y'; 16]).unwrap();
+        db.delete("key9").unwrap();
+        drop(db);
         
-        // Read all records from the log
-        let records = Log::read_all(log_path)?;
+        let open = |max_index_bytes, values_on_disk| {
+            let options = Options {
+                max_index_bytes: Some(max_index_bytes),
+                values_on_disk,
+                ..Options::default()
+            };
+            Db::open_with_options(temp_dir.path(), options)
+        };
+        assert_eq!(open(200, false).unwrap().keys().count(), 9);
         
-        // Apply each record to rebuild the index
-        for record in records {
-            match record {
-                LogRecord::Put { key, value } => {
-                    // Convert key from bytes to string
-                    // If the key is not valid UTF-8, we skip it (could also return an error)
-                    if let Ok(key_str) = String::from_utf8(key) {
-                        index.insert(key_str, value);
-                    }
-                }
-                LogRecord::Delete { key } => {
-                    // Convert key from bytes to string and remove from index
-                    if let Ok(key_str) = String::from_utf8(key) {
-                        index.remove(&key_str);
-                    }
+        let Err(err) = open(100, false) else {
+            panic!("expected the budget to be exceeded");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+        assert!(err.to_string().contains("100 byte memory budget"), "{}", err);
+        
+        // Values kept on disk don't count
+        assert_eq!(open(40, true).unwrap().keys().count(), 9);
+        assert!(open(30, true).is_err());
+    }
+
+    #[test]
+    fn test_put_if_absent_with() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("present", b"old").unwrap();
+        
+        let calls = std::cell::Cell::new(0);
+        let compute = || {
+            calls.set(calls.get() + 1);
+            b"computed".to_vec()
+        };
+        assert!(!db.put_if_absent_with("present", compute).unwrap());
+        assert_eq!(calls.get(), 0);
+        assert_eq!(db.get("present").unwrap().as_deref(), Some(&b"old"[..]));
+        
+        assert!(db.put_if_absent_with("absent", compute).unwrap());
+        assert_eq!(calls.get(), 1);
+        assert_eq!(db.get("absent").unwrap().as_deref(), Some(&b"computed"[..]));
+        assert!(!db.put_if_absent_with("absent", compute).unwrap());
+        assert_eq!(calls.get(), 1);
+        
+        // Deleted keys are absent again
+        db.delete("present").unwrap();
+        assert!(db.put_if_absent_with("present", compute).unwrap());
+        assert_eq!(calls.get(), 2);
+        drop(db);
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("absent").unwrap().as_deref(), Some(&b"computed"[..]));
+    }
+
+    #[test]
+    fn test_apply_remote_record() {
+        let primary_dir = TempDir::new().unwrap();
+        let mut primary = Db::open(primary_dir.path()).unwrap();
+        primary.put("a", b"1").unwrap();
+        primary.put_with_content_type("text", b"hi", "text/plain").unwrap();
+        primary.put_versioned("doc", b"v1", 0).unwrap();
+        primary.put_versioned("doc", b"v2", 1).unwrap();
+        primary.put("gone", b"2").unwrap();
+        primary.delete("gone").unwrap();
+        primary.put("a", b"3").unwrap();
+        
+        // Stream the primary's log into the follower, record by record
+        let follower_dir = TempDir::new().unwrap();
+        let mut follower = Db::open(follower_dir.path()).unwrap();
+        follower.put("gone", b"local").unwrap();
+        for record in Log::read_all(primary_dir.path().join("log")).unwrap() {
+            follower.apply_remote_record(&record).unwrap();
+        }
+        drop(follower);
+        
+        let mut follower = Db::open(follower_dir.path()).unwrap();
+        assert_eq!(follower.keys().collect::<Vec<_>>(), primary.keys().collect::<Vec<_>>());
+        for key in primary.keys() {
+            assert_eq!(follower.get(key).unwrap(), primary.get(key).unwrap());
+            assert_eq!(follower.content_type(key), primary.content_type(key));
+            assert_eq!(follower.get_versioned(key).unwrap(), primary.get_versioned(key).unwrap());
+        }
+        assert_eq!(follower.get_versioned("doc").unwrap(), Some((Cow::from(&b"v2"[..]), 2)));
+        
+        let bad_key = LogRecord::Delete { key: vec![0xff] };
+        let err = follower.apply_remote_record(&bad_key).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_merge_from() {
+        let other_dir = TempDir::new().unwrap();
+        let mut other = Db::open(other_dir.path()).unwrap();
+        other.put("shared", b"theirs").unwrap();
+        other.put("new", b"1").unwrap();
+        other.put("new", b"2").unwrap();
+        other.put("removed", b"x").unwrap();
+        other.delete("removed").unwrap();
+        other.put("never_here", b"x").unwrap();
+        other.delete("never_here").unwrap();
+        other.put_with_content_type("text", b"hi", "text/plain").unwrap();
+        drop(other);
+        
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("shared", b"ours").unwrap();
+        db.put("removed", b"ours").unwrap();
+        db.put("mine", b"kept").unwrap();
+        
+        let report = db.merge_from(other_dir.path()).unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                inserted: 2,
+                overwritten: 1,
+                kept: 0,
+                deleted: 1,
+            }
+        );
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["mine", "new", "shared", "text"]);
+        assert_eq!(db.get("shared").unwrap().as_deref(), Some(&b"theirs"[..]));
+        assert_eq!(db.get("new").unwrap().as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get("mine").unwrap().as_deref(), Some(&b"kept"[..]));
+        assert_eq!(db.content_type("text"), Some("text/plain"));
+        drop(db);
+        
+        // The merge was logged here
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("shared").unwrap().as_deref(), Some(&b"theirs"[..]));
+        assert_eq!(db.get("removed").unwrap().as_deref(), None);
+        
+        let missing = TempDir::new().unwrap();
+        let err = db.merge_from(missing.path()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_merge_from_with_conflict_resolver() {
+        let other_dir = TempDir::new().unwrap();
+        let mut other = Db::open(other_dir.path()).unwrap();
+        other.put("config", br#"{"theme": "dark", "lang": "en"}"#).unwrap();
+        other.put("counter", b"7").unwrap();
+        other.put("new", b"1").unwrap();
+        other.delete("old").unwrap();
+        drop(other);
+        
+        let populate = |dir: &Path, options: Options| {
+            let mut db = Db::open_with_options(dir, options).unwrap();
+            db.put("config", br#"{"theme": "light", "size": 12}"#).unwrap();
+            db.put("counter", b"3").unwrap();
+            db.put("old", b"x").unwrap();
+            db
+        };
+        
+        // Keep whatever is already here
+        let keep_dir = TempDir::new().unwrap();
+        let keep = Options {
+            conflict_resolver: Some(ConflictResolver::new(|_, _, _| Resolution::Keep)),
+            ..Options::default()
+        };
+        let mut db = populate(keep_dir.path(), keep);
+        let report = db.merge_from(other_dir.path()).unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                inserted: 1,
+                overwritten: 0,
+                kept: 2,
+                deleted: 1,
+            }
+        );
+        assert_eq!(
+            db.get("config").unwrap().as_deref(),
+            Some(&br#"{"theme": "light", "size": 12}"#[..])
+        );
+        assert_eq!(db.get("counter").unwrap().as_deref(), Some(&b"3"[..]));
+        assert_eq!(db.get("new").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get("old").unwrap().as_deref(), None);
+        
+        // Merge JSON objects field by field, incoming fields winning; other
+        // values are replaced
+        let merge_dir = TempDir::new().unwrap();
+        let resolver = ConflictResolver::new(|_, existing, incoming| {
+            use serde_json::Value;
+            let parse = |bytes| serde_json::from_slice::<Value>(bytes).ok();
+            match (parse(existing), parse(incoming)) {
+                (Some(Value::Object(mut merged)), Some(Value::Object(new))) => {
+                    merged.extend(new);
+                    Resolution::Value(serde_json::to_vec(&merged).unwrap())
                 }
+                _ => Resolution::Replace,
             }
+        });
+        let merge = Options {
+            conflict_resolver: Some(resolver),
+            ..Options::default()
+        };
+        let mut db = populate(merge_dir.path(), merge);
+        let report = db.merge_from(other_dir.path()).unwrap();
+        assert_eq!(report.overwritten, 2);
+        assert_eq!(report.kept, 0);
+        let config = db.get("config").unwrap().unwrap();
+        let config: serde_json::Value = serde_json::from_slice(&config).unwrap();
+        assert_eq!(config, serde_json::json!({"theme": "dark", "lang": "en", "size": 12}));
+        assert_eq!(db.get("counter").unwrap().as_deref(), Some(&b"7"[..]));
+    }
+
+    #[test]
+    fn test_open_with_report() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        {
+            let mut log = Log::open(&log_path).unwrap();
+            log.put(b"a", b"1").unwrap();
+            log.put(&[0xff, 0xfe], b"2").unwrap();
+            log.put(b"b", b"3").unwrap();
+            log.delete(b"b").unwrap();
+        }
+        let mut torn = Vec::new();
+        LogRecord::Put { key: b"c".to_vec(), value: b"4".to_vec() }
+            .write_to(&mut torn)
+            .unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .unwrap()
+            .write_all(&torn[..5])
+            .unwrap();
+        
+        let expected = OpenReport {
+            records_replayed: 3,
+            skipped_keys: vec![vec![0xff, 0xfe]],
+            corrupt_regions_skipped: 1,
+            truncated_tail_bytes: 5,
+            live_keys: 1,
+            log_checksum_mismatch: false,
+        };
+        let lenient = Options {
+            lenient_recovery: true,
+            ..Options::default()
+        };
+        let on_disk = Options {
+            values_on_disk: true,
+            ..lenient.clone()
+        };
+        for options in [lenient, on_disk] {
+            let (db, report) = Db::open_with_report(temp_dir.path(), options).unwrap();
+            assert_eq!(report, expected);
+            assert_eq!(db.get("a").unwrap().as_deref(), Some(&b"1"[..]));
         }
         
-        Ok(index)
+        // Without lenient recovery the torn tail still fails the open
+        assert!(Db::open_with_report(temp_dir.path(), Options::default()).is_err());
     }
 
-    /// Stores a key-value pair in the database.
-    /// 
-    /// The value is stored as raw bytes (JSON documents should be serialized
-    /// to bytes before calling this method).
-    /// 
-    /// Invariant: The operation is logged before the index is updated,
-    /// ensuring crash safety.
-    pub fn put(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
-        // Write to log first (crash safety)
-        self.log.put(key.as_bytes(), value)?;
+    #[test]
+    fn test_open_creates_log_durably() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("nested").join("db");
         
-        // Update in-memory index
-        self.index.insert(key.to_string(), value.to_vec());
+        // The directory sync only runs when the log is new
+        {
+            let mut db = Db::open(&dir).unwrap();
+            assert!(dir.join("log").exists());
+            db.put("key", b"value").unwrap();
+        }
         
-        Ok(())
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get("key").unwrap().as_deref(), Some(&b"value"[..]));
     }
 
-    /// Retrieves a value by key.
-    /// 
-    /// Returns None if the key doesn't exist or was deleted.
-    pub fn get(&self, key: &str) -> Option<&[u8]> {
-        self.index.get(key).map(|v| v.as_slice())
+    #[test]
+    fn test_min_free_space() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = |margin| Options {
+            min_free_space_bytes: Some(margin),
+            ..Options::default()
+        };
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options(0)).unwrap();
+        db.put("key", b"1").unwrap();
+        drop(db);
+        
+        // No disk has this much to spare
+        let mut db = Db::open_with_options(temp_dir.path(), options(u64::MAX)).unwrap();
+        let log_len = fs::metadata(temp_dir.path().join("log")).unwrap().len();
+        let err = db.put("key", b"2").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert!(err.to_string().contains("Insufficient disk space"), "{}", err);
+        assert_eq!(db.delete("key").unwrap_err().kind(), std::io::ErrorKind::StorageFull);
+        
+        // Nothing was written
+        assert_eq!(db.get("key").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(fs::metadata(temp_dir.path().join("log")).unwrap().len(), log_len);
     }
 
-    /// Deletes a key from the database.
-    /// 
-    /// Invariant: The deletion is logged before the index is updated,
-    /// ensuring crash safety.
-    pub fn delete(&mut self, key: &str) -> std::io::Result<()> {
-        // Write to log first (crash safety)
-        self.log.delete(key.as_bytes())?;
+    #[test]
+    fn test_log_checksum() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let options = |policy| Options {
+            log_checksum: policy,
+            ..Options::default()
+        };
         
-        // Update in-memory index
-        self.index.remove(key);
+        let mut db = Db::open_with_options(dir, options(LogChecksumPolicy::Warn)).unwrap();
+        db.put("alpha", b"\"first\"").unwrap();
+        db.put("beta", b"\"second\"").unwrap();
+        db.close().unwrap();
+        assert!(dir.join(LOG_CHECKSUM_FILE).exists());
         
-        Ok(())
+        // A clean reopen matches, even with records appended since
+        let (mut db, report) =
+            Db::open_with_report(dir, options(LogChecksumPolicy::Error)).unwrap();
+        assert!(!report.log_checksum_mismatch);
+        db.put("gamma", b"3").unwrap();
+        drop(db);
+        let (db, report) = Db::open_with_report(dir, options(LogChecksumPolicy::Error)).unwrap();
+        assert!(!report.log_checksum_mismatch);
+        db.close().unwrap();
+        
+        // Change a byte in the middle of the log, keeping it parsable
+        let mut bytes = fs::read(dir.join("log")).unwrap();
+        let pos = bytes.windows(5).position(|w| w == b"first").unwrap();
+        bytes[pos] = b'F';
+        fs::write(dir.join("log"), &bytes).unwrap();
+        
+        let (db, report) = Db::open_with_report(dir, options(LogChecksumPolicy::Warn)).unwrap();
+        assert!(report.log_checksum_mismatch);
+        assert_eq!(db.get("alpha").unwrap().as_deref(), Some(&b"\"First\""[..]));
+        drop(db);
+        let Err(err) = Db::open_with_options(dir, options(LogChecksumPolicy::Error)) else {
+            panic!("open should fail");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // Checking is opt-in
+        Db::open(dir).unwrap();
+        
+        // Truncation below the recorded length is flagged too
+        let db = Db::open_with_options(dir, options(LogChecksumPolicy::Warn)).unwrap();
+        db.close().unwrap();
+        let len = fs::metadata(dir.join("log")).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(dir.join("log"))
+            .unwrap()
+            .set_len(len - Log::put_len(b"gamma", b"3"))
+            .unwrap();
+        let Err(err) = Db::open_with_options(dir, options(LogChecksumPolicy::Error)) else {
+            panic!("open should fail");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
     }
 
-    /// Returns an iterator over all keys in the database.
-    pub fn keys(&self) -> impl Iterator<Item = &String> {
-        self.index.keys()
+    #[test]
+    fn test_open_skips_experimental_records() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        {
+            let mut log = Log::open(&log_path).unwrap();
+            log.put(b"a", b"1").unwrap();
+            log.append_experimental(0x80, b"not for this reader").unwrap();
+            log.put(b"b", b"2").unwrap();
+        }
+        
+        let on_disk = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        for options in [Options::default(), on_disk] {
+            let (db, report) = Db::open_with_report(temp_dir.path(), options).unwrap();
+            assert_eq!(report.records_replayed, 2);
+            assert_eq!(report.corrupt_regions_skipped, 0);
+            assert_eq!(db.get("a").unwrap().as_deref(), Some(&b"1"[..]));
+            assert_eq!(db.get("b").unwrap().as_deref(), Some(&b"2"[..]));
+        }
     }
 
-    /// Closes the database.
-    /// 
-    /// Currently a no-op, but provided for API completeness.
-    /// The log file is automatically flushed on each write.
-    pub fn close(self) -> std::io::Result<()> {
-        // Log is dropped here, which will flush any remaining buffers
-        // No explicit action needed
-        Ok(())
+    #[test]
+    fn test_overwrite_in_place() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put_versioned("counter", b"0001", 0).unwrap();
+        let before = db.get("counter").unwrap().unwrap().as_ptr();
+        
+        // Same length: the bytes are reused, and the version reset
+        db.put("counter", b"0002").unwrap();
+        assert_eq!(db.get("counter").unwrap().as_deref(), Some(&b"0002"[..]));
+        assert_eq!(db.get("counter").unwrap().unwrap().as_ptr(), before);
+        assert_eq!(db.get_versioned("counter").unwrap(), Some((Cow::from(&b"0002"[..]), 0)));
+        
+        // Different lengths get a new value
+        db.put("counter", b"10").unwrap();
+        assert_eq!(db.get("counter").unwrap().as_deref(), Some(&b"10"[..]));
+        db.put("counter", b"10000").unwrap();
+        assert_eq!(db.get("counter").unwrap().as_deref(), Some(&b"10000"[..]));
+        
+        // A value shared with a handle isn't overwritten under it
+        let handle = db.get_arc("counter").unwrap().unwrap();
+        db.put("counter", b"20000").unwrap();
+        assert_eq!(&*handle, b"10000");
+        assert_eq!(db.get("counter").unwrap().as_deref(), Some(&b"20000"[..]));
+        drop(db);
+        
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("counter").unwrap().as_deref(), Some(&b"20000"[..]));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+    #[test]
+    fn test_external_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let blob_dir = temp_dir.path().join("blobs");
+        let options = Options {
+            external_value_bytes: Some(16),
+            ..Options::default()
+        };
+        let large = vec![b'x'; 100];
+        let mut db = Db::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        db.put("small", b"short").unwrap();
+        db.put("large", &large).unwrap();
+        
+        // Only the large value left the log
+        assert_eq!(db.get("large").unwrap().as_deref(), Some(&large[..]));
+        assert_eq!(fs::read(blob_dir.join("0")).unwrap(), large);
+        assert_eq!(fs::read_dir(&blob_dir).unwrap().count(), 1);
+        let log_len = fs::metadata(temp_dir.path().join("log")).unwrap().len();
+        assert!(log_len < 100);
+        
+        // Overwriting replaces the blob, whether or not the new value is large
+        let larger = vec![b'y'; 200];
+        db.put("large", &larger).unwrap();
+        assert!(!blob_dir.join("0").exists());
+        assert_eq!(fs::read(blob_dir.join("1")).unwrap(), larger);
+        db.put("large", b"now small").unwrap();
+        assert!(!blob_dir.join("1").exists());
+        db.put("large", &larger).unwrap();
+        db.put("deleted", &large).unwrap();
+        db.delete("deleted").unwrap();
+        assert!(!blob_dir.join("3").exists());
+        assert!(db.verify_consistency().unwrap());
+        drop(db);
+        
+        // Recovery reads blobs back, in every replay mode, and new blobs
+        // don't reuse ids
+        let on_disk = Options {
+            values_on_disk: true,
+            ..options.clone()
+        };
+        for options in [options.clone(), on_disk] {
+            let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+            assert_eq!(db.get("large").unwrap().as_deref(), Some(&larger[..]));
+            assert_eq!(db.get("small").unwrap().as_deref(), Some(&b"short"[..]));
+            assert_eq!(db.get("deleted").unwrap(), None);
+        }
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("another", &large).unwrap();
+        assert!(blob_dir.join("4").exists());
+        
+        // A missing blob fails the open
+        fs::remove_file(blob_dir.join("4")).unwrap();
+        drop(db);
+        assert!(Db::open(temp_dir.path()).is_err());
+    }
 
     #[test]
-    fn test_put_and_get() {
+    fn test_keys_idle_since() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            track_access_time: true,
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            db.put(key, b"1").unwrap();
+        }
+        
+        let cutoff = Instant::now();
+        assert_eq!(db.keys_idle_since(cutoff), vec!["a", "b", "c", "d"]);
+        db.get("a").unwrap();
+        db.get("c").unwrap();
+        db.get("missing").unwrap();
+        assert_eq!(db.keys_idle_since(cutoff), vec!["b", "d"]);
+        
+        // Reads before the cutoff don't count
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(db.keys_idle_since(Instant::now()).len(), 4);
+        
+        // Deleting and re-putting a key forgets its reads
+        db.delete("a").unwrap();
+        db.put("a", b"2").unwrap();
+        assert_eq!(db.keys_idle_since(cutoff), vec!["a", "b", "d"]);
+        
+        // Untracked databases report every key
+        drop(db);
+        let db = Db::open(temp_dir.path()).unwrap();
+        db.get("a").unwrap();
+        assert_eq!(db.keys_idle_since(cutoff).len(), 4);
+    }
+
+    #[test]
+    fn test_cleanup_orphans() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let mut db = Db::open(dir).unwrap();
+        db.put("key1", b"value1").unwrap();
+        drop(db);
+        
+        // A temp file from an interrupted log replacement, next to files
+        // docdb didn't create
+        fs::write(dir.join("log.tmp"), b"partial").unwrap();
+        fs::write(dir.join("notes.txt"), b"mine").unwrap();
+        fs::create_dir(dir.join("backups")).unwrap();
+        
+        assert_eq!(Db::cleanup_orphans(dir).unwrap(), vec![dir.join("log.tmp")]);
+        let mut remaining: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, ["backups", "log", "notes.txt"]);
+        assert_eq!(Db::open(dir).unwrap().get("key1").unwrap().as_deref(), Some(&b"value1"[..]));
+        
+        // Nothing left to clean
+        assert_eq!(Db::cleanup_orphans(dir).unwrap(), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn test_cleanup_orphans_blobs() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let options = Options { external_value_bytes: Some(4), ..Options::default() };
+        let mut db = Db::open_with_options(dir, options.clone()).unwrap();
+        db.put("big", b"external value").unwrap();
+        db.put("gone", b"deleted value").unwrap();
+        db.delete("gone").unwrap();
+        drop(db);
+        
+        // A blob temp file from an interrupted write, a blob no record
+        // refers to, and a file docdb didn't create
+        let blob_dir = dir.join(BLOB_DIR);
+        fs::write(blob_dir.join("7.tmp"), b"partial").unwrap();
+        fs::write(blob_dir.join("999"), b"unlogged value").unwrap();
+        fs::write(blob_dir.join("notes.txt"), b"mine").unwrap();
+        
+        assert_eq!(
+            Db::cleanup_orphans(dir).unwrap(),
+            vec![blob_dir.join("7.tmp"), blob_dir.join("999")]
+        );
+        let mut remaining: Vec<_> = fs::read_dir(&blob_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, ["0", "notes.txt"]);
+        let db = Db::open_with_options(dir, options).unwrap();
+        assert_eq!(db.get("big").unwrap().as_deref(), Some(&b"external value"[..]));
+        drop(db);
+        
+        // Blobs are kept when the log can't be read
+        fs::write(blob_dir.join("999"), b"unlogged value").unwrap();
+        let mut log = fs::OpenOptions::new().append(true).open(dir.join("log")).unwrap();
+        log.write_all(&[0xEE; 16]).unwrap();
+        drop(log);
+        assert_eq!(Db::cleanup_orphans(dir).unwrap(), Vec::<PathBuf>::new());
+        assert!(blob_dir.join("999").exists());
+    }
+
+    #[test]
+    fn test_content_type() {
         let temp_dir = TempDir::new().unwrap();
         let mut db = Db::open(temp_dir.path()).unwrap();
         
+        db.put_with_content_type("raw", &[0, 255, 7], "application/octet-stream").unwrap();
+        db.put("json", b"{}").unwrap();
+        db.put_with_content_type("retyped", b"text", "text/plain").unwrap();
+        db.put("retyped", b"[]").unwrap();
+        db.put_with_content_type("deleted", b"x", "text/plain").unwrap();
+        db.delete("deleted").unwrap();
+        
+        let check = |db: &Db| {
+            assert_eq!(db.content_type("raw"), Some("application/octet-stream"));
+            assert_eq!(db.content_type("json"), Some(DEFAULT_CONTENT_TYPE));
+            // A later untyped put resets the tag
+            assert_eq!(db.content_type("retyped"), Some(DEFAULT_CONTENT_TYPE));
+            assert_eq!(db.content_type("deleted"), None);
+            assert_eq!(db.content_type("missing"), None);
+            assert_eq!(db.get("raw").unwrap().as_deref(), Some(&[0, 255, 7][..]));
+        };
+        check(&db);
+        drop(db);
+        
+        // Tags survive replay in every value storage mode
+        for values_on_disk in [false, true] {
+            let options = Options {
+                values_on_disk,
+                ..Options::default()
+            };
+            check(&Db::open_with_options(temp_dir.path(), options).unwrap());
+        }
+        
+        // And snapshots
+        let db = Db::open(temp_dir.path()).unwrap();
+        let mut snapshot = Vec::new();
+        db.snapshot_to_writer(&mut snapshot).unwrap();
+        let restore_dir = TempDir::new().unwrap();
+        check(&Db::restore_from_reader(restore_dir.path(), &mut snapshot.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let source_dir = TempDir::new().unwrap();
+        let mut db = Db::open(source_dir.path()).unwrap();
+        db.put("b", br#"{"n": 2}"#).unwrap();
+        db.put("a", b"").unwrap();
+        db.put("c", &[0, 255, 7]).unwrap();
+        db.put("b", br#"{"n": 3}"#).unwrap();
+        db.delete("c").unwrap();
+        
+        let mut snapshot = Vec::new();
+        db.snapshot_to_writer(&mut snapshot).unwrap();
+        
+        let restore_dir = TempDir::new().unwrap();
+        let restored = Db::restore_from_reader(restore_dir.path(), &mut snapshot.as_slice()).unwrap();
+        let entries = |db: &Db| -> Vec<(String, Vec<u8>)> {
+            db.keys()
+                .map(|key| (key.clone(), db.get(key).unwrap().unwrap().to_vec()))
+                .collect()
+        };
+        assert_eq!(entries(&restored), entries(&db));
+        assert_eq!(restored.keys().count(), 2);
+        
+        // The restored log holds only the live entries
+        let records = Log::read_all(restore_dir.path().join("log")).unwrap();
+        assert_eq!(records.len(), 2);
+        
+        // Restoring never overwrites an existing database
+        let Err(err) = Db::restore_from_reader(source_dir.path(), &mut snapshot.as_slice()) else {
+            panic!("Restore over an existing database should fail");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        
+        // A truncated snapshot is rejected without creating a log
+        let truncated_dir = TempDir::new().unwrap();
+        let mut truncated = &snapshot[..snapshot.len() - 1];
+        let Err(err) = Db::restore_from_reader(truncated_dir.path(), &mut truncated) else {
+            panic!("Restore from a truncated snapshot should fail");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(!truncated_dir.path().join("log").exists());
+    }
+
+    #[test]
+    fn test_destroy() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            defer_flush: true,
+            unflushed_drop: UnflushedDropPolicy::Panic,
+            ..Options::default()
+        };
+        {
+            let mut db = Db::open(temp_dir.path()).unwrap();
+            db.put("key1", b"value1").unwrap();
+            db.close().unwrap();
+        }
+        fs::write(temp_dir.path().join("log.tmp"), b"partial").unwrap();
+        
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        db.put("key2", b"value2").unwrap();
+        db.destroy().unwrap();
+        
+        assert!(temp_dir.path().exists());
+        assert!(!temp_dir.path().join("log").exists());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        
+        // The directory can host a fresh, empty database afterwards
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.keys().count(), 0);
+    }
+
+    #[test]
+    fn test_verify_consistency() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            defer_flush: true,
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert!(db.verify_consistency().unwrap());
+        
         db.put("key1", b"value1").unwrap();
+        db.put("key2", b"value2").unwrap();
+        db.flush().unwrap();
+        db.put("key1", b"value3").unwrap();
+        db.delete("key2").unwrap();
+        db.put_all_atomic(vec![("key4".to_string(), b"value4".to_vec())])
+            .unwrap();
+        
+        // Includes writes still buffered in deferred mode
+        assert!(db.verify_consistency().unwrap());
+        db.close().unwrap();
         
-        assert_eq!(db.get("key1"), Some(b"value1".as_slice()));
-        assert_eq!(db.get("nonexistent"), None);
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        assert!(db.verify_consistency().unwrap());
     }
 
     #[test]
-    fn test_delete() {
+    fn test_verify_consistency_detects_desync() {
         let temp_dir = TempDir::new().unwrap();
         let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("key1", b"value1").unwrap();
+        db.put("key2", b"value2").unwrap();
+        assert!(db.verify_consistency().unwrap());
+        
+        // Bypass the log, as an index-maintenance bug would
+        db.index_insert("key1".to_string(), StoredValue::Owned(Arc::from(&b"stale"[..])));
+        assert!(!db.verify_consistency().unwrap());
         
         db.put("key1", b"value1").unwrap();
-        assert_eq!(db.get("key1"), Some(b"value1".as_slice()));
+        assert!(db.verify_consistency().unwrap());
+        db.index_remove("key2");
+        assert!(!db.verify_consistency().unwrap());
         
-        db.delete("key1").unwrap();
-        assert_eq!(db.get("key1"), None);
+        db.index_insert("key2".to_string(), StoredValue::Owned(Arc::from(&b"value2"[..])));
+        db.index_insert("extra".to_string(), StoredValue::Owned(Arc::from(&b"value"[..])));
+        assert!(!db.verify_consistency().unwrap());
+    }
+
+    #[test]
+    fn test_history() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("key", b"v1").unwrap();
+        db.put("other", b"x").unwrap();
+        db.put("key", b"v2").unwrap();
+        db.delete("key").unwrap();
+        db.put_all_atomic(vec![
+            ("other".to_string(), b"y".to_vec()),
+            ("key".to_string(), b"v3".to_vec()),
+        ])
+        .unwrap();
+        db.close().unwrap();
+        
+        let history = Db::history(temp_dir.path(), "key").unwrap();
+        assert_eq!(
+            history,
+            vec![
+                HistoricalVersion::Put(b"v1".to_vec()),
+                HistoricalVersion::Put(b"v2".to_vec()),
+                HistoricalVersion::Delete,
+                HistoricalVersion::Put(b"v3".to_vec()),
+            ]
+        );
+        assert!(Db::history(temp_dir.path(), "missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_put_owned() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        
+        let value = br#"{"name":"owned"}"#.to_vec();
+        db.put_owned("key1", value).unwrap();
+        db.put_owned(String::from("key2"), "text").unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&br#"{"name":"owned"}"#[..]));
+        assert_eq!(db.get("key2").unwrap().as_deref(), Some(&b"text"[..]));
+        drop(db);
+        
+        // Owned puts are logged like any other
+        let db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(&br#"{"name":"owned"}"#[..]));
+        assert_eq!(db.get("key2").unwrap().as_deref(), Some(&b"text"[..]));
+        assert!(db.verify_consistency().unwrap());
+    }
+
+    #[test]
+    fn test_put_owned_values_on_disk() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = Options {
+            values_on_disk: true,
+            value_cache_bytes: Some(1024),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(temp_dir.path(), options).unwrap();
+        
+        db.put_owned("key", b"value".to_vec()).unwrap();
+        assert_eq!(db.get("key").unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(db.metrics().unwrap().cache_hits, 1);
+        assert!(db.verify_consistency().unwrap());
+    }
+
+    #[test]
+    fn test_push() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        db.put("other", b"x").unwrap();
+        
+        assert_eq!(db.push(b"first").unwrap(), 0);
+        assert_eq!(db.push(b"second").unwrap(), 1);
+        assert_eq!(Db::push_key(1), "00000000000000000001");
+        assert_eq!(db.get(&Db::push_key(0)).unwrap().as_deref(), Some(&b"first"[..]));
+        assert_eq!(db.get(&Db::push_key(1)).unwrap().as_deref(), Some(&b"second"[..]));
+        
+        // Deleting the latest id doesn't rewind this handle
+        db.delete(&Db::push_key(1)).unwrap();
+        assert_eq!(db.push(b"third").unwrap(), 2);
+        db.close().unwrap();
+        
+        // Numbering continues after a reopen
+        let mut db = Db::open(temp_dir.path()).unwrap();
+        assert_eq!(db.push(b"fourth").unwrap(), 3);
+        
+        // Pushed keys sort numerically
+        db.push(b"fifth").unwrap();
+        let keys: Vec<&String> = db.keys().collect();
+        assert_eq!(
+            keys,
+            vec![&Db::push_key(0), &Db::push_key(2), &Db::push_key(3), &Db::push_key(4), "other"]
+        );
     }
 
     #[test]
@@ -176,7 +6399,7 @@ mod tests {
         db.put("key1", b"value1").unwrap();
         db.put("key1", b"value2").unwrap();
         
-        assert_eq!(db.get("key1"), Some(b"value2".as_slice()));
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value2".as_slice()));
     }
 
     #[test]
@@ -188,9 +6411,9 @@ mod tests {
         db.put("key2", b"value2").unwrap();
         db.put("key3", b"value3").unwrap();
         
-        assert_eq!(db.get("key1"), Some(b"value1".as_slice()));
-        assert_eq!(db.get("key2"), Some(b"value2".as_slice()));
-        assert_eq!(db.get("key3"), Some(b"value3".as_slice()));
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
+        assert_eq!(db.get("key2").unwrap().as_deref(), Some(b"value2".as_slice()));
+        assert_eq!(db.get("key3").unwrap().as_deref(), Some(b"value3".as_slice()));
     }
 
     #[test]
@@ -206,7 +6429,7 @@ mod tests {
         
         // Reopen and verify the value is still there
         let db = Db::open(temp_dir.path()).unwrap();
-        assert_eq!(db.get("key1"), Some(b"value1".as_slice()));
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
     }
 
     #[test]
@@ -223,7 +6446,7 @@ mod tests {
         
         // Reopen and verify the key is gone
         let db = Db::open(temp_dir.path()).unwrap();
-        assert_eq!(db.get("key1"), None);
+        assert_eq!(db.get("key1").unwrap().as_deref(), None);
     }
 
     #[test]
@@ -243,9 +6466,9 @@ mod tests {
         
         // Reopen and verify final state
         let db = Db::open(temp_dir.path()).unwrap();
-        assert_eq!(db.get("key1"), Some(b"value1_updated".as_slice()));
-        assert_eq!(db.get("key2"), None);
-        assert_eq!(db.get("key3"), Some(b"value3".as_slice()));
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1_updated".as_slice()));
+        assert_eq!(db.get("key2").unwrap().as_deref(), None);
+        assert_eq!(db.get("key3").unwrap().as_deref(), Some(b"value3".as_slice()));
     }
 
     #[test]
@@ -253,7 +6476,7 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
         let db = Db::open(temp_dir.path()).unwrap();
         
-        assert_eq!(db.get("anykey"), None);
+        assert_eq!(db.get("anykey").unwrap().as_deref(), None);
     }
 
     #[test]
@@ -272,7 +6495,7 @@ mod tests {
         
         // Should be able to use the database
         db.put("key1", b"value1").unwrap();
-        assert_eq!(db.get("key1"), Some(b"value1".as_slice()));
+        assert_eq!(db.get("key1").unwrap().as_deref(), Some(b"value1".as_slice()));
         
         // Log file should exist
         let log_path = db_path.join("log");
@@ -287,7 +6510,9 @@ mod tests {
         
         // Test multiple sequences: (operations, expected_final_state)
         // expected_final_state is a map of key -> expected value (None if deleted)
-        let test_cases: Vec<(Vec<(&str, &str, Option<&str>)>, Vec<(&str, Option<&str>)>)> = vec![
+        type Operation<'a> = (&'a str, &'a str, Option<&'a str>);
+        type Expectation<'a> = (&'a str, Option<&'a str>);
+        let test_cases: Vec<(Vec<Operation>, Vec<Expectation>)> = vec![
             (
                 vec![("put", "key1", Some("value1"))],
                 vec![("key1", Some("value1"))],
@@ -338,7 +6563,8 @@ mod tests {
             // Reopen and verify final state matches expectations
             let db = Db::open(temp_dir.path()).unwrap();
             for (key, expected_value) in expected_final_state {
-                let actual = db.get(key);
+                let actual = db.get(key).unwrap();
+                let actual = actual.as_deref();
                 let expected = expected_value.map(|v| v.as_bytes());
                 assert_eq!(
                     actual,
@@ -0,0 +1,169 @@
+use std::io::{Error, ErrorKind};
+
+/// How the library writes JSON values out again, as in
+/// `Db::get_json_formatted` and `Db::export_json`.
+/// 
+/// The default is compact with sorted keys, so the same data always formats
+/// to the same bytes, whatever order its keys were put in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Put each member and element on its own line, indented two spaces per
+    /// level, as `serde_json::to_string_pretty` does. Otherwise no
+    /// whitespace is written between tokens.
+    pub pretty: bool,
+    /// Write object keys in sorted order, at every level. Otherwise they
+    /// keep the order they were stored in.
+    /// 
+    /// Sorting parses the value, so numbers are written the way
+    /// `serde_json` prints them (`1e2` becomes `100.0`); without sorting,
+    /// every token is kept as stored.
+    pub sort_keys: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            pretty: false,
+            sort_keys: true,
+        }
+    }
+}
+
+impl FormatOptions {
+    /// Formats the JSON document `value`.
+    /// 
+    /// Fails with `ErrorKind::InvalidData` if `value` isn't valid JSON.
+    pub fn format(&self, value: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.format_nested(value, 0)
+    }
+
+    /// Formats `value` for a pretty document that nests it `depth` levels
+    /// deep, indenting its inner lines to match.
+    pub(crate) fn format_nested(&self, value: &[u8], depth: usize) -> std::io::Result<Vec<u8>> {
+        let invalid = |e| Error::new(ErrorKind::InvalidData, e);
+        if self.sort_keys {
+            // serde_json's map keeps its keys sorted
+            let json: serde_json::Value = serde_json::from_slice(value).map_err(invalid)?;
+            let sorted = serde_json::to_vec(&json).map_err(invalid)?;
+            return Ok(reindent(&sorted, self.pretty, depth));
+        }
+        serde_json::from_slice::<serde::de::IgnoredAny>(value).map_err(invalid)?;
+        Ok(reindent(value, self.pretty, depth))
+    }
+}
+
+/// Rewrites the whitespace between the tokens of valid JSON text, for
+/// pretty output starting `depth` levels deep or for compact output.
+fn reindent(json: &[u8], pretty: bool, mut depth: usize) -> Vec<u8> {
+    let newline = |out: &mut Vec<u8>, depth: usize| {
+        out.push(b'\n');
+        out.resize(out.len() + 2 * depth, b' ');
+    };
+    let mut out = Vec::with_capacity(json.len());
+    let (mut in_string, mut escaped) = (false, false);
+    let mut i = 0;
+    
+    while i < json.len() {
+        let byte = json[i];
+        i += 1;
+        if in_string {
+            out.push(byte);
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+        
+        match byte {
+            b' ' | b'\t' | b'\n' | b'\r' => {}
+            b'"' => {
+                in_string = true;
+                out.push(byte);
+            }
+            b'{' | b'[' => {
+                out.push(byte);
+                let close = json[i..].iter().position(|b| !b.is_ascii_whitespace());
+                match close.map(|offset| (i + offset, json[i + offset])) {
+                    // Empty containers stay on one line
+                    Some((end, b'}' | b']')) => {
+                        out.push(json[end]);
+                        i = end + 1;
+                    }
+                    _ if pretty => {
+                        depth += 1;
+                        newline(&mut out, depth);
+                    }
+                    _ => {}
+                }
+            }
+            b'}' | b']' => {
+                if pretty {
+                    depth -= 1;
+                    newline(&mut out, depth);
+                }
+                out.push(byte);
+            }
+            b',' => {
+                out.push(byte);
+                if pretty {
+                    newline(&mut out, depth);
+                }
+            }
+            b':' => {
+                out.push(byte);
+                if pretty {
+                    out.push(b' ');
+                }
+            }
+            _ => out.push(byte),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(value: &str, pretty: bool, sort_keys: bool) -> String {
+        let options = FormatOptions { pretty, sort_keys };
+        String::from_utf8(options.format(value.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let value = r#"{ "b": [1, {"y": 2, "x": 1}], "a": "s, \"t\": {" }"#;
+        assert_eq!(format(value, false, true), r#"{"a":"s, \"t\": {","b":[1,{"x":1,"y":2}]}"#);
+        assert_eq!(format(value, false, false), r#"{"b":[1,{"y":2,"x":1}],"a":"s, \"t\": {"}"#);
+        
+        // Key order in the input doesn't change sorted output
+        let reordered = r#"{"a": "s, \"t\": {", "b": [1, {"x": 1, "y": 2}]}"#;
+        assert_eq!(format(reordered, false, true), format(value, false, true));
+    }
+
+    #[test]
+    fn test_pretty_matches_serde_json() {
+        let value = r#"{"a": [], "b": {}, "c": [1, [2, {"d": null}]], "e": "x"}"#;
+        let json: serde_json::Value = serde_json::from_str(value).unwrap();
+        let expected = serde_json::to_string_pretty(&json).unwrap();
+        assert_eq!(format(value, true, true), expected);
+        assert_eq!(format(value, true, false), expected);
+        assert_eq!(format("7", true, false), "7");
+    }
+
+    #[test]
+    fn test_nested_and_invalid() {
+        let nested = FormatOptions { pretty: true, sort_keys: false };
+        let formatted = nested.format_nested(br#"{"a":[1]}"#, 1).unwrap();
+        assert_eq!(formatted, b"{\n    \"a\": [\n      1\n    ]\n  }");
+        
+        for options in [FormatOptions::default(), nested] {
+            let err = options.format(b"{\"a\":").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::crc::crc32;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Atomically replaces `dir/final_name` with `contents`.
+/// 
+/// Every operation that swaps a whole file (compaction, clear, snapshots)
+/// must go through this helper so that a crash leaves either the old file or
+/// the new one, never a partial write.
+/// 
+/// Steps:
+/// 1. Write `contents` to a temp file in the same directory (so the rename
+///    never crosses filesystems).
+/// 2. Fsync the temp file and read it back, checking its CRC-32 against
+///    `contents` before it is allowed to replace anything.
+/// 3. Rename the temp file over the final name.
+/// 4. Fsync the directory so the rename itself is durable.
+/// 
+/// On error the temp file is removed and the original file is untouched.
+pub fn atomic_write(dir: &Path, final_name: &str, contents: &[u8]) -> std::io::Result<()> {
+    let temp_path = temp_path(dir, final_name);
+    
+    if let Err(e) = write_verified(&temp_path, contents) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    
+    if let Err(e) = fs::rename(&temp_path, dir.join(final_name)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    
+    sync_dir(dir)
+}
+
+/// Fsyncs a directory so that entries created, renamed, or removed in it
+/// survive a crash.
+/// 
+/// This is a no-op on platforms where directories cannot be opened as files.
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+    }
+    Ok(())
+}
+
+/// Returns the bytes available to unprivileged writers on the filesystem
+/// holding `dir`.
+/// 
+/// Always `u64::MAX` on platforms without `statvfs`, so space checks built
+/// on it never reject anything there.
+pub fn available_space(dir: &Path) -> std::io::Result<u64> {
+    #[cfg(unix)]
+    {
+        let stat = nix::sys::statvfs::statvfs(dir).map_err(std::io::Error::from)?;
+        // The field types differ between platforms
+        #[allow(clippy::useless_conversion)]
+        let available =
+            u64::from(stat.blocks_available()).saturating_mul(u64::from(stat.fragment_size()));
+        Ok(available)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok(u64::MAX)
+    }
+}
+
+/// Fails with `ErrorKind::StorageFull` unless writing `needed` bytes would
+/// leave at least `margin` of the `available` bytes free.
+pub(crate) fn check_free_space(available: u64, needed: u64, margin: u64) -> std::io::Result<()> {
+    if available < needed.saturating_add(margin) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            format!(
+                "Insufficient disk space: {} bytes available, and a {} byte write must leave {} free",
+                available, needed, margin
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the temp path used while atomically writing `final_name`.
+pub(crate) fn temp_path(dir: &Path, final_name: &str) -> PathBuf {
+    dir.join(format!("{}.tmp", final_name))
+}
+
+/// Writes `contents` to `path`, fsyncs it, and verifies the on-disk CRC.
+fn write_verified(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    
+    let mut written = Vec::with_capacity(contents.len());
+    File::open(path)?.read_to_end(&mut written)?;
+    if crc32(&written) != crc32(contents) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Checksum mismatch after writing {}", path.display()),
+        ));
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_atomic_write_creates_file_and_removes_temp() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        
+        atomic_write(dir, "data", b"contents").unwrap();
+        
+        assert_eq!(fs::read(dir.join("data")).unwrap(), b"contents");
+        assert!(!temp_path(dir, "data").exists());
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        assert!(check_free_space(1000, 100, 900).is_ok());
+        assert!(check_free_space(1000, 100, 0).is_ok());
+        let err = check_free_space(1000, 101, 900).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        // A write larger than the space left can't underflow
+        assert!(check_free_space(10, 100, 0).is_err());
+        
+        let temp_dir = TempDir::new().unwrap();
+        assert!(available_space(temp_dir.path()).unwrap() > 0);
+        assert!(available_space(&temp_dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_atomic_write_replaces_existing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        
+        fs::write(dir.join("data"), b"old contents that are longer").unwrap();
+        atomic_write(dir, "data", b"new").unwrap();
+        
+        assert_eq!(fs::read(dir.join("data")).unwrap(), b"new");
+        assert!(!temp_path(dir, "data").exists());
+    }
+
+    #[test]
+    fn test_atomic_write_failure_leaves_original() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        
+        // A directory at the final path makes the rename fail
+        fs::create_dir(dir.join("data")).unwrap();
+        assert!(atomic_write(dir, "data", b"contents").is_err());
+        
+        assert!(dir.join("data").is_dir());
+        assert!(!temp_path(dir, "data").exists());
+    }
+}
@@ -0,0 +1,198 @@
+/// Shell-style glob patterns for matching keys.
+/// 
+/// Supported syntax:
+/// - `*` matches any run of characters, including none.
+/// - `?` matches exactly one character.
+/// - `[abc]` matches one of the listed characters, `[a-z]` one in the
+///   range, and `[!abc]` or `[^abc]` one not listed.
+/// - `\` matches the next character literally, e.g. `\*`.
+/// 
+/// Any other character, and a `[` with no closing `]`, matches itself.
+/// Matching is by whole `char`, against the whole key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+/// One element of a parsed pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// Matches this character.
+    Literal(char),
+    /// `?`: matches any one character.
+    Any,
+    /// `*`: matches any run of characters.
+    Star,
+    /// `[...]`: matches one character in (or, if negated, not in) the
+    /// inclusive ranges. Single characters are ranges of one.
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+impl Glob {
+    /// Parses a pattern. Every string is a valid pattern.
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        
+        while i < chars.len() {
+            match chars[i] {
+                '*' => {
+                    // Consecutive stars match the same as one
+                    if tokens.last() != Some(&Token::Star) {
+                        tokens.push(Token::Star);
+                    }
+                }
+                '?' => tokens.push(Token::Any),
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    tokens.push(Token::Literal(chars[i]));
+                }
+                '[' => match parse_class(&chars, i) {
+                    Some((class, end)) => {
+                        tokens.push(class);
+                        i = end;
+                    }
+                    None => tokens.push(Token::Literal('[')),
+                },
+                c => tokens.push(Token::Literal(c)),
+            }
+            i += 1;
+        }
+        
+        Glob { tokens }
+    }
+
+    /// Returns true if the pattern matches all of `text`.
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let (mut t, mut p) = (0, 0);
+        // Where to resume after the most recent star: the token after it,
+        // and the text position it has consumed up to
+        let mut backtrack: Option<(usize, usize)> = None;
+        
+        while t < text.len() {
+            match self.tokens.get(p) {
+                Some(Token::Star) => {
+                    backtrack = Some((p + 1, t));
+                    p += 1;
+                }
+                Some(token) if token.matches_char(text[t]) => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => match backtrack {
+                    // Let the star swallow one more character and retry
+                    Some((star_next, star_pos)) => {
+                        backtrack = Some((star_next, star_pos + 1));
+                        p = star_next;
+                        t = star_pos + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+        
+        // Only stars can match the empty rest of the text
+        self.tokens[p..].iter().all(|token| *token == Token::Star)
+    }
+}
+
+impl Token {
+    /// Returns true if this single-character token matches `c`.
+    fn matches_char(&self, c: char) -> bool {
+        match self {
+            Token::Literal(literal) => *literal == c,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+        }
+    }
+}
+
+/// Parses the class opening at `chars[start]`, a `[`.
+/// 
+/// Returns the class and the index of its closing `]`, or `None` if it is
+/// never closed. A `]` right after the `[` (or its negation) is taken
+/// literally, as in shells.
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(chars.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    
+    let mut ranges = Vec::new();
+    let first = i;
+    loop {
+        let c = *chars.get(i)?;
+        if c == ']' && i > first {
+            return Some((Token::Class { negated, ranges }, i));
+        }
+        // A `-` between two characters forms a range
+        match (chars.get(i + 1), chars.get(i + 2)) {
+            (Some('-'), Some(&hi)) if hi != ']' => {
+                ranges.push((c, hi));
+                i += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Glob::new(pattern).matches(text)
+    }
+
+    #[test]
+    fn test_star() {
+        assert!(matches("session:*:active", "session:42:active"));
+        assert!(matches("session:*:active", "session::active"));
+        assert!(matches("session:*:active", "session:a:b:active"));
+        assert!(!matches("session:*:active", "session:42:idle"));
+        assert!(matches("*", ""));
+        assert!(matches("a**b", "ab"));
+        assert!(matches("*a*b", "xxaxxb"));
+        assert!(!matches("*a*b", "xxaxxbx"));
+    }
+
+    #[test]
+    fn test_question_mark() {
+        assert!(matches("user?", "user1"));
+        assert!(!matches("user?", "user"));
+        assert!(!matches("user?", "user12"));
+        // One character, not one byte
+        assert!(matches("caf?", "café"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("key[0-9]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+        assert!(matches("[abc]x", "bx"));
+        assert!(matches("[!abc]x", "dx"));
+        assert!(!matches("[^abc]x", "ax"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        // Unclosed classes and escapes are literal
+        assert!(matches("[abc", "[abc"));
+        assert!(matches(r"a\*", "a*"));
+        assert!(!matches(r"a\*", "ab"));
+    }
+
+    #[test]
+    fn test_no_match() {
+        assert!(!matches("nothing*", "something"));
+        assert!(!matches("", "a"));
+        assert!(matches("", ""));
+    }
+}
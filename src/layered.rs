@@ -0,0 +1,127 @@
+use crate::db::Db;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+/// Databases stacked in priority order, such as overrides over environment
+/// settings over defaults.
+/// 
+/// Reads return the value from the highest-priority layer holding the key;
+/// writes go to the top layer only. Deleting a key from the top layer
+/// uncovers any value a lower layer holds for it.
+pub struct LayeredDb {
+    /// The layers, highest priority (the top, taking writes) first.
+    /// 
+    /// Invariant: Never empty.
+    layers: Vec<Db>,
+}
+
+impl LayeredDb {
+    /// Stacks `layers`, highest priority first. The first layer is the top,
+    /// which takes writes.
+    /// 
+    /// Fails with `ErrorKind::InvalidInput` if `layers` is empty.
+    pub fn new(layers: Vec<Db>) -> std::io::Result<Self> {
+        if layers.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "A layered database needs at least one layer",
+            ));
+        }
+        Ok(LayeredDb { layers })
+    }
+
+    /// Retrieves the value of `key` from the highest-priority layer that
+    /// has it.
+    /// 
+    /// Values kept on disk are read as `Db::get` reads them; a failed
+    /// read fails the lookup rather than falling through to lower layers.
+    pub fn get(&self, key: &str) -> std::io::Result<Option<Cow<'_, [u8]>>> {
+        for layer in &self.layers {
+            if let Some(value) = layer.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns every key held by any layer, in sorted order, each once.
+    pub fn keys(&self) -> Vec<&String> {
+        let keys: BTreeSet<&String> = self.layers.iter().flat_map(Db::keys).collect();
+        keys.into_iter().collect()
+    }
+
+    /// Stores a key-value pair in the top layer, overriding lower layers.
+    pub fn put(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.top_mut().put(key, value)
+    }
+
+    /// Deletes a key from the top layer. Lower layers are unchanged, so a
+    /// value one of them holds becomes visible again.
+    pub fn delete(&mut self, key: &str) -> std::io::Result<()> {
+        self.top_mut().delete(key)
+    }
+
+    /// Returns the layers, highest priority first.
+    pub fn layers(&self) -> &[Db] {
+        &self.layers
+    }
+
+    /// Returns the top layer, which takes writes.
+    pub fn top_mut(&mut self) -> &mut Db {
+        &mut self.layers[0]
+    }
+
+    /// Unstacks the layers, highest priority first, e.g. to close each.
+    pub fn into_layers(self) -> Vec<Db> {
+        self.layers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Options;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_layers() {
+        let temp_dir = TempDir::new().unwrap();
+        // Lower layers may keep their values on disk
+        let options = Options {
+            values_on_disk: true,
+            ..Options::default()
+        };
+        let mut defaults =
+            Db::open_with_options(temp_dir.path().join("defaults"), options).unwrap();
+        defaults.put("color", b"\"blue\"").unwrap();
+        defaults.put("size", b"10").unwrap();
+        let mut overrides = Db::open(temp_dir.path().join("overrides")).unwrap();
+        overrides.put("size", b"12").unwrap();
+        overrides.put("font", b"\"mono\"").unwrap();
+        
+        let mut layered = LayeredDb::new(vec![overrides, defaults]).unwrap();
+        assert_eq!(layered.get("size").unwrap().as_deref(), Some(&b"12"[..]));
+        assert_eq!(layered.get("color").unwrap().as_deref(), Some(&b"\"blue\""[..]));
+        assert_eq!(layered.get("font").unwrap().as_deref(), Some(&b"\"mono\""[..]));
+        assert_eq!(layered.get("missing").unwrap(), None);
+        assert_eq!(layered.keys(), vec!["color", "font", "size"]);
+        
+        // Writes land in the top layer only
+        layered.put("color", b"\"red\"").unwrap();
+        assert_eq!(layered.get("color").unwrap().as_deref(), Some(&b"\"red\""[..]));
+        let lower = layered.layers()[1].get("color").unwrap();
+        assert_eq!(lower.as_deref(), Some(&b"\"blue\""[..]));
+        
+        // Deleting from the top uncovers the lower layer's value
+        layered.delete("size").unwrap();
+        assert_eq!(layered.get("size").unwrap().as_deref(), Some(&b"10"[..]));
+        layered.delete("font").unwrap();
+        assert_eq!(layered.get("font").unwrap(), None);
+        assert_eq!(layered.keys(), vec!["color", "size"]);
+        
+        for layer in layered.into_layers() {
+            layer.close().unwrap();
+        }
+        assert!(LayeredDb::new(Vec::new()).is_err());
+    }
+}
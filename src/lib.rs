@@ -0,0 +1,16 @@
+//! DocDB: a simple, crash-safe document database.
+//! 
+//! The library exposes the database (`db`), the append-only log it is built
+//! on (`log`), databases stacked in priority order (`layered`), operation
+//! metrics (`metrics`), key patterns (`glob`), and JSON output formatting
+//! (`format`). The file helpers used to keep on-disk state crash-safe
+//! (`fsutil`) are internal.
+
+pub mod crc;
+pub mod db;
+pub mod format;
+pub(crate) mod fsutil;
+pub mod glob;
+pub mod layered;
+pub mod log;
+pub mod metrics;
@@ -1,5 +1,8 @@
+use serde::{Deserialize, Serialize};
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Read, Write};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::{Range, RangeInclusive};
 use std::path::Path;
 
 /// Record type identifiers for the append-only log.
@@ -7,16 +10,195 @@ use std::path::Path;
 /// Invariant: Each record type has a unique byte value.
 const RECORD_PUT: u8 = 0;
 const RECORD_DELETE: u8 = 1;
+const RECORD_BATCH: u8 = 2;
+const RECORD_TYPED_PUT: u8 = 3;
+const RECORD_VERSIONED_PUT: u8 = 4;
+const RECORD_BLOB_PUT: u8 = 5;
+const RECORD_PENDING: u8 = 6;
+const RECORD_COMMIT: u8 = 7;
+
+/// Record type bytes reserved for experimental record types.
+/// 
+/// Records of these types are framed by a payload length (see `Log`), so
+/// readers that don't know a type can skip over it. Types below this range
+/// belong to docdb's own record formats; an unknown one there is an error.
+pub const EXPERIMENTAL_RECORD_TYPES: RangeInclusive<u8> = 0x80..=0xFF;
+
+/// Version of the on-disk record format described on `Log`.
+/// 
+/// Logs don't carry a header yet, so every existing log is version 1.
+pub const FORMAT_VERSION: u32 = 1;
 
 /// Represents a single operation in the log.
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// 
+/// Serializes as an object tagged by `"op"` (`"put"`, `"typed_put"`,
+/// `"versioned_put"`, `"blob_put"`, or `"delete"`), with keys and values as
+/// arrays of byte values, e.g.
+/// `{"op":"delete","key":[97]}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
 pub enum LogRecord {
     /// Put operation: store a key-value pair.
     Put { key: Vec<u8>, value: Vec<u8> },
+    /// Put operation that also tags the value with a content type, such as
+    /// `application/octet-stream`.
+    #[serde(rename = "typed_put")]
+    TypedPut {
+        key: Vec<u8>,
+        content_type: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Put operation that also records the value's version, for
+    /// `Db::put_versioned`.
+    #[serde(rename = "versioned_put")]
+    VersionedPut {
+        key: Vec<u8>,
+        version: u64,
+        value: Vec<u8>,
+    },
+    /// Put operation whose value is stored outside the log, in the blob
+    /// file with this id, for `Options::external_value_bytes`.
+    #[serde(rename = "blob_put")]
+    BlobPut { key: Vec<u8>, blob: u64 },
     /// Delete operation: remove a key.
     Delete { key: Vec<u8> },
 }
 
+/// Location of one record's fields within the bytes of a log.
+/// 
+/// Produced by `Log::scan`, for readers that borrow keys and values from a
+/// buffer instead of copying them out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordSpan {
+    /// Put operation: byte ranges of the key and value, the byte range of
+    /// the content type for a typed put, and the version for a versioned
+    /// put.
+    Put {
+        key: Range<usize>,
+        content_type: Option<Range<usize>>,
+        version: Option<u64>,
+        value: Range<usize>,
+    },
+    /// Put operation with an external value: byte range of the key and the
+    /// id of the blob file holding the value.
+    BlobPut { key: Range<usize>, blob: u64 },
+    /// Delete operation: byte range of the key.
+    Delete { key: Range<usize> },
+}
+
+impl RecordSpan {
+    /// Returns the byte range of the record's key.
+    pub fn key(&self) -> Range<usize> {
+        match self {
+            RecordSpan::Put { key, .. } => key.clone(),
+            RecordSpan::BlobPut { key, .. } => key.clone(),
+            RecordSpan::Delete { key } => key.clone(),
+        }
+    }
+
+    /// Returns the offset just past the record, its last field being the
+    /// value of a Put, the blob id of a Blob Put, or the key of a Delete.
+    pub fn end(&self) -> usize {
+        match self {
+            RecordSpan::Put { value, .. } => value.end,
+            RecordSpan::BlobPut { key, .. } => key.end + 8,
+            RecordSpan::Delete { key } => key.end,
+        }
+    }
+
+    /// Copies the record out of `bytes`, the buffer the span was scanned from.
+    pub fn to_record(&self, bytes: &[u8]) -> LogRecord {
+        match self {
+            RecordSpan::Put { key, version: Some(version), value, .. } => {
+                LogRecord::VersionedPut {
+                    key: bytes[key.clone()].to_vec(),
+                    version: *version,
+                    value: bytes[value.clone()].to_vec(),
+                }
+            }
+            RecordSpan::Put { key, content_type: None, value, .. } => LogRecord::Put {
+                key: bytes[key.clone()].to_vec(),
+                value: bytes[value.clone()].to_vec(),
+            },
+            RecordSpan::Put { key, content_type: Some(content_type), value, .. } => {
+                LogRecord::TypedPut {
+                    key: bytes[key.clone()].to_vec(),
+                    content_type: bytes[content_type.clone()].to_vec(),
+                    value: bytes[value.clone()].to_vec(),
+                }
+            }
+            RecordSpan::BlobPut { key, blob } => LogRecord::BlobPut {
+                key: bytes[key.clone()].to_vec(),
+                blob: *blob,
+            },
+            RecordSpan::Delete { key } => LogRecord::Delete {
+                key: bytes[key.clone()].to_vec(),
+            },
+        }
+    }
+}
+
+/// Parses the payload of an experimental record into a log record.
+/// 
+/// Returns `None` if the payload is malformed.
+pub type ExperimentalParser = fn(&[u8]) -> Option<LogRecord>;
+
+/// Parsers for experimental record types, used by `Log::read_all_with`.
+/// 
+/// Experimental records whose type has no registered parser are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct RecordRegistry {
+    parsers: HashMap<u8, ExperimentalParser>,
+}
+
+impl RecordRegistry {
+    /// Creates a registry with no experimental types registered.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `parse` as the parser for `record_type`, replacing any
+    /// earlier one.
+    /// 
+    /// Fails with `ErrorKind::InvalidInput` if `record_type` isn't in
+    /// `EXPERIMENTAL_RECORD_TYPES`.
+    pub fn register(&mut self, record_type: u8, parse: ExperimentalParser) -> std::io::Result<()> {
+        check_experimental(record_type)?;
+        self.parsers.insert(record_type, parse);
+        Ok(())
+    }
+
+    /// Parses an experimental record's payload.
+    /// 
+    /// Returns `None` if its type isn't registered.
+    fn parse(&self, record_type: u8, payload: &[u8]) -> std::io::Result<Option<LogRecord>> {
+        let Some(parse) = self.parsers.get(&record_type) else {
+            return Ok(None);
+        };
+        parse(payload).map(Some).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Malformed experimental record of type {}", record_type),
+            )
+        })
+    }
+}
+
+/// Summary of a log replay.
+/// 
+/// Produced by `Log::read_all_lenient`, which skips corrupt regions instead
+/// of failing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of records successfully parsed.
+    pub records_recovered: usize,
+    /// Number of corrupt regions skipped. Each run of unparsable bytes
+    /// between two valid records (or at the tail) counts as one.
+    pub records_skipped: usize,
+    /// Total number of bytes skipped across all corrupt regions.
+    pub bytes_skipped: u64,
+}
+
 /// Append-only log for crash-safe persistence.
 /// 
 /// Invariants:
@@ -25,14 +207,54 @@ pub enum LogRecord {
 /// - The log file is opened in append mode to prevent accidental overwrites.
 /// 
 /// Record format (binary):
-/// - Record type: 1 byte (0 = Put, 1 = Delete)
+/// - Record type: 1 byte (0 = Put, 1 = Delete, 3 = Typed Put,
+///   4 = Versioned Put, 5 = Blob Put)
 /// - Key length: 4 bytes (u32, little-endian)
 /// - Key: N bytes (where N = key length)
-/// - For Put records only:
+/// - For Typed Put records only:
+///   - Content type length: 4 bytes (u32, little-endian)
+///   - Content type: C bytes (where C = content type length)
+/// - For Versioned Put records only:
+///   - Version: 8 bytes (u64, little-endian)
+/// - For Blob Put records only, in place of a value:
+///   - Blob id: 8 bytes (u64, little-endian)
+/// - For Put, Typed Put, and Versioned Put records only:
 ///   - Value length: 4 bytes (u32, little-endian)
 ///   - Value: M bytes (where M = value length)
+/// 
+/// Batch records (type 2) group Put/Delete records that must be applied
+/// all-or-nothing:
+/// - Record type: 1 byte (2 = Batch)
+/// - Record count: 4 bytes (u32, little-endian)
+/// - Payload length: 4 bytes (u32, little-endian)
+/// - Payload: the grouped records, each in the format above
+/// 
+/// Readers yield a batch's records individually, but only once the whole
+/// batch has been read.
+/// 
+/// Two-phase writes (`write_two_phase`) frame their records as a Pending
+/// record (type 6), laid out like a batch, followed by a Commit marker: a
+/// single byte, 7. Readers apply a pending record only if the marker comes
+/// right after it; one followed by anything else was cut off by a crash
+/// before it committed and is skipped. A pending record at the very end
+/// of the log is left unread by tailing readers, whose next read may find
+/// its marker, and ignored when the log is opened.
+/// 
+/// Experimental records (types in `EXPERIMENTAL_RECORD_TYPES`) can't be
+/// batched and have a fixed framing:
+/// - Record type: 1 byte (0x80 to 0xFF)
+/// - Payload length: 4 bytes (u32, little-endian)
+/// - Payload: P bytes, interpreted only by a registered parser
+/// 
+/// Readers skip experimental records they have no parser for, and
+/// compaction drops them.
 pub struct Log {
     writer: BufWriter<File>,
+    /// Whether each record is flushed as soon as it is appended.
+    /// 
+    /// When false (deferred mode), records stay buffered until `flush` is
+    /// called or the log is dropped.
+    auto_flush: bool,
 }
 
 impl Log {
@@ -47,246 +269,1799 @@ impl Log {
             .open(path)?;
         Ok(Log {
             writer: BufWriter::new(file),
+            auto_flush: true,
         })
     }
 
-    /// Appends a Put record to the log.
-    /// 
-    /// Invariant: The record is written atomically (all bytes are written
-    /// before returning, or an error is returned).
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> std::io::Result<()> {
-        // Write record type
-        self.writer.write_all(&[RECORD_PUT])?;
-        
-        // Write key length and key
-        let key_len = key.len() as u32;
-        self.writer.write_all(&key_len.to_le_bytes())?;
-        self.writer.write_all(key)?;
+    /// Enables or disables flushing after every appended record.
+    /// 
+    /// Auto-flush is on by default. With it off, callers must call `flush`
+    /// to push buffered records to the OS.
+    pub fn set_auto_flush(&mut self, auto_flush: bool) {
+        self.auto_flush = auto_flush;
+    }
+
+    /// Flushes any buffered records to the OS.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flushes buffered records and fsyncs the log file, so that every
+    /// record appended so far survives a power loss.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+
+    /// Returns the size of the log in bytes, including buffered records.
+    /// 
+    /// Reads the file's current length, so appends made through other
+    /// handles are counted too.
+    pub fn size_bytes(&self) -> std::io::Result<u64> {
+        let on_disk = self.writer.get_ref().metadata()?.len();
+        Ok(on_disk + self.writer.buffer().len() as u64)
+    }
+
+    /// Returns the records appended but still buffered in memory.
+    /// 
+    /// Non-empty only with auto-flush off. Appending these bytes to the
+    /// file's contents gives the log as it will be once flushed.
+    pub fn buffered(&self) -> &[u8] {
+        self.writer.buffer()
+    }
+
+    /// Returns the encoded size of a Put record.
+    pub fn put_len(key: &[u8], value: &[u8]) -> u64 {
+        (1 + 4 + key.len() + 4 + value.len()) as u64
+    }
+
+    /// Returns the encoded size of a Typed Put record.
+    pub fn typed_put_len(key: &[u8], value: &[u8], content_type: &[u8]) -> u64 {
+        Self::put_len(key, value) + 4 + content_type.len() as u64
+    }
+
+    /// Returns the encoded size of a Versioned Put record.
+    pub fn versioned_put_len(key: &[u8], value: &[u8]) -> u64 {
+        Self::put_len(key, value) + 8
+    }
+
+    /// Returns the encoded size of a Blob Put record.
+    pub fn blob_put_len(key: &[u8]) -> u64 {
+        (1 + 4 + key.len() + 8) as u64
+    }
+
+    /// Returns the encoded size of a Delete record.
+    pub fn delete_len(key: &[u8]) -> u64 {
+        (1 + 4 + key.len()) as u64
+    }
+
+    /// Returns the encoded size of a Batch record holding `records`.
+    /// 
+    /// Zero for an empty batch, which `write_batch` doesn't write.
+    pub fn batch_len(records: &[LogRecord]) -> u64 {
+        if records.is_empty() {
+            return 0;
+        }
+        let payload: u64 = records
+            .iter()
+            .map(|record| match record {
+                LogRecord::Put { key, value } => Self::put_len(key, value),
+                LogRecord::TypedPut { key, content_type, value } => {
+                    Self::typed_put_len(key, value, content_type)
+                }
+                LogRecord::VersionedPut { key, value, .. } => Self::versioned_put_len(key, value),
+                LogRecord::BlobPut { key, .. } => Self::blob_put_len(key),
+                LogRecord::Delete { key } => Self::delete_len(key),
+            })
+            .sum();
+        1 + 4 + 4 + payload
+    }
+
+    /// Returns the encoded size of a two-phase write of `records`: the
+    /// Pending record and its Commit marker.
+    /// 
+    /// Zero for no records, which `write_two_phase` doesn't write.
+    pub fn two_phase_len(records: &[LogRecord]) -> u64 {
+        match Self::batch_len(records) {
+            0 => 0,
+            len => len + 1,
+        }
+    }
+
+    /// Appends a Put record to the log.
+    /// 
+    /// Invariant: The record is written atomically (all bytes are written
+    /// before returning, or an error is returned).
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(Self::put_len(key, value) as usize);
+        encode_put(key, value, &mut record);
+        self.write_record(&record)
+    }
+
+    /// Appends a Typed Put record, tagging the value with `content_type`.
+    /// 
+    /// Invariant: The record is written atomically (all bytes are written
+    /// before returning, or an error is returned).
+    pub fn put_with_content_type(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        content_type: &[u8],
+    ) -> std::io::Result<()> {
+        let len = Self::typed_put_len(key, value, content_type) as usize;
+        let mut record = Vec::with_capacity(len);
+        encode_typed_put(key, content_type, value, &mut record);
+        self.write_record(&record)
+    }
+
+    /// Appends a Versioned Put record, storing `version` alongside the value.
+    /// 
+    /// Invariant: The record is written atomically (all bytes are written
+    /// before returning, or an error is returned).
+    pub fn put_versioned(&mut self, key: &[u8], value: &[u8], version: u64) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(Self::versioned_put_len(key, value) as usize);
+        encode_versioned_put(key, version, value, &mut record);
+        self.write_record(&record)
+    }
+
+    /// Appends a Blob Put record, referencing the blob file holding the
+    /// value.
+    /// 
+    /// Invariant: The record is written atomically (all bytes are written
+    /// before returning, or an error is returned).
+    pub fn put_blob(&mut self, key: &[u8], blob: u64) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(Self::blob_put_len(key) as usize);
+        encode_blob_put(key, blob, &mut record);
+        self.write_record(&record)
+    }
+
+    /// Appends a Delete record to the log.
+    /// 
+    /// Invariant: The record is written atomically (all bytes are written
+    /// before returning, or an error is returned).
+    pub fn delete(&mut self, key: &[u8]) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(Self::delete_len(key) as usize);
+        encode_delete(key, &mut record);
+        self.write_record(&record)
+    }
+
+    /// Writes one fully encoded entry, flushing it unless deferred.
+    /// 
+    /// Entries are encoded in memory first and handed over in a single
+    /// `write_all`, so the OS sees one contiguous write per record. That
+    /// narrows the window in which a crash can leave a torn record at the
+    /// tail of the log, though it can't rule one out.
+    fn write_record(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(bytes)?;
+        
+        // Flush to ensure data is written to disk (unless deferred)
+        if self.auto_flush {
+            self.writer.flush()?;
+        }
+        
+        Ok(())
+    }
+
+    /// Appends a prebuilt Put, Typed Put, Versioned Put, or Delete record to
+    /// the log.
+    /// 
+    /// Equivalent to calling `put`, `put_with_content_type`,
+    /// `put_versioned`, or `delete` with the record's fields, for
+    /// callers such as import and replication tools that already hold
+    /// `LogRecord` values.
+    pub fn append(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        encode_record(record, &mut buf);
+        self.write_record(&buf)
+    }
+
+    /// Appends an experimental record carrying `payload`.
+    /// 
+    /// Fails with `ErrorKind::InvalidInput` if `record_type` isn't in
+    /// `EXPERIMENTAL_RECORD_TYPES`.
+    pub fn append_experimental(&mut self, record_type: u8, payload: &[u8]) -> std::io::Result<()> {
+        check_experimental(record_type)?;
+        let mut buf = vec![record_type];
+        encode_field(payload, &mut buf);
+        self.write_record(&buf)
+    }
+
+    /// Appends a Batch record grouping `records` so replay applies all of
+    /// them or none.
+    /// 
+    /// The batch is encoded in memory and written with a single `write_all`,
+    /// like every record, so a crash can only leave a torn batch at the tail
+    /// of the log. An empty batch writes nothing.
+    pub fn write_batch(&mut self, records: &[LogRecord]) -> std::io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.write_record(&encode_group(RECORD_BATCH, records))
+    }
+
+    /// Appends `records` as a Pending record, fsyncs the log, then appends
+    /// the Commit marker that makes replay apply them, all or none.
+    /// 
+    /// A crash before the marker is on disk leaves the records pending,
+    /// and readers skip them. Unlike a torn batch, a pending record that
+    /// was fully written but never committed doesn't fail a strict open.
+    /// The marker itself is flushed like any record, so with auto-flush
+    /// off it stays buffered until `flush`. No records writes nothing.
+    pub fn write_two_phase(&mut self, records: &[LogRecord]) -> std::io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&encode_group(RECORD_PENDING, records))?;
+        // The marker mustn't reach the disk before the whole payload has
+        self.sync()?;
+        self.write_record(&[RECORD_COMMIT])
+    }
+
+    /// Truncates the log file at `path` to its first `offset` bytes, then
+    /// fsyncs it.
+    /// 
+    /// `offset` must fall on a record boundary, and the records before it
+    /// must be well-formed; anything else (including an offset inside a
+    /// batch, or past the end of the file) fails with
+    /// `ErrorKind::InvalidInput` and leaves the file untouched. The log must
+    /// not be open for writing elsewhere, or later appends would leave a gap.
+    pub fn truncate_to<P: AsRef<Path>>(path: P, offset: u64) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        let invalid = |message: String| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+        };
+        
+        let Some(end) = usize::try_from(offset).ok().filter(|&end| end <= bytes.len()) else {
+            return Err(invalid(format!(
+                "Offset {} is past the end of the log ({} bytes)",
+                offset,
+                bytes.len()
+            )));
+        };
+        let (_, consumed) = Self::scan_complete(&bytes[..end]);
+        if consumed != end {
+            return Err(invalid(format!(
+                "Offset {} is not on a record boundary (last boundary before it is {})",
+                offset, consumed
+            )));
+        }
+        
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(offset)?;
+        file.sync_all()
+    }
+
+    /// Reads all records from a log file.
+    /// 
+    /// This is used during recovery to rebuild the in-memory index.
+    /// Returns an error if the log file is corrupted or unreadable.
+    /// Experimental records are skipped.
+    pub fn read_all<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<LogRecord>> {
+        Self::read_all_with(path, &RecordRegistry::default())
+    }
+
+    /// Reads all records from a log file, parsing experimental records with
+    /// `registry`.
+    /// 
+    /// Experimental records of unregistered types are skipped. One whose
+    /// registered parser rejects it fails with `ErrorKind::InvalidData`.
+    pub fn read_all_with<P: AsRef<Path>>(
+        path: P,
+        registry: &RecordRegistry,
+    ) -> std::io::Result<Vec<LogRecord>> {
+        let file = BufReader::new(File::open(path)?);
+        LogReader::with_registry(file, registry.clone()).collect()
+    }
+
+    /// Reads all records from any stream holding an encoded log, such as a
+    /// socket, pipe, or decompressor.
+    /// 
+    /// Same format and errors as `read_all`; experimental records are
+    /// skipped. The stream is read to its end, one record at a time, so it
+    /// needn't be seekable. Wrap unbuffered streams in a `BufReader`.
+    pub fn read_all_from_reader<R: Read>(reader: R) -> std::io::Result<Vec<LogRecord>> {
+        Self::records_from_reader(reader).collect()
+    }
+
+    /// Returns an iterator over the records in a stream holding an encoded
+    /// log, reading each only when it is reached.
+    /// 
+    /// The streaming form of `read_all_from_reader`, for streams too long to
+    /// hold in memory or that never end, such as a replication socket.
+    pub fn records_from_reader<R: Read>(reader: R) -> LogReader<R> {
+        LogReader::with_registry(reader, RecordRegistry::default())
+    }
+
+    /// Reads all records from a log file, skipping anything unparsable.
+    /// 
+    /// Whenever a record can't be parsed, the reader advances one byte at a
+    /// time until a well-formed record starts again. Records carry no
+    /// checksums, so "well-formed" only means a known record type whose
+    /// lengths fit in the file; garbage that happens to look like a record
+    /// will be recovered as one. Prefer `read_all` unless salvaging a damaged
+    /// log.
+    /// 
+    /// A batch whose declared length runs past the end of the file is taken
+    /// to be a write torn by a crash, and everything from its start is
+    /// skipped so none of the batch is recovered.
+    pub fn read_all_lenient<P: AsRef<Path>>(
+        path: P,
+    ) -> std::io::Result<(Vec<LogRecord>, RecoveryReport)> {
+        let bytes = std::fs::read(path)?;
+        let (spans, report) = Self::scan_lenient(&bytes);
+        let records = spans.iter().map(|span| span.to_record(&bytes)).collect();
+        Ok((records, report))
+    }
+
+    /// Locates every record in an encoded log without copying it out.
+    /// 
+    /// `bytes` is the full contents of a log file.
+    /// Fails with `ErrorKind::InvalidData` at the first malformed or
+    /// truncated record. A pending record ending the log, whose commit
+    /// marker was never written, is ignored.
+    pub fn scan(bytes: &[u8]) -> std::io::Result<Vec<RecordSpan>> {
+        let (spans, consumed) = Self::scan_complete(bytes);
+        if consumed < bytes.len() && !is_uncommitted(&bytes[consumed..]) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Malformed or truncated record at byte {}", consumed),
+            ));
+        }
+        Ok(spans)
+    }
+
+    /// Returns `pos` moved past the commit marker there, if any.
+    /// 
+    /// A span of a two-phase write ends before its marker, so this gives
+    /// the end of the entry holding the last record of the write.
+    pub(crate) fn skip_commit_marker(bytes: &[u8], pos: usize) -> usize {
+        match bytes.get(pos) {
+            Some(&RECORD_COMMIT) => pos + 1,
+            _ => pos,
+        }
+    }
+
+    /// Locates records up to the first one that is malformed or incomplete.
+    /// 
+    /// Returns the spans and the number of bytes they cover. Anything past
+    /// that point is left unread, which suits tailing a log another writer
+    /// may be partway through appending to.
+    pub fn scan_complete(bytes: &[u8]) -> (Vec<RecordSpan>, usize) {
+        let mut spans = Vec::new();
+        let mut pos = 0;
+        
+        while let Some((entry, len)) = decode_entry(bytes, pos) {
+            spans.extend(entry);
+            pos += len;
+        }
+        
+        (spans, pos)
+    }
+
+    /// Decodes the entry at `pos`: a single record, or every record of a
+    /// batch or committed two-phase write.
+    /// 
+    /// Returns the records' spans and the entry's encoded length, or `None`
+    /// if `bytes` doesn't hold a complete, well-formed entry at `pos`.
+    pub(crate) fn scan_entry(bytes: &[u8], pos: usize) -> Option<(Vec<RecordSpan>, usize)> {
+        decode_entry(bytes, pos)
+    }
+
+    /// Returns true if `bytes` holds exactly one two-phase write whose
+    /// commit marker was never written, which scans ignore.
+    pub(crate) fn is_uncommitted(bytes: &[u8]) -> bool {
+        is_uncommitted(bytes)
+    }
+
+    /// Returns true if `bytes` starts with an entry cut off by their end,
+    /// such as one a writer is partway through appending.
+    /// 
+    /// Bytes no amount of appending could turn into a valid entry, like an
+    /// unknown record type or a complete batch whose records don't parse,
+    /// return false, as do complete entries.
+    pub fn is_partial_entry(bytes: &[u8]) -> bool {
+        match bytes.first() {
+            None => false,
+            Some(&(RECORD_BATCH | RECORD_PENDING)) => is_torn_batch(bytes),
+            Some(record_type) => {
+                let known = record_format(*record_type).is_some()
+                    || EXPERIMENTAL_RECORD_TYPES.contains(record_type);
+                // A record of a known type fails to decode only when one of
+                // its fields runs past the end
+                known && decode_entry(bytes, 0).is_none()
+            }
+        }
+    }
+
+    /// Locates every record in an encoded log, skipping anything unparsable.
+    /// 
+    /// Corrupt regions are skipped as described on `read_all_lenient`.
+    pub fn scan_lenient(bytes: &[u8]) -> (Vec<RecordSpan>, RecoveryReport) {
+        Self::scan_lenient_from(bytes, 0)
+    }
+
+    /// Like `scan_lenient`, but starts at offset `pos` of `bytes`, with the
+    /// spans still relative to the start of `bytes`.
+    pub(crate) fn scan_lenient_from(
+        bytes: &[u8],
+        mut pos: usize,
+    ) -> (Vec<RecordSpan>, RecoveryReport) {
+        let mut spans = Vec::new();
+        let mut report = RecoveryReport::default();
+        let mut in_corrupt_region = false;
+        
+        while pos < bytes.len() {
+            // A batch cut off by a crash, or a pending record that never
+            // committed; resyncing inside it would recover part of the
+            // batch, so drop the whole tail. While resyncing, the type byte
+            // is more likely garbage, so it is skipped like any other.
+            let torn = is_torn_batch(&bytes[pos..]) || is_uncommitted(&bytes[pos..]);
+            if torn && !in_corrupt_region {
+                report.records_skipped += 1;
+                report.bytes_skipped += (bytes.len() - pos) as u64;
+                break;
+            }
+            
+            // An experimental record's length is unchecked, so resyncing on
+            // one could skip over good records, and any stray byte could
+            // pass for a commit marker; only accept them in sequence
+            let resyncing_on_unchecked = in_corrupt_region
+                && (EXPERIMENTAL_RECORD_TYPES.contains(&bytes[pos]) || bytes[pos] == RECORD_COMMIT);
+            match decode_entry(bytes, pos).filter(|_| !resyncing_on_unchecked) {
+                Some((entry, len)) => {
+                    spans.extend(entry);
+                    pos += len;
+                    in_corrupt_region = false;
+                }
+                None => {
+                    if !in_corrupt_region {
+                        report.records_skipped += 1;
+                        in_corrupt_region = true;
+                    }
+                    report.bytes_skipped += 1;
+                    pos += 1;
+                }
+            }
+        }
+        
+        report.records_recovered = spans.len();
+        (spans, report)
+    }
+}
+
+impl LogRecord {
+    /// Returns the key the record writes.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            LogRecord::Put { key, .. } => key,
+            LogRecord::TypedPut { key, .. } => key,
+            LogRecord::VersionedPut { key, .. } => key,
+            LogRecord::BlobPut { key, .. } => key,
+            LogRecord::Delete { key } => key,
+        }
+    }
+
+    /// Writes the record to `writer` in the log's Put/Delete record format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        encode_record(self, &mut buf);
+        writer.write_all(&buf)
+    }
+
+    /// Reads one Put or Delete record written by `write_to`.
+    /// 
+    /// Returns `None` at a clean end of input. Experimental records are
+    /// skipped; batch records aren't accepted.
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Option<Self>> {
+        loop {
+            let mut record_type_buf = [0u8; 1];
+            match reader.read_exact(&mut record_type_buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            
+            let record_type = record_type_buf[0];
+            if EXPERIMENTAL_RECORD_TYPES.contains(&record_type) {
+                read_field(reader)?;
+                continue;
+            }
+            return read_record_body(reader, record_type).map(Some);
+        }
+    }
+}
+
+/// Iterator over the records of an encoded log read from a stream, from
+/// `Log::records_from_reader`.
+/// 
+/// Records of a batch are yielded one by one. Fails with
+/// `ErrorKind::UnexpectedEof` if the stream ends partway through a record,
+/// or `ErrorKind::InvalidData` on a malformed one; nothing more is yielded
+/// after an error.
+pub struct LogReader<R> {
+    reader: R,
+    /// Parsers for experimental records.
+    registry: RecordRegistry,
+    /// Records of the last entry read that haven't been yielded yet.
+    pending: std::vec::IntoIter<LogRecord>,
+    /// Type byte of the next entry, read while looking for the commit
+    /// marker of a pending record.
+    peeked: Option<u8>,
+    /// Whether the stream has ended or failed.
+    done: bool,
+}
+
+impl<R: Read> LogReader<R> {
+    /// Reads records from `reader`, parsing experimental records with
+    /// `registry` as `Log::read_all_with` does.
+    pub fn with_registry(reader: R, registry: RecordRegistry) -> Self {
+        LogReader {
+            reader,
+            registry,
+            pending: Vec::new().into_iter(),
+            peeked: None,
+            done: false,
+        }
+    }
+
+    /// Reads the next entry of the log: a batch, a single record, or an
+    /// experimental record (no records, if its type isn't registered).
+    /// 
+    /// A pending record yields its records only if its commit marker
+    /// follows; a stray marker yields none. Returns `None` at a clean end
+    /// of the stream.
+    fn read_entry(&mut self) -> std::io::Result<Option<Vec<LogRecord>>> {
+        let record_type = match self.peeked.take() {
+            Some(record_type) => record_type,
+            None => match self.read_type()? {
+                Some(record_type) => record_type,
+                None => return Ok(None),
+            },
+        };
+        
+        if record_type == RECORD_COMMIT {
+            return Ok(Some(Vec::new()));
+        }
+        if record_type == RECORD_BATCH || record_type == RECORD_PENDING {
+            // Read record count and payload length
+            let mut header = [0u8; 8];
+            self.reader.read_exact(&mut header)?;
+            let count = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let payload_len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+            
+            // Read and decode the grouped records
+            let mut payload = vec![0u8; payload_len];
+            self.reader.read_exact(&mut payload)?;
+            let batch = decode_batch_payload(&payload, 0, count).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed batch record")
+            })?;
+            if record_type == RECORD_PENDING {
+                // Uncommitted, unless the marker comes next
+                match self.read_type()? {
+                    Some(RECORD_COMMIT) => {}
+                    next => {
+                        self.peeked = next;
+                        return Ok(Some(Vec::new()));
+                    }
+                }
+            }
+            return Ok(Some(batch.iter().map(|span| span.to_record(&payload)).collect()));
+        }
+        
+        if EXPERIMENTAL_RECORD_TYPES.contains(&record_type) {
+            let payload = read_field(&mut self.reader)?;
+            return Ok(Some(self.registry.parse(record_type, &payload)?.into_iter().collect()));
+        }
+        
+        read_record_body(&mut self.reader, record_type).map(|record| Some(vec![record]))
+    }
+
+    /// Reads a record type byte, or returns `None` at the end of the stream.
+    fn read_type(&mut self) -> std::io::Result<Option<u8>> {
+        let mut record_type_buf = [0u8; 1];
+        match self.reader.read_exact(&mut record_type_buf) {
+            Ok(()) => Ok(Some(record_type_buf[0])),
+            // End of the stream between records, this is normal
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = std::io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.next() {
+                return Some(Ok(record));
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_entry() {
+                Ok(Some(records)) => self.pending = records.into_iter(),
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Fails with `ErrorKind::InvalidInput` unless `record_type` is reserved for
+/// experimental records.
+fn check_experimental(record_type: u8) -> std::io::Result<()> {
+    if !EXPERIMENTAL_RECORD_TYPES.contains(&record_type) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Record type {} is not in the experimental range", record_type),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the rest of a Put, Typed Put, Versioned Put, Blob Put, or Delete
+/// record whose type byte was `record_type`.
+/// 
+/// The record's fields are read as its `RecordFormat` lists them, then
+/// decoded by the same code as `Log::scan`.
+fn read_record_body<R: Read>(reader: &mut R, record_type: u8) -> std::io::Result<LogRecord> {
+    let format = record_format(record_type).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unknown record type: {}", record_type),
+        )
+    })?;
+    
+    let mut buf = vec![record_type];
+    for field in format.fields {
+        field.read(reader, &mut buf)?;
+    }
+    
+    let (span, _) = decode_span(&buf, 0).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed record")
+    })?;
+    Ok(span.to_record(&buf))
+}
+
+/// Reads a u32-length-prefixed field.
+fn read_field<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf)?;
+    let mut field = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+    reader.read_exact(&mut field)?;
+    Ok(field)
+}
+
+/// Decodes the entry at `pos`: a single record, or every record of a batch.
+/// 
+/// Returns the records' spans and the entry's encoded length, or `None` if
+/// `bytes` doesn't hold a complete, well-formed entry at `pos`.
+/// 
+/// An experimental record decodes to no spans, so scans skip it, as does a
+/// pending record followed by anything but its commit marker, or a commit
+/// marker on its own. A pending record ending `bytes` isn't complete, as
+/// its marker may still be written.
+fn decode_entry(bytes: &[u8], pos: usize) -> Option<(Vec<RecordSpan>, usize)> {
+    let record_type = *bytes.get(pos)?;
+    if EXPERIMENTAL_RECORD_TYPES.contains(&record_type) {
+        let payload = decode_field(bytes, pos + 1)?;
+        return Some((Vec::new(), payload.end - pos));
+    }
+    if record_type == RECORD_COMMIT {
+        return Some((Vec::new(), 1));
+    }
+    if record_type != RECORD_BATCH && record_type != RECORD_PENDING {
+        return decode_span(bytes, pos).map(|(span, len)| (vec![span], len));
+    }
+    let (count, payload_len) = decode_batch_header(&bytes[pos..])?;
+    let payload_end = (pos + 9).checked_add(payload_len)?;
+    let payload = bytes.get(..payload_end)?;
+    let spans = decode_batch_payload(payload, pos + 9, count)?;
+    if record_type == RECORD_PENDING {
+        return match *bytes.get(payload_end)? {
+            RECORD_COMMIT => Some((spans, 9 + payload_len + 1)),
+            // Abandoned by a crash before it committed
+            _ => Some((Vec::new(), 9 + payload_len)),
+        };
+    }
+    Some((spans, 9 + payload_len))
+}
+
+/// Decodes a batch header, returning the record count and payload length.
+fn decode_batch_header(bytes: &[u8]) -> Option<(usize, usize)> {
+    let count: [u8; 4] = bytes.get(1..5)?.try_into().ok()?;
+    let payload_len: [u8; 4] = bytes.get(5..9)?.try_into().ok()?;
+    Some((
+        u32::from_le_bytes(count) as usize,
+        u32::from_le_bytes(payload_len) as usize,
+    ))
+}
+
+/// Returns true if `bytes` starts with a batch or pending record that
+/// extends past its end.
+fn is_torn_batch(bytes: &[u8]) -> bool {
+    if !matches!(bytes.first(), Some(&(RECORD_BATCH | RECORD_PENDING))) {
+        return false;
+    }
+    match decode_batch_header(bytes) {
+        Some((_, payload_len)) => 9 + payload_len > bytes.len(),
+        None => true,
+    }
+}
+
+/// Returns true if `bytes` holds exactly one pending record, whose commit
+/// marker a crash kept from being written.
+fn is_uncommitted(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&RECORD_PENDING)
+        && decode_batch_header(bytes).is_some_and(|(_, payload_len)| 9 + payload_len == bytes.len())
+}
+
+/// Decodes a batch payload running from `start` to the end of `bytes`, which
+/// must hold exactly `count` Put/Delete records.
+fn decode_batch_payload(bytes: &[u8], start: usize, count: usize) -> Option<Vec<RecordSpan>> {
+    let mut spans = Vec::new();
+    let mut pos = start;
+    while pos < bytes.len() {
+        let (span, len) = decode_span(bytes, pos)?;
+        spans.push(span);
+        pos += len;
+    }
+    (spans.len() == count).then_some(spans)
+}
+
+/// Encodes `records` as one Batch or Pending record, per `record_type`.
+fn encode_group(record_type: u8, records: &[LogRecord]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for record in records {
+        encode_record(record, &mut payload);
+    }
+    
+    let mut group = Vec::with_capacity(1 + 4 + 4 + payload.len());
+    group.push(record_type);
+    group.extend_from_slice(&(records.len() as u32).to_le_bytes());
+    group.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    group.extend_from_slice(&payload);
+    group
+}
+
+/// Appends the encoding of a Put, Typed Put, Versioned Put, Blob Put, or
+/// Delete record to `buf`.
+fn encode_record(record: &LogRecord, buf: &mut Vec<u8>) {
+    match record {
+        LogRecord::Put { key, value } => encode_put(key, value, buf),
+        LogRecord::TypedPut { key, content_type, value } => {
+            encode_typed_put(key, content_type, value, buf)
+        }
+        LogRecord::VersionedPut { key, version, value } => {
+            encode_versioned_put(key, *version, value, buf)
+        }
+        LogRecord::BlobPut { key, blob } => encode_blob_put(key, *blob, buf),
+        LogRecord::Delete { key } => encode_delete(key, buf),
+    }
+}
+
+/// Appends the encoding of a Put record to `buf`.
+fn encode_put(key: &[u8], value: &[u8], buf: &mut Vec<u8>) {
+    buf.push(RECORD_PUT);
+    encode_field(key, buf);
+    encode_field(value, buf);
+}
+
+/// Appends the encoding of a Typed Put record to `buf`.
+fn encode_typed_put(key: &[u8], content_type: &[u8], value: &[u8], buf: &mut Vec<u8>) {
+    buf.push(RECORD_TYPED_PUT);
+    encode_field(key, buf);
+    encode_field(content_type, buf);
+    encode_field(value, buf);
+}
+
+/// Appends the encoding of a Versioned Put record to `buf`.
+fn encode_versioned_put(key: &[u8], version: u64, value: &[u8], buf: &mut Vec<u8>) {
+    buf.push(RECORD_VERSIONED_PUT);
+    encode_field(key, buf);
+    buf.extend_from_slice(&version.to_le_bytes());
+    encode_field(value, buf);
+}
+
+/// Appends the encoding of a Blob Put record to `buf`.
+fn encode_blob_put(key: &[u8], blob: u64, buf: &mut Vec<u8>) {
+    buf.push(RECORD_BLOB_PUT);
+    encode_field(key, buf);
+    buf.extend_from_slice(&blob.to_le_bytes());
+}
+
+/// Appends the encoding of a Delete record to `buf`.
+fn encode_delete(key: &[u8], buf: &mut Vec<u8>) {
+    buf.push(RECORD_DELETE);
+    encode_field(key, buf);
+}
+
+/// Appends a u32 length prefix followed by `field` to `buf`.
+fn encode_field(field: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    buf.extend_from_slice(field);
+}
+
+/// Layout of a built-in record type: the fields following its type byte.
+struct RecordFormat {
+    record_type: u8,
+    /// Fields in encoding order. The first is always the key.
+    fields: &'static [Field],
+    /// Builds the record's span from `bytes` and the byte ranges of its
+    /// decoded fields.
+    to_span: fn(&[u8], &[Range<usize>]) -> RecordSpan,
+}
+
+/// Encoding of one field of a record.
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    /// A u32 length prefix followed by that many bytes.
+    Bytes,
+    /// An 8-byte little-endian u64.
+    U64,
+}
+
+/// Most fields any `RecordFormat` has.
+const MAX_FIELDS: usize = 3;
+
+/// Dispatch table for decoding the built-in record types, except batches,
+/// which nest other records.
+/// 
+/// Adding a record type means adding its row here, an encoder, and a
+/// `LogRecord` variant.
+const RECORD_FORMATS: &[RecordFormat] = &[
+    RecordFormat {
+        record_type: RECORD_PUT,
+        fields: &[Field::Bytes, Field::Bytes],
+        to_span: |_, fields| RecordSpan::Put {
+            key: fields[0].clone(),
+            content_type: None,
+            version: None,
+            value: fields[1].clone(),
+        },
+    },
+    RecordFormat {
+        record_type: RECORD_DELETE,
+        fields: &[Field::Bytes],
+        to_span: |_, fields| RecordSpan::Delete { key: fields[0].clone() },
+    },
+    RecordFormat {
+        record_type: RECORD_TYPED_PUT,
+        fields: &[Field::Bytes, Field::Bytes, Field::Bytes],
+        to_span: |_, fields| RecordSpan::Put {
+            key: fields[0].clone(),
+            content_type: Some(fields[1].clone()),
+            version: None,
+            value: fields[2].clone(),
+        },
+    },
+    RecordFormat {
+        record_type: RECORD_VERSIONED_PUT,
+        fields: &[Field::Bytes, Field::U64, Field::Bytes],
+        to_span: |bytes, fields| RecordSpan::Put {
+            key: fields[0].clone(),
+            content_type: None,
+            version: Some(decode_u64(bytes, &fields[1])),
+            value: fields[2].clone(),
+        },
+    },
+    RecordFormat {
+        record_type: RECORD_BLOB_PUT,
+        fields: &[Field::Bytes, Field::U64],
+        to_span: |bytes, fields| RecordSpan::BlobPut {
+            key: fields[0].clone(),
+            blob: decode_u64(bytes, &fields[1]),
+        },
+    },
+];
+
+/// Looks up the format of a built-in record type.
+fn record_format(record_type: u8) -> Option<&'static RecordFormat> {
+    RECORD_FORMATS.iter().find(|format| format.record_type == record_type)
+}
+
+impl Field {
+    /// Decodes the field at `pos`, returning the byte range of its contents.
+    fn decode(self, bytes: &[u8], pos: usize) -> Option<Range<usize>> {
+        match self {
+            Field::Bytes => decode_field(bytes, pos),
+            Field::U64 => {
+                let end = pos.checked_add(8)?;
+                (end <= bytes.len()).then_some(pos..end)
+            }
+        }
+    }
+
+    /// Reads the field from `reader`, appending its encoding to `buf`.
+    fn read<R: Read>(self, reader: &mut R, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        match self {
+            Field::Bytes => encode_field(&read_field(reader)?, buf),
+            Field::U64 => {
+                let mut value = [0u8; 8];
+                reader.read_exact(&mut value)?;
+                buf.extend_from_slice(&value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the u64 field whose bytes are `range`.
+fn decode_u64(bytes: &[u8], range: &Range<usize>) -> u64 {
+    u64::from_le_bytes(bytes[range.clone()].try_into().unwrap())
+}
+
+/// Decodes the Put, Typed Put, Versioned Put, Blob Put, or Delete record at
+/// `pos`.
+/// 
+/// Returns the record's span and its encoded length, or `None` if `bytes`
+/// doesn't hold a complete record of a known type at `pos`.
+fn decode_span(bytes: &[u8], pos: usize) -> Option<(RecordSpan, usize)> {
+    let format = record_format(*bytes.get(pos)?)?;
+    let mut fields: [Range<usize>; MAX_FIELDS] = Default::default();
+    let mut end = pos + 1;
+    
+    for (field, range) in format.fields.iter().zip(fields.iter_mut()) {
+        *range = field.decode(bytes, end)?;
+        end = range.end;
+    }
+    
+    let span = (format.to_span)(bytes, &fields[..format.fields.len()]);
+    Some((span, end - pos))
+}
+
+/// Decodes the u32-length-prefixed field at `pos`, returning its byte range.
+fn decode_field(bytes: &[u8], pos: usize) -> Option<Range<usize>> {
+    let len_bytes: [u8; 4] = bytes.get(pos..pos.checked_add(4)?)?.try_into().ok()?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    let start = pos + 4;
+    let end = start.checked_add(len)?;
+    if end > bytes.len() {
+        return None;
+    }
+    Some(start..end)
+}
+
+impl Drop for Log {
+    /// Attempts a final flush of buffered records.
+    /// 
+    /// Errors can't be reported from `drop`, so callers that care should call
+    /// `flush` explicitly before dropping the log.
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+// The test helpers on `LogRecord` stay at the end of the file
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_put_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let mut log = Log::open(path).unwrap();
+        log.put(b"key1", b"value1").unwrap();
+        
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0],
+            LogRecord::Put {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn test_delete_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let mut log = Log::open(path).unwrap();
+        log.delete(b"key1").unwrap();
+        
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0],
+            LogRecord::Delete {
+                key: b"key1".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn test_multiple_records() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let mut log = Log::open(path).unwrap();
+        log.put(b"key1", b"value1").unwrap();
+        log.put(b"key2", b"value2").unwrap();
+        log.delete(b"key1").unwrap();
+        log.put(b"key3", b"value3").unwrap();
+        
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            records[0],
+            LogRecord::Put {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec()
+            }
+        );
+        assert_eq!(
+            records[1],
+            LogRecord::Put {
+                key: b"key2".to_vec(),
+                value: b"value2".to_vec()
+            }
+        );
+        assert_eq!(
+            records[2],
+            LogRecord::Delete {
+                key: b"key1".to_vec()
+            }
+        );
+        assert_eq!(
+            records[3],
+            LogRecord::Put {
+                key: b"key3".to_vec(),
+                value: b"value3".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_key_and_value() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let mut log = Log::open(path).unwrap();
+        log.put(b"", b"").unwrap();
+        log.put(b"key", b"").unwrap();
+        log.put(b"", b"value").unwrap();
+        
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records[0],
+            LogRecord::Put {
+                key: vec![],
+                value: vec![]
+            }
+        );
+        assert_eq!(
+            records[1],
+            LogRecord::Put {
+                key: b"key".to_vec(),
+                value: vec![]
+            }
+        );
+        assert_eq!(
+            records[2],
+            LogRecord::Put {
+                key: vec![],
+                value: b"value".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn test_large_key_and_value() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let large_key = vec![0u8; 10000];
+        let large_value = vec![1u8; 50000];
+        
+        let mut log = Log::open(path).unwrap();
+        log.put(&large_key, &large_value).unwrap();
+        
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key().len(), 10000);
+        assert_eq!(records[0].value().unwrap().len(), 50000);
+    }
+
+    #[test]
+    fn test_deferred_flush() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let mut log = Log::open(path).unwrap();
+        log.set_auto_flush(false);
+        log.put(b"key1", b"value1").unwrap();
+        
+        // Still buffered
+        assert!(Log::read_all(path).unwrap().is_empty());
+        
+        log.flush().unwrap();
+        assert_eq!(Log::read_all(path).unwrap().len(), 1);
+        
+        log.delete(b"key1").unwrap();
+        drop(log);
+        assert_eq!(Log::read_all(path).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_read_all_lenient_skips_corrupt_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"key1", b"value1").unwrap();
+        }
+        {
+            // Garbage sandwiched between two valid records
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(&[0xFF, 0xEE, 0xDD, 0xCC]).unwrap();
+        }
+        {
+            let mut log = Log::open(path).unwrap();
+            log.delete(b"key0").unwrap();
+            log.put(b"key2", b"value2").unwrap();
+        }
+        
+        assert!(Log::read_all(path).is_err());
+        
+        let (records, report) = Log::read_all_lenient(path).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                LogRecord::Put {
+                    key: b"key1".to_vec(),
+                    value: b"value1".to_vec()
+                },
+                LogRecord::Delete {
+                    key: b"key0".to_vec()
+                },
+                LogRecord::Put {
+                    key: b"key2".to_vec(),
+                    value: b"value2".to_vec()
+                },
+            ]
+        );
+        assert_eq!(
+            report,
+            RecoveryReport {
+                records_recovered: 3,
+                records_skipped: 1,
+                bytes_skipped: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_all_lenient_batch_type_in_garbage() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"a", b"1").unwrap();
+        }
+        {
+            // A stray batch or pending type byte inside the garbage mustn't
+            // be taken for a torn batch at the tail
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(&[0xEE, RECORD_BATCH, 0xEE, RECORD_PENDING, 0xEE]).unwrap();
+        }
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"b", b"2").unwrap();
+            log.put(b"c", b"3").unwrap();
+        }
+        
+        let (records, report) = Log::read_all_lenient(path).unwrap();
+        let keys: Vec<_> = records.iter().map(LogRecord::key).collect();
+        assert_eq!(keys, vec![b"a", b"b", b"c"]);
+        assert_eq!(
+            report,
+            RecoveryReport {
+                records_recovered: 3,
+                records_skipped: 1,
+                bytes_skipped: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_all_lenient_truncated_tail() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"key1", b"value1").unwrap();
+            log.put(b"key2", b"value2").unwrap();
+        }
+        let len = std::fs::metadata(path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        
+        let (records, report) = Log::read_all_lenient(path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(report.records_skipped, 1);
+        assert_eq!(report.bytes_skipped, 1 + 4 + 4 + 4 + 6 - 3);
+    }
+
+    #[test]
+    fn test_append_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let records = vec![
+            LogRecord::Put {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+            },
+            LogRecord::Delete {
+                key: b"key1".to_vec(),
+            },
+        ];
+        
+        let mut log = Log::open(path).unwrap();
+        for record in &records {
+            log.append(record).unwrap();
+        }
+        log.put(b"key2", b"value2").unwrap();
+        
+        let read = Log::read_all(path).unwrap();
+        assert_eq!(read[..2], records[..]);
+        
+        // Byte-for-byte the same as the dedicated methods
+        let other = NamedTempFile::new().unwrap();
+        let mut log = Log::open(other.path()).unwrap();
+        log.put(b"key1", b"value1").unwrap();
+        log.delete(b"key1").unwrap();
+        log.put(b"key2", b"value2").unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), std::fs::read(other.path()).unwrap());
+    }
+
+    #[test]
+    fn test_record_encoding() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
         
-        // Write value length and value
-        let value_len = value.len() as u32;
-        self.writer.write_all(&value_len.to_le_bytes())?;
-        self.writer.write_all(value)?;
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"ab", b"xyz").unwrap();
+            log.delete(b"ab").unwrap();
+            log.put_with_content_type(b"k", b"v", b"t").unwrap();
+        }
         
-        // Flush to ensure data is written to disk
-        self.writer.flush()?;
+        // Byte-for-byte the format documented on `Log`
+        let expected: &[u8] = &[
+            0, 2, 0, 0, 0, b'a', b'b', 3, 0, 0, 0, b'x', b'y', b'z',
+            1, 2, 0, 0, 0, b'a', b'b',
+            3, 1, 0, 0, 0, b'k', 1, 0, 0, 0, b't', 1, 0, 0, 0, b'v',
+        ];
+        assert_eq!(std::fs::read(path).unwrap(), expected);
         
-        Ok(())
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                LogRecord::Put { key: b"ab".to_vec(), value: b"xyz".to_vec() },
+                LogRecord::Delete { key: b"ab".to_vec() },
+                LogRecord::TypedPut {
+                    key: b"k".to_vec(),
+                    content_type: b"t".to_vec(),
+                    value: b"v".to_vec(),
+                },
+            ]
+        );
     }
 
-    /// Appends a Delete record to the log.
-    /// 
-    /// Invariant: The record is written atomically (all bytes are written
-    /// before returning, or an error is returned).
-    pub fn delete(&mut self, key: &[u8]) -> std::io::Result<()> {
-        // Write record type
-        self.writer.write_all(&[RECORD_DELETE])?;
+    #[test]
+    fn test_truncate_to() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
         
-        // Write key length and key
-        let key_len = key.len() as u32;
-        self.writer.write_all(&key_len.to_le_bytes())?;
-        self.writer.write_all(key)?;
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"key1", b"value1").unwrap();
+            log.write_batch(&[
+                LogRecord::Put {
+                    key: b"key2".to_vec(),
+                    value: b"value2".to_vec(),
+                },
+                LogRecord::Delete {
+                    key: b"key1".to_vec(),
+                },
+            ])
+            .unwrap();
+        }
+        let original = std::fs::read(path).unwrap();
+        let first_len = Log::put_len(b"key1", b"value1");
         
-        // Flush to ensure data is written to disk
-        self.writer.flush()?;
+        // Mid-record, mid-batch, and past-the-end offsets are refused
+        for offset in [3, first_len + 12, original.len() as u64 + 1] {
+            let err = Log::truncate_to(path, offset).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(std::fs::read(path).unwrap(), original);
+        }
         
-        Ok(())
+        // Truncating at the end is a no-op
+        Log::truncate_to(path, original.len() as u64).unwrap();
+        assert_eq!(Log::read_all(path).unwrap().len(), 3);
+        
+        Log::truncate_to(path, first_len).unwrap();
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key(), b"key1");
+        assert_eq!(records[0].value(), Some(&b"value1"[..]));
+        
+        Log::truncate_to(path, 0).unwrap();
+        assert!(Log::read_all(path).unwrap().is_empty());
     }
 
-    /// Reads all records from a log file.
-    /// 
-    /// This is used during recovery to rebuild the in-memory index.
-    /// Returns an error if the log file is corrupted or unreadable.
-    pub fn read_all<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<LogRecord>> {
-        let mut file = File::open(path)?;
-        let mut records = Vec::new();
+    #[test]
+    fn test_typed_put_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
         
-        loop {
-            // Try to read record type
-            let mut record_type_buf = [0u8; 1];
-            match file.read_exact(&mut record_type_buf) {
-                Ok(()) => {}
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    // End of file reached, this is normal
-                    break;
-                }
-                Err(e) => return Err(e),
-            }
-            
-            let record_type = record_type_buf[0];
-            
-            // Read key length
-            let mut key_len_buf = [0u8; 4];
-            file.read_exact(&mut key_len_buf)?;
-            let key_len = u32::from_le_bytes(key_len_buf) as usize;
-            
-            // Read key
-            let mut key = vec![0u8; key_len];
-            file.read_exact(&mut key)?;
-            
-            match record_type {
-                RECORD_PUT => {
-                    // Read value length
-                    let mut value_len_buf = [0u8; 4];
-                    file.read_exact(&mut value_len_buf)?;
-                    let value_len = u32::from_le_bytes(value_len_buf) as usize;
-                    
-                    // Read value
-                    let mut value = vec![0u8; value_len];
-                    file.read_exact(&mut value)?;
-                    
-                    records.push(LogRecord::Put { key, value });
-                }
-                RECORD_DELETE => {
-                    records.push(LogRecord::Delete { key });
-                }
-                _ => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Unknown record type: {}", record_type),
-                    ));
-                }
-            }
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put_with_content_type(b"key1", &[0, 255], b"application/octet-stream")
+                .unwrap();
+            log.put(b"key2", b"{}").unwrap();
         }
         
-        Ok(records)
+        let typed = LogRecord::TypedPut {
+            key: b"key1".to_vec(),
+            content_type: b"application/octet-stream".to_vec(),
+            value: vec![0, 255],
+        };
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records[0], typed);
+        assert_eq!(records[1].value(), Some(&b"{}"[..]));
+        
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(
+            Log::typed_put_len(b"key1", &[0, 255], b"application/octet-stream"),
+            bytes.len() as u64 - Log::put_len(b"key2", b"{}")
+        );
+        let spans = Log::scan(&bytes).unwrap();
+        let RecordSpan::Put { content_type: Some(content_type), value, .. } = &spans[0] else {
+            panic!("expected a typed put, got {:?}", spans[0]);
+        };
+        assert_eq!(&bytes[content_type.clone()], b"application/octet-stream");
+        assert_eq!(&bytes[value.clone()], &[0, 255]);
+        assert_eq!(spans[0].to_record(&bytes), typed);
+        
+        // Prebuilt and framed records use the same encoding
+        let mut encoded = Vec::new();
+        typed.write_to(&mut encoded).unwrap();
+        assert_eq!(encoded[..], bytes[..encoded.len()]);
+        assert_eq!(LogRecord::read_from(&mut &encoded[..]).unwrap(), Some(typed));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+    #[test]
+    fn test_read_all_from_reader() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let put = |key: &[u8], value: &[u8]| LogRecord::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        let expected = vec![
+            put(b"a", b"1"),
+            put(b"b", b"2"),
+            put(b"c", b"3"),
+            LogRecord::Delete { key: b"a".to_vec() },
+        ];
+        {
+            let mut log = Log::open(temp_file.path()).unwrap();
+            log.append(&expected[0]).unwrap();
+            log.write_batch(&expected[1..3]).unwrap();
+            log.append_experimental(0x80, b"skipped").unwrap();
+            log.append(&expected[3]).unwrap();
+        }
+        let bytes = std::fs::read(temp_file.path()).unwrap();
+        
+        let from_cursor = Log::read_all_from_reader(std::io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(from_cursor, expected);
+        
+        // A pipe can't seek; the writer feeds it a few bytes at a time
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let chunks: Vec<Vec<u8>> = bytes.chunks(3).map(<[u8]>::to_vec).collect();
+        let feeder = std::thread::spawn(move || {
+            for chunk in chunks {
+                writer.write_all(&chunk).unwrap();
+            }
+        });
+        let mut records = Log::records_from_reader(BufReader::new(reader));
+        assert_eq!(records.next().unwrap().unwrap(), expected[0]);
+        let rest: Vec<LogRecord> = records.map(Result::unwrap).collect();
+        assert_eq!(rest, expected[1..]);
+        feeder.join().unwrap();
+        
+        // A stream cut off mid-record fails, then ends
+        let mut records = Log::records_from_reader(&bytes[..bytes.len() - 1]);
+        assert_eq!(records.by_ref().take(3).count(), 3);
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(records.next().is_none());
+    }
 
     #[test]
-    fn test_put_record() {
+    fn test_versioned_put_record() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path();
         
-        let mut log = Log::open(path).unwrap();
-        log.put(b"key1", b"value1").unwrap();
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put_versioned(b"key1", b"{}", 7).unwrap();
+            log.delete(b"key2").unwrap();
+        }
         
+        let versioned = LogRecord::VersionedPut {
+            key: b"key1".to_vec(),
+            version: 7,
+            value: b"{}".to_vec(),
+        };
         let records = Log::read_all(path).unwrap();
-        assert_eq!(records.len(), 1);
+        assert_eq!(records[0], versioned);
+        
+        let bytes = std::fs::read(path).unwrap();
         assert_eq!(
-            records[0],
-            LogRecord::Put {
-                key: b"key1".to_vec(),
-                value: b"value1".to_vec()
-            }
+            Log::versioned_put_len(b"key1", b"{}"),
+            bytes.len() as u64 - Log::delete_len(b"key2")
         );
+        let spans = Log::scan(&bytes).unwrap();
+        let RecordSpan::Put { version: Some(7), value, .. } = &spans[0] else {
+            panic!("expected a versioned put, got {:?}", spans[0]);
+        };
+        assert_eq!(&bytes[value.clone()], b"{}");
+        assert_eq!(spans[0].to_record(&bytes), versioned);
+        
+        let mut encoded = Vec::new();
+        versioned.write_to(&mut encoded).unwrap();
+        assert_eq!(encoded[..], bytes[..encoded.len()]);
+        assert_eq!(LogRecord::read_from(&mut &encoded[..]).unwrap(), Some(versioned));
     }
 
     #[test]
-    fn test_delete_record() {
+    fn test_blob_put_record() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path();
         
-        let mut log = Log::open(path).unwrap();
-        log.delete(b"key1").unwrap();
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put_blob(b"key1", 42).unwrap();
+            log.delete(b"key2").unwrap();
+        }
         
+        let blob_put = LogRecord::BlobPut {
+            key: b"key1".to_vec(),
+            blob: 42,
+        };
         let records = Log::read_all(path).unwrap();
-        assert_eq!(records.len(), 1);
-        assert_eq!(
-            records[0],
-            LogRecord::Delete {
-                key: b"key1".to_vec()
-            }
-        );
+        assert_eq!(records[0], blob_put);
+        
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(Log::blob_put_len(b"key1"), bytes.len() as u64 - Log::delete_len(b"key2"));
+        let spans = Log::scan(&bytes).unwrap();
+        assert_eq!(spans[0], RecordSpan::BlobPut { key: 5..9, blob: 42 });
+        assert_eq!(spans[0].end() as u64, Log::blob_put_len(b"key1"));
+        assert_eq!(spans[0].to_record(&bytes), blob_put);
+        
+        let mut encoded = Vec::new();
+        blob_put.write_to(&mut encoded).unwrap();
+        assert_eq!(encoded[..], bytes[..encoded.len()]);
+        assert_eq!(LogRecord::read_from(&mut &encoded[..]).unwrap(), Some(blob_put));
     }
 
     #[test]
-    fn test_multiple_records() {
+    fn test_experimental_record_round_trip() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path();
         
-        let mut log = Log::open(path).unwrap();
-        log.put(b"key1", b"value1").unwrap();
-        log.put(b"key2", b"value2").unwrap();
-        log.delete(b"key1").unwrap();
-        log.put(b"key3", b"value3").unwrap();
+        // A custom type whose payload is a key, a `=`, and a value
+        fn parse_assignment(payload: &[u8]) -> Option<LogRecord> {
+            let split = payload.iter().position(|&b| b == b'=')?;
+            Some(LogRecord::Put {
+                key: payload[..split].to_vec(),
+                value: payload[split + 1..].to_vec(),
+            })
+        }
+        
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"key1", b"value1").unwrap();
+            log.append_experimental(0x80, b"key2=value2").unwrap();
+            log.delete(b"key1").unwrap();
+            
+            let err = log.append_experimental(RECORD_PUT, b"").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        
+        let mut registry = RecordRegistry::new();
+        registry.register(0x80, parse_assignment).unwrap();
+        assert!(registry.register(0x7F, parse_assignment).is_err());
+        
+        let records = Log::read_all_with(path, &registry).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].key(), b"key2");
+        assert_eq!(records[1].value(), Some(&b"value2"[..]));
+        
+        // A payload the parser rejects is an error, not a skip
+        {
+            let mut log = Log::open(path).unwrap();
+            log.append_experimental(0x80, b"no separator").unwrap();
+        }
+        let err = Log::read_all_with(path, &registry).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_unknown_experimental_record_skipped() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"key1", b"value1").unwrap();
+            log.append_experimental(0xC3, b"opaque payload").unwrap();
+            log.delete(b"key1").unwrap();
+        }
         
         let records = Log::read_all(path).unwrap();
-        assert_eq!(records.len(), 4);
-        assert_eq!(
-            records[0],
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].key(), b"key1");
+        assert_eq!(records[1], LogRecord::Delete { key: b"key1".to_vec() });
+        
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(Log::scan(&bytes).unwrap().len(), 2);
+        let (spans, report) = Log::scan_lenient(&bytes);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(report.records_skipped, 0);
+        
+        let mut reader = &bytes[Log::put_len(b"key1", b"value1") as usize..];
+        let next = LogRecord::read_from(&mut reader).unwrap();
+        assert_eq!(next, Some(records[1].clone()));
+        
+        // Types outside the reserved range still fail
+        let mut unknown = bytes.clone();
+        unknown.extend_from_slice(&[0x7F, 0, 0, 0, 0]);
+        assert!(Log::scan(&unknown).is_err());
+        std::fs::write(path, &unknown).unwrap();
+        let err = Log::read_all(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_batch_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        let batch = vec![
             LogRecord::Put {
                 key: b"key1".to_vec(),
-                value: b"value1".to_vec()
-            }
-        );
-        assert_eq!(
-            records[1],
-            LogRecord::Put {
-                key: b"key2".to_vec(),
-                value: b"value2".to_vec()
-            }
-        );
-        assert_eq!(
-            records[2],
+                value: b"value1".to_vec(),
+            },
             LogRecord::Delete {
-                key: b"key1".to_vec()
-            }
-        );
+                key: b"key0".to_vec(),
+            },
+        ];
+        
+        let mut log = Log::open(path).unwrap();
+        log.put(b"key0", b"value0").unwrap();
+        log.write_batch(&batch).unwrap();
+        log.write_batch(&[]).unwrap();
+        log.delete(b"key1").unwrap();
+        
+        let records = Log::read_all(path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[1..3], batch[..]);
+        
+        let (lenient, report) = Log::read_all_lenient(path).unwrap();
+        assert_eq!(lenient, records);
+        assert_eq!(report.records_skipped, 0);
+    }
+
+    #[test]
+    fn test_read_all_lenient_drops_torn_batch() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"key0", b"value0").unwrap();
+            log.write_batch(&[
+                LogRecord::Put {
+                    key: b"key1".to_vec(),
+                    value: b"value1".to_vec(),
+                },
+                LogRecord::Put {
+                    key: b"key2".to_vec(),
+                    value: b"value2".to_vec(),
+                },
+            ])
+            .unwrap();
+        }
+        let len = std::fs::metadata(path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        
+        assert!(Log::read_all(path).is_err());
+        
+        // The intact first item of the batch must not be recovered on its own
+        let (records, report) = Log::read_all_lenient(path).unwrap();
         assert_eq!(
-            records[3],
-            LogRecord::Put {
-                key: b"key3".to_vec(),
-                value: b"value3".to_vec()
-            }
+            records,
+            vec![LogRecord::Put {
+                key: b"key0".to_vec(),
+                value: b"value0".to_vec()
+            }]
         );
+        assert_eq!(report.records_skipped, 1);
+        assert_eq!(report.bytes_skipped, len - 1 - (1 + 4 + 4 + 4 + 6));
     }
 
     #[test]
-    fn test_empty_key_and_value() {
+    fn test_two_phase_record() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path();
+        let put = |key: &[u8]| LogRecord::Put {
+            key: key.to_vec(),
+            value: b"value".to_vec(),
+        };
         
         let mut log = Log::open(path).unwrap();
-        log.put(b"", b"").unwrap();
-        log.put(b"key", b"").unwrap();
-        log.put(b"", b"value").unwrap();
+        log.put(b"key0", b"value").unwrap();
+        log.write_two_phase(&[put(b"key1"), put(b"key2")]).unwrap();
+        log.write_two_phase(&[]).unwrap();
+        let len = std::fs::metadata(path).unwrap().len();
+        let two_phase_len = Log::two_phase_len(&[put(b"key1"), put(b"key2")]);
+        assert_eq!(len, Log::put_len(b"key0", b"value") + two_phase_len);
+        let committed = vec![put(b"key0"), put(b"key1"), put(b"key2")];
+        assert_eq!(Log::read_all(path).unwrap(), committed);
         
-        let records = Log::read_all(path).unwrap();
-        assert_eq!(records.len(), 3);
+        // Without its commit marker, the pending record is ignored
+        log.write_two_phase(&[put(b"key3")]).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let uncommitted = &bytes[..bytes.len() - 1];
+        let spans = Log::scan(uncommitted).unwrap();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(Log::scan_complete(uncommitted).1, len as usize);
+        let (_, report) = Log::scan_lenient(uncommitted);
+        assert_eq!(report.records_recovered, 3);
+        let records: Vec<_> = Log::records_from_reader(uncommitted).collect();
+        assert_eq!(records.into_iter().map(Result::unwrap).collect::<Vec<_>>(), committed);
+        
+        // Nor is it applied once later records follow it
+        let mut abandoned = uncommitted.to_vec();
+        put(b"key4").write_to(&mut abandoned).unwrap();
+        let expected = vec![put(b"key0"), put(b"key1"), put(b"key2"), put(b"key4")];
+        let spans = Log::scan(&abandoned).unwrap();
+        let keys: Vec<_> = spans.iter().map(|span| &abandoned[span.key()]).collect();
+        assert_eq!(keys, expected.iter().map(LogRecord::key).collect::<Vec<_>>());
+        let records: Vec<_> = Log::records_from_reader(&abandoned[..]).collect();
+        assert_eq!(records.into_iter().map(Result::unwrap).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_scan_spans() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        
+        {
+            let mut log = Log::open(path).unwrap();
+            log.put(b"key1", b"value1").unwrap();
+            log.write_batch(&[LogRecord::Delete {
+                key: b"key1".to_vec(),
+            }])
+            .unwrap();
+        }
+        
+        let bytes = std::fs::read(path).unwrap();
+        let spans = Log::scan(&bytes).unwrap();
         assert_eq!(
-            records[0],
-            LogRecord::Put {
-                key: vec![],
-                value: vec![]
-            }
+            spans,
+            vec![
+                RecordSpan::Put { key: 5..9, content_type: None, version: None, value: 13..19 },
+                RecordSpan::Delete { key: 33..37 },
+            ]
         );
-        assert_eq!(
-            records[1],
-            LogRecord::Put {
-                key: b"key".to_vec(),
-                value: vec![]
+        assert_eq!(&bytes[spans[0].key()], b"key1");
+        let records: Vec<LogRecord> = spans.iter().map(|span| span.to_record(&bytes)).collect();
+        assert_eq!(records, Log::read_all(path).unwrap());
+        
+        let err = Log::scan(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_is_partial_entry() {
+        let mut bytes = Vec::new();
+        LogRecord::Put { key: b"key1".to_vec(), value: b"value1".to_vec() }
+            .write_to(&mut bytes)
+            .unwrap();
+        let batch = encode_group(RECORD_BATCH, &[LogRecord::Delete { key: b"key1".to_vec() }]);
+        
+        // Any prefix of an entry could still be completed
+        for entry in [&bytes[..], &batch[..]] {
+            for len in 1..entry.len() {
+                assert!(Log::is_partial_entry(&entry[..len]), "{:?}", &entry[..len]);
             }
-        );
+            assert!(!Log::is_partial_entry(entry));
+        }
+        assert!(!Log::is_partial_entry(&[]));
+        
+        // Neither an unknown type nor a complete batch with a bad payload can
+        let mut bad_batch = batch.clone();
+        bad_batch[1] = 2;
+        assert!(!Log::is_partial_entry(&bad_batch));
+        assert!(!Log::is_partial_entry(&[0x42, 0, 0]));
+    }
+
+    #[test]
+    fn test_serde_json_round_trip() {
+        let put = LogRecord::Put {
+            key: b"key1".to_vec(),
+            value: vec![0, 255, b'{'],
+        };
+        let delete = LogRecord::Delete { key: b"a".to_vec() };
+        
+        let put_json = serde_json::to_string(&put).unwrap();
+        let delete_json = serde_json::to_string(&delete).unwrap();
         assert_eq!(
-            records[2],
-            LogRecord::Put {
-                key: vec![],
-                value: b"value".to_vec()
-            }
+            put_json,
+            r#"{"op":"put","key":[107,101,121,49],"value":[0,255,123]}"#
         );
+        assert_eq!(delete_json, r#"{"op":"delete","key":[97]}"#);
+        
+        assert_eq!(serde_json::from_str::<LogRecord>(&put_json).unwrap(), put);
+        assert_eq!(serde_json::from_str::<LogRecord>(&delete_json).unwrap(), delete);
     }
 
     #[test]
-    fn test_large_key_and_value() {
+    fn test_size_bytes() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path();
         
-        let large_key = vec![0u8; 10000];
-        let large_value = vec![1u8; 50000];
-        
         let mut log = Log::open(path).unwrap();
-        log.put(&large_key, &large_value).unwrap();
+        assert_eq!(log.size_bytes().unwrap(), 0);
         
-        let records = Log::read_all(path).unwrap();
-        assert_eq!(records.len(), 1);
-        assert_eq!(records[0].key().len(), 10000);
-        assert_eq!(records[0].value().unwrap().len(), 50000);
+        log.put(b"key1", b"value1").unwrap();
+        log.delete(b"key1").unwrap();
+        let batch = [LogRecord::Put {
+            key: b"key2".to_vec(),
+            value: b"value2".to_vec(),
+        }];
+        log.write_batch(&batch).unwrap();
+        let expected = Log::put_len(b"key1", b"value1")
+            + Log::delete_len(b"key1")
+            + Log::batch_len(&batch);
+        assert_eq!(log.size_bytes().unwrap(), expected);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), expected);
+        
+        // Buffered records count before they are flushed
+        log.set_auto_flush(false);
+        log.delete(b"key2").unwrap();
+        assert_eq!(log.size_bytes().unwrap(), expected + Log::delete_len(b"key2"));
     }
 
     #[test]
@@ -326,17 +2101,12 @@ mod tests {
 // Helper methods for tests
 #[cfg(test)]
 impl LogRecord {
-    fn key(&self) -> &[u8] {
-        match self {
-            LogRecord::Put { key, .. } => key,
-            LogRecord::Delete { key } => key,
-        }
-    }
-
     fn value(&self) -> Option<&[u8]> {
         match self {
             LogRecord::Put { value, .. } => Some(value),
-            LogRecord::Delete { .. } => None,
+            LogRecord::TypedPut { value, .. } => Some(value),
+            LogRecord::VersionedPut { value, .. } => Some(value),
+            LogRecord::BlobPut { .. } | LogRecord::Delete { .. } => None,
         }
     }
 }
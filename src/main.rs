@@ -1,19 +1,52 @@
-mod log;
-mod db;
+use clap::{Parser, Subcommand, ValueEnum};
+use docdb::db::{Db, OpenReport, Options, DEFAULT_CONTENT_TYPE};
+use docdb::glob::Glob;
+use docdb::log::{Log, LogRecord, FORMAT_VERSION};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Read, Seek, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use clap::{Parser, Subcommand};
-use db::Db;
-use std::io::{self, Read};
-use std::path::PathBuf;
+/// Set from `--verbose-open`, which applies to every command that opens the
+/// database.
+static VERBOSE_OPEN: AtomicBool = AtomicBool::new(false);
 
 #[derive(Parser)]
 #[command(name = "docdb")]
 #[command(about = "A CLI for the document database", long_about = None)]
+#[command(after_help = "Exit codes:
+  0  Success
+  1  Database or I/O error
+  2  Invalid command-line usage
+  3  Key not found
+  4  Invalid input (e.g. malformed JSON, rejected key)")]
 struct Cli {
     /// Database directory path (defaults to current directory)
     #[arg(long, default_value = ".")]
     db_dir: PathBuf,
 
+    /// Fail if the database directory doesn't exist, instead of creating it
+    #[arg(long)]
+    no_create: bool,
+
+    /// Emit machine-readable output: compact JSON values, JSON lines for
+    /// lists, and errors on stderr as JSON objects with a `code` field
+    #[arg(long, global = true)]
+    machine: bool,
+
+    /// Report what `delete`, `touch`, `replay`, or a `shell` put or delete
+    /// would change, one line per key (JSON objects with `--machine`),
+    /// without writing anything
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Print replay statistics on stderr when the database is opened: the
+    /// records replayed, how long replay took, and the live key count
+    #[arg(long, global = true)]
+    verbose_open: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -24,8 +57,17 @@ enum Commands {
     Put {
         /// The key to store
         key: String,
-        /// The JSON value to store (if not provided, reads from stdin)
+        /// The JSON value to store (if neither this nor --file is given,
+        /// reads from stdin)
         value: Option<String>,
+        /// Read the value from this file instead of the command line
+        #[arg(long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        /// Content type to tag the value with. Values of any type other than
+        /// application/json skip JSON validation and are printed verbatim by
+        /// `get`
+        #[arg(long)]
+        content_type: Option<String>,
     },
     /// Retrieve a value by key
     Get {
@@ -35,183 +77,861 @@ enum Commands {
     /// Delete a key from the database
     Delete {
         /// The key to delete
+        #[arg(required_unless_present = "prefix")]
+        key: Option<String>,
+        /// Delete every key starting with this prefix instead
+        #[arg(long, conflicts_with = "key")]
+        prefix: Option<String>,
+    },
+    /// Write a key's current value back unchanged, as a fresh log record
+    Touch {
+        /// The key to touch
         key: String,
     },
     /// List all keys in the database
-    List,
-    /// Show the database location on disk
+    List {
+        /// Maximum number of keys to print
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Only print keys sorting strictly after this key (pagination cursor)
+        #[arg(long)]
+        after: Option<String>,
+        /// Only print keys matching this shell-style pattern (`*`, `?`, `[...]`)
+        #[arg(long)]
+        glob: Option<String>,
+        /// Print each key's value size in bytes after a tab
+        #[arg(long)]
+        sizes: bool,
+        /// Print each key's value after it, as `<key>: <value>`
+        #[arg(long, conflicts_with = "sizes")]
+        values: bool,
+        /// With --values, pretty-print JSON values instead of compacting them
+        #[arg(long, requires = "values")]
+        pretty: bool,
+        /// With --values, cut values longer than this many characters short,
+        /// ending them with `...`
+        #[arg(long, value_name = "CHARS", requires = "values")]
+        max_value_width: Option<usize>,
+        /// Order keys by value size, largest first, instead of by key
+        #[arg(long, requires = "sizes", conflicts_with = "after")]
+        sort_by_size: bool,
+        /// Order keys by a field of their JSON values, given as a JSON
+        /// pointer such as `/created_at`: numbers first, then strings, then
+        /// other values, with keys lacking the field last
+        #[arg(long, value_name = "POINTER", conflicts_with_all = ["after", "sort_by_size"])]
+        sort_by_field: Option<String>,
+        /// Also list keys whose last write in the log was a delete, marked
+        /// `[deleted]`, interleaved with the live keys
+        #[arg(long, conflicts_with_all = ["sizes", "values", "sort_by_field"])]
+        deleted: bool,
+        /// Like --deleted, but list only the deleted keys
+        #[arg(long, conflicts_with_all = ["sizes", "values", "sort_by_field"])]
+        only_deleted: bool,
+    },
+    /// Show the database location, format version, and record counts
     Info,
+    /// Write every live entry to stdout in a tabular format, sorted by key
+    Export {
+        /// Output format
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+    },
+    /// Write every live entry to stdout as binary log records, for `replay`
+    DumpRaw,
+    /// Apply binary log records read from stdin, as written by `dump-raw`
+    Replay,
+    /// Connect to a primary's replication stream and apply its records as
+    /// they arrive, until the primary closes the connection
+    Follow {
+        /// Address of the primary's stream, e.g. `127.0.0.1:7070`
+        source_addr: String,
+    },
+    /// Serve the log to one follower over TCP: every record from `--from`
+    /// onward, then new records as other processes write them
+    Replicate {
+        /// Address to listen on, e.g. `127.0.0.1:7070`
+        listen_addr: String,
+        /// Log byte offset to start from, which must be a record boundary
+        /// such as a log size reported by `info`
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+    },
+    /// Open the database once and run `put`, `get`, `delete`, and `list`
+    /// commands read from stdin, one per line, against it
+    Shell,
+    /// Print a line per change (`PUT <key>` or `DELETE <key>`) as other
+    /// processes write them, until interrupted. Polls the log for new
+    /// records
+    Watch {
+        /// Log byte offset to start from, which must be a record boundary;
+        /// defaults to the end of the log, printing only new changes
+        #[arg(long)]
+        from: Option<u64>,
+    },
+}
+
+/// Output formats for `export`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// `key,value` rows, with each value's raw JSON text as a CSV field
+    Csv,
+}
+
+/// Category of a CLI failure.
+/// 
+/// Each category has a fixed exit code (listed in the `--help` text) and a
+/// name reported as `code` in `--machine` mode. Both are part of the CLI's
+/// scripting contract and must not change.
+#[derive(Debug, Clone, Copy)]
+enum ErrorCode {
+    /// Opening, reading, or writing the database failed.
+    Failed,
+    /// The command line couldn't be parsed.
+    Usage,
+    /// The requested key doesn't exist.
+    NotFound,
+    /// The input was rejected (malformed JSON, key over the limit, ...).
+    InvalidInput,
+}
+
+impl ErrorCode {
+    fn exit_code(self) -> i32 {
+        match self {
+            ErrorCode::Failed => 1,
+            ErrorCode::Usage => 2,
+            ErrorCode::NotFound => 3,
+            ErrorCode::InvalidInput => 4,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorCode::Failed => "failed",
+            ErrorCode::Usage => "usage",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::InvalidInput => "invalid_input",
+        }
+    }
+
+    /// Classifies a database error.
+    fn from_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidInput => ErrorCode::InvalidInput,
+            _ => ErrorCode::Failed,
+        }
+    }
+}
+
+/// How results and errors are printed.
+#[derive(Debug, Clone, Copy)]
+enum OutputMode {
+    /// Pretty-printed output and prose errors for people.
+    Human,
+    /// Compact JSON output and JSON errors for scripts (`--machine`).
+    Machine,
+}
+
+impl OutputMode {
+    /// Reports an error on stderr and exits with the code's exit status.
+    fn fail(self, code: ErrorCode, message: &str) -> ! {
+        self.report_error(code, message);
+        std::process::exit(code.exit_code());
+    }
+
+    /// Reports an error on stderr without exiting.
+    fn report_error(self, code: ErrorCode, message: &str) {
+        match self {
+            OutputMode::Human => eprintln!("Error: {}", message),
+            OutputMode::Machine => eprintln!(
+                "{}",
+                serde_json::json!({ "code": code.name(), "message": message })
+            ),
+        }
+    }
+
+    /// Opens the database, creating its directory if needed, exiting on
+    /// failure.
+    fn open_db(self, db_dir: &PathBuf) -> Db {
+        self.open_db_with_report(db_dir).0
+    }
+
+    /// Opens the database like `open_db`, warning on stderr if the log held
+    /// keys that aren't UTF-8. Those records are skipped on open, so no
+    /// command can list or read them.
+    fn open_db_warning_skipped(self, db_dir: &PathBuf) -> Db {
+        let (db, report) = self.open_db_with_report(db_dir);
+        let skipped = report.skipped_keys.len();
+        if skipped > 0 {
+            let message = format!(
+                "Skipped {} log record(s) whose keys are not valid UTF-8; those entries can't be listed or read",
+                skipped
+            );
+            match self {
+                OutputMode::Human => eprintln!("Warning: {}", message),
+                OutputMode::Machine => {
+                    eprintln!("{}", serde_json::json!({ "warning": message }))
+                }
+            }
+        }
+        db
+    }
+
+    /// Shared implementation of `open_db` and `open_db_warning_skipped`.
+    fn open_db_with_report(self, db_dir: &PathBuf) -> (Db, OpenReport) {
+        if let Err(e) = std::fs::create_dir_all(db_dir) {
+            self.fail(
+                ErrorCode::Failed,
+                &format!("Failed to create database directory {}: {}", db_dir.display(), e),
+            );
+        }
+        let started = Instant::now();
+        match Db::open_with_report(db_dir, Options::default()) {
+            Ok((db, report)) => {
+                self.open_stats(&report, started.elapsed());
+                (db, report)
+            }
+            Err(e) => self.fail(
+                ErrorCode::Failed,
+                &format!("Failed to open database: {}", e),
+            ),
+        }
+    }
+
+    /// Prints replay statistics on stderr, if `--verbose-open` was given.
+    fn open_stats(self, report: &OpenReport, elapsed: Duration) {
+        if !VERBOSE_OPEN.load(Ordering::Relaxed) {
+            return;
+        }
+        let replay_ms = elapsed.as_secs_f64() * 1000.0;
+        match self {
+            OutputMode::Human => eprintln!(
+                "Opened database: replayed {} record(s) in {:.1} ms, {} live key(s)",
+                report.records_replayed, replay_ms, report.live_keys
+            ),
+            OutputMode::Machine => eprintln!(
+                "{}",
+                serde_json::json!({
+                    "open_stats": {
+                        "records_replayed": report.records_replayed,
+                        "replay_ms": replay_ms,
+                        "live_keys": report.live_keys,
+                    }
+                })
+            ),
+        }
+    }
+
+    /// Reports a change `--dry-run` skipped, such as `delete` of a key.
+    fn dry_run(self, action: &str, key: &str) {
+        match self {
+            OutputMode::Human => println!("Would {} {}", action, key),
+            OutputMode::Machine => {
+                println!("{}", serde_json::json!({ "action": action, "key": key }))
+            }
+        }
+    }
+
+    /// Closes a database after a write. Only a warning for people, but an
+    /// error for scripts, since the write may not have been flushed.
+    fn close_db(self, db: Db) {
+        if let Err(e) = db.close() {
+            let message = format!("Failed to close database: {}", e);
+            match self {
+                OutputMode::Human => eprintln!("Warning: {}", message),
+                OutputMode::Machine => self.fail(ErrorCode::Failed, &message),
+            }
+        }
+    }
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            // Help and version requests aren't failures
+            if !e.use_stderr() || !std::env::args().any(|arg| arg == "--machine") {
+                e.exit();
+            }
+            // Keep clap's one-line summary, minus its "error: " prefix
+            let rendered = e.to_string();
+            let summary = rendered.lines().next().unwrap_or_default();
+            let message = summary.strip_prefix("error: ").unwrap_or(summary);
+            OutputMode::Machine.fail(ErrorCode::Usage, message);
+        }
+    };
+    let mode = if cli.machine {
+        OutputMode::Machine
+    } else {
+        OutputMode::Human
+    };
+    VERBOSE_OPEN.store(cli.verbose_open, Ordering::Relaxed);
+
+    // Guard against a mistyped path silently creating a new, empty database
+    if cli.no_create && !cli.db_dir.is_dir() {
+        mode.fail(
+            ErrorCode::Failed,
+            &format!("Database directory {} does not exist", cli.db_dir.display()),
+        );
+    }
 
     match cli.command {
-        Commands::Put { key, value } => {
-            handle_put(&cli.db_dir, &key, value);
+        Commands::Put { key, value, file, content_type } => {
+            handle_put(mode, &cli.db_dir, &key, value, file.as_deref(), content_type.as_deref());
         }
         Commands::Get { key } => {
-            handle_get(&cli.db_dir, &key);
+            handle_get(mode, &cli.db_dir, &key);
         }
-        Commands::Delete { key } => {
-            handle_delete(&cli.db_dir, &key);
+        Commands::Delete { key, prefix } => {
+            handle_delete(mode, &cli.db_dir, key.as_deref(), prefix.as_deref(), cli.dry_run);
         }
-        Commands::List => {
-            handle_list(&cli.db_dir);
+        Commands::Touch { key } => handle_touch(mode, &cli.db_dir, &key, cli.dry_run),
+        Commands::List {
+            limit,
+            after,
+            glob,
+            sizes,
+            values,
+            pretty,
+            max_value_width,
+            sort_by_size,
+            sort_by_field,
+            deleted,
+            only_deleted,
+        } => {
+            let (after, glob) = (after.as_deref(), glob.as_deref());
+            if deleted || only_deleted {
+                handle_list_deleted(mode, &cli.db_dir, limit, after, glob, only_deleted);
+                return;
+            }
+            let order = match sort_by_field {
+                Some(pointer) => ListOrder::Field(pointer),
+                None if sort_by_size => ListOrder::Size,
+                None => ListOrder::Key,
+            };
+            let detail = if values {
+                ListDetail::Value { pretty, max_width: max_value_width }
+            } else if sizes {
+                ListDetail::Size
+            } else {
+                ListDetail::Nothing
+            };
+            handle_list(mode, &cli.db_dir, limit, after, glob, detail, order);
         }
         Commands::Info => {
-            handle_info(&cli.db_dir);
+            handle_info(mode, &cli.db_dir);
+        }
+        Commands::Export { format } => {
+            handle_export(mode, &cli.db_dir, format);
+        }
+        Commands::DumpRaw => {
+            handle_dump_raw(mode, &cli.db_dir);
+        }
+        Commands::Replay => {
+            handle_replay(mode, &cli.db_dir, cli.dry_run);
+        }
+        Commands::Follow { source_addr } => {
+            handle_follow(mode, &cli.db_dir, &source_addr);
+        }
+        Commands::Replicate { listen_addr, from } => {
+            handle_replicate(mode, &cli.db_dir, &listen_addr, from);
+        }
+        Commands::Shell => {
+            handle_shell(mode, &cli.db_dir, cli.dry_run);
+        }
+        Commands::Watch { from } => {
+            handle_watch(mode, &cli.db_dir, from);
         }
     }
 }
 
-fn handle_put(db_dir: &PathBuf, key: &str, value: Option<String>) {
-    let value_bytes = match value {
-        Some(v) => {
-            // Validate that it's valid JSON
-            match serde_json::from_str::<serde_json::Value>(&v) {
-                Ok(_) => v.into_bytes(),
-                Err(e) => {
-                    eprintln!("Error: Invalid JSON: {}", e);
-                    std::process::exit(1);
-                }
-            }
-        }
-        None => {
+fn handle_put(
+    mode: OutputMode,
+    db_dir: &PathBuf,
+    key: &str,
+    value: Option<String>,
+    file: Option<&Path>,
+    content_type: Option<&str>,
+) {
+    // Only JSON values are validated; other content types are stored as given
+    let content_type = content_type.filter(|content_type| *content_type != DEFAULT_CONTENT_TYPE);
+
+    // The value comes from the argument or --file (clap rejects both), else stdin
+    let (value_bytes, invalid_json) = match (value, file) {
+        (Some(v), _) => (v.into_bytes(), "Invalid JSON".to_string()),
+        (None, Some(path)) => match std::fs::read(path) {
+            Ok(bytes) => (bytes, format!("Invalid JSON in {}", path.display())),
+            Err(e) => mode.fail(
+                ErrorCode::Failed,
+                &format!("Failed to read {}: {}", path.display(), e),
+            ),
+        },
+        (None, None) => {
             // Read from stdin
-            let mut buffer = String::new();
-            io::stdin()
-                .read_to_string(&mut buffer)
-                .expect("Failed to read from stdin");
-            
-            // Validate JSON
-            match serde_json::from_str::<serde_json::Value>(&buffer) {
-                Ok(_) => buffer.into_bytes(),
-                Err(e) => {
-                    eprintln!("Error: Invalid JSON from stdin: {}", e);
-                    std::process::exit(1);
-                }
+            let mut buffer = Vec::new();
+            if let Err(e) = io::stdin().read_to_end(&mut buffer) {
+                mode.fail(ErrorCode::Failed, &format!("Failed to read from stdin: {}", e));
             }
+            (buffer, "Invalid JSON from stdin".to_string())
         }
     };
 
-    let mut db = match Db::open(db_dir) {
-        Ok(db) => db,
-        Err(e) => {
-            eprintln!("Error: Failed to open database: {}", e);
-            std::process::exit(1);
+    // Validate JSON before opening, so invalid input doesn't create the database
+    if content_type.is_none() {
+        if let Err(e) = Db::validate_json(&value_bytes) {
+            mode.fail(ErrorCode::InvalidInput, &format!("{}: {}", invalid_json, e));
         }
-    };
+    }
+
+    let mut db = mode.open_db(db_dir);
 
-    match db.put(key, &value_bytes) {
+    let result = match content_type {
+        Some(content_type) => db.put_with_content_type(key, &value_bytes, content_type),
+        None => db.put(key, &value_bytes),
+    };
+    match result {
         Ok(()) => {
             // Success - no output for put operations
         }
-        Err(e) => {
-            eprintln!("Error: Failed to put value: {}", e);
-            std::process::exit(1);
-        }
+        Err(e) => mode.fail(ErrorCode::from_io(&e), &format!("Failed to put value: {}", e)),
     }
 
-    if let Err(e) = db.close() {
-        eprintln!("Warning: Failed to close database: {}", e);
+    mode.close_db(db);
+}
+
+fn handle_get(mode: OutputMode, db_dir: &PathBuf, key: &str) {
+    let db = mode.open_db_warning_skipped(db_dir);
+    if let Err((code, message)) = print_value(mode, &db, key) {
+        mode.fail(code, &message);
     }
 }
 
-fn handle_get(db_dir: &PathBuf, key: &str) {
-    let db = match Db::open(db_dir) {
-        Ok(db) => db,
-        Err(e) => {
-            eprintln!("Error: Failed to open database: {}", e);
-            std::process::exit(1);
-        }
+/// Prints a key's value as `get` does, or returns why it can't.
+fn print_value(mode: OutputMode, db: &Db, key: &str) -> Result<(), (ErrorCode, String)> {
+    let value_bytes = match db.get(key) {
+        Ok(Some(value)) => value,
+        Ok(None) => return Err((ErrorCode::NotFound, format!("Key '{}' not found", key))),
+        Err(e) => return Err((ErrorCode::Failed, format!("Failed to read value: {}", e))),
     };
 
-    match db.get(key) {
-        Some(value_bytes) => {
-            // Try to parse as JSON and pretty-print
-            match serde_json::from_slice::<serde_json::Value>(value_bytes) {
-                Ok(json_value) => {
-                    match serde_json::to_string_pretty(&json_value) {
-                        Ok(pretty) => println!("{}", pretty),
-                        Err(e) => {
-                            eprintln!("Error: Failed to format JSON: {}", e);
-                            // Fall back to raw output
-                            match String::from_utf8(value_bytes.to_vec()) {
-                                Ok(s) => println!("{}", s),
-                                Err(_) => {
-                                    eprintln!("Error: Value is not valid UTF-8 or JSON");
-                                    std::process::exit(1);
-                                }
-                            }
-                        }
+    // Values of other content types aren't JSON; print them verbatim
+    if db.content_type(key) != Some(DEFAULT_CONTENT_TYPE) {
+        let mut stdout = io::stdout().lock();
+        if let Err(e) = stdout.write_all(&value_bytes).and_then(|()| stdout.flush()) {
+            return Err((ErrorCode::Failed, format!("Failed to write value: {}", e)));
+        }
+        return Ok(());
+    }
+
+    // Try to parse as JSON and print it (pretty for people, compact for scripts)
+    match serde_json::from_slice::<serde_json::Value>(&value_bytes) {
+        Ok(json_value) => {
+            let formatted = match mode {
+                OutputMode::Human => serde_json::to_string_pretty(&json_value),
+                OutputMode::Machine => serde_json::to_string(&json_value),
+            };
+            match formatted {
+                Ok(text) => println!("{}", text),
+                Err(e) => {
+                    if let OutputMode::Human = mode {
+                        eprintln!("Error: Failed to format JSON: {}", e);
                     }
-                }
-                Err(_) => {
-                    // Not valid JSON, try to output as string
+                    // Fall back to raw output
                     match String::from_utf8(value_bytes.to_vec()) {
                         Ok(s) => println!("{}", s),
                         Err(_) => {
-                            eprintln!("Error: Value is not valid UTF-8");
-                            std::process::exit(1);
+                            let message = "Value is not valid UTF-8 or JSON".to_string();
+                            return Err((ErrorCode::Failed, message));
                         }
                     }
                 }
             }
         }
-        None => {
-            eprintln!("Error: Key '{}' not found", key);
-            std::process::exit(1);
+        Err(_) => {
+            // Not valid JSON, output as a string (a JSON string for scripts)
+            match String::from_utf8(value_bytes.to_vec()) {
+                Ok(s) => match mode {
+                    OutputMode::Human => println!("{}", s),
+                    OutputMode::Machine => println!("{}", serde_json::Value::String(s)),
+                },
+                Err(_) => return Err((ErrorCode::Failed, "Value is not valid UTF-8".to_string())),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_delete(
+    mode: OutputMode,
+    db_dir: &PathBuf,
+    key: Option<&str>,
+    prefix: Option<&str>,
+    dry_run: bool,
+) {
+    // A dry run must not create the database just to find it empty
+    if dry_run && !db_dir.join("log").exists() {
+        return;
+    }
+    let mut db = mode.open_db(db_dir);
+
+    let keys: Vec<String> = match (key, prefix) {
+        (_, Some(prefix)) => db.keys().filter(|key| key.starts_with(prefix)).cloned().collect(),
+        (Some(key), None) => vec![key.to_string()],
+        (None, None) => unreachable!("clap requires a key or --prefix"),
+    };
+
+    if dry_run {
+        // Deleting a missing key changes nothing, so it isn't reported
+        // Only live keys have a content type; checking it reads no values
+        for key in keys.iter().filter(|key| db.content_type(key).is_some()) {
+            mode.dry_run("delete", key);
         }
+        return;
     }
+
+    for key in &keys {
+        match db.delete(key) {
+            Ok(()) => {
+                // Success - no output for delete operations
+            }
+            Err(e) => mode.fail(ErrorCode::from_io(&e), &format!("Failed to delete key: {}", e)),
+        }
+    }
+
+    mode.close_db(db);
 }
 
-fn handle_delete(db_dir: &PathBuf, key: &str) {
-    let mut db = match Db::open(db_dir) {
-        Ok(db) => db,
-        Err(e) => {
-            eprintln!("Error: Failed to open database: {}", e);
-            std::process::exit(1);
+fn handle_touch(mode: OutputMode, db_dir: &PathBuf, key: &str, dry_run: bool) {
+    // A dry run must not create the database just to find the key missing
+    if dry_run && !db_dir.join("log").exists() {
+        mode.fail(ErrorCode::NotFound, &format!("Key '{}' not found", key));
+    }
+    let mut db = mode.open_db(db_dir);
+
+    let value = match db.get(key) {
+        Ok(Some(value)) => value.into_owned(),
+        Ok(None) => mode.fail(ErrorCode::NotFound, &format!("Key '{}' not found", key)),
+        Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to read value: {}", e)),
+    };
+    if dry_run {
+        mode.dry_run("touch", key);
+        return;
+    }
+
+    // Keep a non-default content type, which a plain put would reset
+    let result = match db.content_type(key).filter(|&ct| ct != DEFAULT_CONTENT_TYPE) {
+        Some(content_type) => {
+            let content_type = content_type.to_string();
+            db.put_with_content_type(key, &value, &content_type)
         }
+        None => db.put(key, &value),
     };
+    if let Err(e) = result {
+        mode.fail(ErrorCode::from_io(&e), &format!("Failed to touch key: {}", e));
+    }
 
-    match db.delete(key) {
-        Ok(()) => {
-            // Success - no output for delete operations
+    mode.close_db(db);
+}
+
+fn handle_shell(mode: OutputMode, db_dir: &PathBuf, dry_run: bool) {
+    let mut db = mode.open_db_warning_skipped(db_dir);
+
+    // A failed command is reported and the session carries on; the exit
+    // status is that of the last failure
+    let mut last_failure = None;
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to read from stdin: {}", e)),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-        Err(e) => {
-            eprintln!("Error: Failed to delete key: {}", e);
-            std::process::exit(1);
+        if let Err((code, message)) = run_shell_command(mode, &mut db, line, dry_run) {
+            mode.report_error(code, &message);
+            last_failure = Some(code);
         }
     }
 
-    if let Err(e) = db.close() {
-        eprintln!("Warning: Failed to close database: {}", e);
+    mode.close_db(db);
+    if let Some(code) = last_failure {
+        std::process::exit(code.exit_code());
     }
 }
 
-fn handle_list(db_dir: &PathBuf) {
-    let db = match Db::open(db_dir) {
-        Ok(db) => db,
-        Err(e) => {
-            eprintln!("Error: Failed to open database: {}", e);
-            std::process::exit(1);
+/// Runs one `shell` command line, printing its output as the subcommand
+/// of the same name would.
+/// 
+/// With `dry_run`, puts and deletes are reported instead of written, so
+/// later commands in the session don't see them.
+fn run_shell_command(
+    mode: OutputMode,
+    db: &mut Db,
+    line: &str,
+    dry_run: bool,
+) -> Result<(), (ErrorCode, String)> {
+    let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim_start();
+    let usage = |usage: &str| Err((ErrorCode::Usage, format!("Usage: {}", usage)));
+    // Set when the arguments are exactly one key
+    let single_key = (!args.is_empty() && !args.contains(char::is_whitespace)).then_some(args);
+
+    match command {
+        "put" => {
+            // The value is the rest of the line, so it may contain spaces
+            let Some((key, value)) = args.split_once(char::is_whitespace) else {
+                return usage("put <key> <json>");
+            };
+            let value = value.trim_start();
+            if let Err(e) = Db::validate_json(value.as_bytes()) {
+                return Err((ErrorCode::InvalidInput, format!("Invalid JSON: {}", e)));
+            }
+            if dry_run {
+                mode.dry_run("put", key);
+                return Ok(());
+            }
+            db.put(key, value.as_bytes())
+                .map_err(|e| (ErrorCode::from_io(&e), format!("Failed to put value: {}", e)))
+        }
+        "get" => match single_key {
+            Some(key) => print_value(mode, db, key),
+            None => usage("get <key>"),
+        },
+        "delete" => match single_key {
+            // As with `delete`, a missing key wouldn't change anything
+            Some(key) if dry_run => {
+                if db.content_type(key).is_some() {
+                    mode.dry_run("delete", key);
+                }
+                Ok(())
+            }
+            Some(key) => db
+                .delete(key)
+                .map_err(|e| (ErrorCode::from_io(&e), format!("Failed to delete key: {}", e))),
+            None => usage("delete <key>"),
+        },
+        "list" if args.is_empty() => {
+            for key in db.keys_sorted() {
+                match mode {
+                    OutputMode::Human => println!("{}", key),
+                    OutputMode::Machine => println!("{}", serde_json::Value::String(key.clone())),
+                }
+            }
+            Ok(())
+        }
+        "list" => usage("list"),
+        _ => Err((
+            ErrorCode::Usage,
+            format!("Unknown command '{}'; expected put, get, delete, or list", command),
+        )),
+    }
+}
+
+/// The order `list` prints keys in.
+enum ListOrder {
+    /// Sorted by key.
+    Key,
+    /// Largest value first.
+    Size,
+    /// By the JSON value at this pointer in each value.
+    Field(String),
+}
+
+/// What `list` prints alongside each key.
+enum ListDetail {
+    /// Just the key.
+    Nothing,
+    /// The value's size in bytes.
+    Size,
+    /// The value itself. JSON values are reformatted, compact unless
+    /// `pretty`; in human output, values are cut to `max_width` characters.
+    Value { pretty: bool, max_width: Option<usize> },
+}
+
+/// A value's field as a sort key for `list --sort-by-field`. Variants are
+/// declared in sort order.
+#[derive(PartialEq, PartialOrd)]
+enum FieldValue {
+    Number(f64),
+    String(String),
+    /// Booleans, nulls, arrays, and objects, which tie with each other.
+    Other,
+    /// Values that aren't JSON or lack the field.
+    Missing,
+}
+
+impl FieldValue {
+    fn of(value: Option<&[u8]>, pointer: &str) -> Self {
+        let json = value.and_then(|value| serde_json::from_slice::<serde_json::Value>(value).ok());
+        let Some(json) = json else {
+            return FieldValue::Missing;
+        };
+        match json.pointer(pointer) {
+            Some(serde_json::Value::Number(n)) => {
+                n.as_f64().map_or(FieldValue::Other, FieldValue::Number)
+            }
+            Some(serde_json::Value::String(s)) => FieldValue::String(s.clone()),
+            Some(_) => FieldValue::Other,
+            None => FieldValue::Missing,
+        }
+    }
+}
+
+fn handle_list(
+    mode: OutputMode,
+    db_dir: &PathBuf,
+    limit: Option<usize>,
+    after: Option<&str>,
+    glob: Option<&str>,
+    detail: ListDetail,
+    order: ListOrder,
+) {
+    let db = mode.open_db_warning_skipped(db_dir);
+
+    // Keys come in sorted order, so the cursor is a prefix to skip
+    let matcher = glob.map(Glob::new);
+    let matching = db
+        .keys_sorted()
+        .skip_while(|key| after.is_some_and(|after| key.as_str() <= after))
+        .filter(|key| matcher.as_ref().is_none_or(|matcher| matcher.matches(key)));
+    let size = |key: &str| db.get_size(key).unwrap_or(0);
+    // Listing in key order streams; the other orders collect the matches to
+    // sort them, and both sorts are stable, so ties stay in key order
+    let keys: Box<dyn Iterator<Item = &String>> = match order {
+        ListOrder::Key => Box::new(matching),
+        ListOrder::Size => {
+            let mut keys: Vec<&String> = matching.collect();
+            keys.sort_by_key(|key| std::cmp::Reverse(size(key)));
+            Box::new(keys.into_iter())
+        }
+        ListOrder::Field(pointer) => {
+            let mut fields: Vec<(&String, FieldValue)> = matching
+                .map(|key| (key, FieldValue::of(db.get(key).ok().flatten().as_deref(), &pointer)))
+                .collect();
+            // JSON numbers are never NaN, so every pair compares
+            fields.sort_by(|(_, a), (_, b)| {
+                a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)
+            });
+            Box::new(fields.into_iter().map(|(key, _)| key))
         }
     };
 
-    let mut keys: Vec<&String> = db.keys().collect();
-    keys.sort();
+    let mut listed = 0;
+    for key in keys.take(limit.unwrap_or(usize::MAX)) {
+        listed += 1;
+        if let OutputMode::Machine = mode {
+            // One JSON value per line; an empty result prints nothing
+            match detail {
+                ListDetail::Nothing => println!("{}", serde_json::Value::String(key.clone())),
+                ListDetail::Size => {
+                    println!("{}", serde_json::json!({"key": key, "bytes": size(key)}))
+                }
+                ListDetail::Value { .. } => {
+                    // Values that aren't JSON become strings
+                    let value = json_value(&db, key)
+                        .unwrap_or_else(|| serde_json::Value::String(lossy_value(&db, key)));
+                    println!("{}", serde_json::json!({"key": key, "value": value}));
+                }
+            }
+            continue;
+        }
+        match detail {
+            ListDetail::Nothing => println!("{}", key),
+            ListDetail::Size => println!("{}\t{}", key, size(key)),
+            ListDetail::Value { pretty, max_width } => {
+                // Values that aren't JSON print verbatim, as `get` does
+                let text = match json_value(&db, key) {
+                    Some(json) if pretty => {
+                        serde_json::to_string_pretty(&json).unwrap_or_default()
+                    }
+                    Some(json) => json.to_string(),
+                    None => lossy_value(&db, key),
+                };
+                println!("{}: {}", key, truncate_chars(&text, max_width));
+            }
+        }
+    }
 
-    if keys.is_empty() {
+    // An empty page past the cursor, or of matches, is not an empty database
+    if listed == 0 && matches!(mode, OutputMode::Human) && after.is_none() && glob.is_none() {
         println!("No keys found in database");
-    } else {
-        for key in keys {
-            println!("{}", key);
+    }
+}
+
+/// Lists deleted keys for `list --deleted` and `list --only-deleted`,
+/// interleaved with the live keys unless `only_deleted`.
+fn handle_list_deleted(
+    mode: OutputMode,
+    db_dir: &PathBuf,
+    limit: Option<usize>,
+    after: Option<&str>,
+    glob: Option<&str>,
+    only_deleted: bool,
+) {
+    let db = mode.open_db_warning_skipped(db_dir);
+    let deleted = match Db::deleted_keys(db_dir) {
+        Ok(deleted) => deleted,
+        Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to read log: {}", e)),
+    };
+
+    let live: Vec<&String> = if only_deleted { Vec::new() } else { db.keys_sorted().collect() };
+    let mut keys: Vec<(&String, bool)> = live
+        .into_iter()
+        .map(|key| (key, false))
+        .chain(deleted.iter().map(|key| (key, true)))
+        .collect();
+    keys.sort();
+    let matcher = glob.map(Glob::new);
+    let keys = keys
+        .into_iter()
+        .skip_while(|(key, _)| after.is_some_and(|after| key.as_str() <= after))
+        .filter(|(key, _)| matcher.as_ref().is_none_or(|matcher| matcher.matches(key)))
+        .take(limit.unwrap_or(usize::MAX));
+
+    let mut listed = 0;
+    for (key, is_deleted) in keys {
+        listed += 1;
+        match mode {
+            OutputMode::Human if is_deleted => println!("{} [deleted]", key),
+            OutputMode::Human => println!("{}", key),
+            OutputMode::Machine => {
+                println!("{}", serde_json::json!({"key": key, "deleted": is_deleted}))
+            }
         }
     }
+
+    if listed == 0 && matches!(mode, OutputMode::Human) && after.is_none() && glob.is_none() {
+        println!("No keys found in database");
+    }
+}
+
+/// Parses a key's value, if it has the JSON content type, can be read, and
+/// is valid JSON.
+fn json_value(db: &Db, key: &str) -> Option<serde_json::Value> {
+    if db.content_type(key) != Some(DEFAULT_CONTENT_TYPE) {
+        return None;
+    }
+    serde_json::from_slice(&db.get(key).ok()??).ok()
 }
 
-fn handle_info(db_dir: &PathBuf) {
+/// Returns a key's value as text, replacing invalid UTF-8. A value that
+/// can't be read gives empty text.
+fn lossy_value(db: &Db, key: &str) -> String {
+    match db.get(key) {
+        Ok(Some(value)) => String::from_utf8_lossy(&value).into_owned(),
+        _ => String::new(),
+    }
+}
+
+/// Cuts `text` to `max_width` characters, marking the cut with `...`.
+fn truncate_chars(text: &str, max_width: Option<usize>) -> Cow<'_, str> {
+    match max_width.and_then(|width| text.char_indices().nth(width)) {
+        Some((end, _)) => Cow::Owned(format!("{}...", &text[..end])),
+        None => Cow::Borrowed(text),
+    }
+}
+
+fn handle_info(mode: OutputMode, db_dir: &PathBuf) {
     // Resolve the absolute path to show the actual location
     let abs_path = match std::fs::canonicalize(db_dir) {
         Ok(path) => path,
@@ -224,18 +944,393 @@ fn handle_info(db_dir: &PathBuf) {
             }
         }
     };
-    
+
     let log_path = abs_path.join("log");
-    
-    println!("Database directory: {}", abs_path.display());
-    println!("Log file: {}", log_path.display());
-    
+    let mut info = serde_json::json!({
+        "database_directory": abs_path.display().to_string(),
+        "log_file": log_path.display().to_string(),
+    });
+
+    if let OutputMode::Human = mode {
+        println!("Database directory: {}", abs_path.display());
+        println!("Log file: {}", log_path.display());
+    }
+
     // Show if the log file exists
-    if log_path.exists() {
-        if let Ok(metadata) = std::fs::metadata(&log_path) {
+    if !log_path.exists() {
+        match mode {
+            OutputMode::Human => println!("Log file: (not created yet)"),
+            OutputMode::Machine => {
+                info["log_status"] = "missing".into();
+                println!("{}", info);
+            }
+        }
+        return;
+    }
+    if let Ok(metadata) = std::fs::metadata(&log_path) {
+        info["log_bytes"] = metadata.len().into();
+        if let OutputMode::Human = mode {
             println!("Log file size: {} bytes", metadata.len());
         }
+    }
+    info["format_version"] = FORMAT_VERSION.into();
+    if let OutputMode::Human = mode {
+        println!("Format version: {}", FORMAT_VERSION);
+    }
+
+    // Open leniently so a damaged log can still be diagnosed
+    let options = Options {
+        lenient_recovery: true,
+        ..Options::default()
+    };
+    let started = Instant::now();
+    let db = match Db::open_with_report(&abs_path, options) {
+        Ok((db, open_report)) => {
+            mode.open_stats(&open_report, started.elapsed());
+            db
+        }
+        Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to open database: {}", e)),
+    };
+    let report = db.last_recovery_report();
+    let live_keys = db.keys().count();
+
+    if let OutputMode::Machine = mode {
+        info["records"] = report.records_recovered.into();
+        info["live_keys"] = live_keys.into();
+        info["log_status"] = if report.records_skipped == 0 { "clean" } else { "damaged" }.into();
+        info["corrupt_regions"] = report.records_skipped.into();
+        info["bytes_skipped"] = report.bytes_skipped.into();
+        println!("{}", info);
+        return;
+    }
+
+    println!("Records: {}", report.records_recovered);
+    println!("Live keys: {}", live_keys);
+    if report.records_skipped == 0 {
+        println!("Log status: clean");
     } else {
-        println!("Log file: (not created yet)");
+        println!(
+            "Log status: damaged ({} corrupt region(s), {} bytes unparsable)",
+            report.records_skipped, report.bytes_skipped
+        );
+    }
+}
+
+fn handle_export(mode: OutputMode, db_dir: &PathBuf, format: ExportFormat) {
+    let db = mode.open_db(db_dir);
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+
+    let result = match format {
+        ExportFormat::Csv => db.export_csv(&mut stdout),
+    };
+    if let Err(e) = result {
+        mode.fail(ErrorCode::Failed, &format!("Failed to export: {}", e));
+    }
+}
+
+fn handle_dump_raw(mode: OutputMode, db_dir: &PathBuf) {
+    let db = mode.open_db(db_dir);
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+
+    for key in db.keys() {
+        // Keep content types so a replay reproduces them, and versions so
+        // versioned values stay versioned
+        let (value, version) = match db.get_versioned(key) {
+            Ok(Some(versioned)) => versioned,
+            Ok(None) => continue,
+            Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to read '{}': {}", key, e)),
+        };
+        let record = match db.content_type(key) {
+            Some(content_type) if content_type != DEFAULT_CONTENT_TYPE => LogRecord::TypedPut {
+                key: key.as_bytes().to_vec(),
+                content_type: content_type.as_bytes().to_vec(),
+                value: value.to_vec(),
+            },
+            _ if version > 0 => LogRecord::VersionedPut {
+                key: key.as_bytes().to_vec(),
+                version,
+                value: value.to_vec(),
+            },
+            _ => LogRecord::Put {
+                key: key.as_bytes().to_vec(),
+                value: value.to_vec(),
+            },
+        };
+        if let Err(e) = record.write_to(&mut stdout) {
+            mode.fail(ErrorCode::Failed, &format!("Failed to write record: {}", e));
+        }
+    }
+
+    if let Err(e) = stdout.flush() {
+        mode.fail(ErrorCode::Failed, &format!("Failed to write record: {}", e));
+    }
+}
+
+fn handle_replay(mode: OutputMode, db_dir: &PathBuf, dry_run: bool) {
+    let mut db = (!dry_run).then(|| mode.open_db(db_dir));
+    let mut stdin = io::BufReader::new(io::stdin().lock());
+
+    // Records are applied in order, so later writes to a key win
+    loop {
+        let record = match LogRecord::read_from(&mut stdin) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                        ErrorCode::InvalidInput
+                    }
+                    _ => ErrorCode::Failed,
+                };
+                mode.fail(code, &format!("Failed to read record from stdin: {}", e));
+            }
+        };
+
+        let Some(db) = &mut db else {
+            let (action, key) = match record {
+                LogRecord::Put { key, .. }
+                | LogRecord::TypedPut { key, .. }
+                | LogRecord::VersionedPut { key, .. }
+                | LogRecord::BlobPut { key, .. } => ("put", key),
+                LogRecord::Delete { key } => ("delete", key),
+            };
+            mode.dry_run(action, &record_key(mode, key));
+            continue;
+        };
+
+        let (key, result) = match record {
+            LogRecord::Put { key, value } => {
+                let key = record_key(mode, key);
+                let result = db.put(&key, &value);
+                (key, result)
+            }
+            LogRecord::TypedPut { key, content_type, value } => {
+                let key = record_key(mode, key);
+                let content_type = String::from_utf8_lossy(&content_type);
+                let result = db.put_with_content_type(&key, &value, &content_type);
+                (key, result)
+            }
+            LogRecord::VersionedPut { key, value, .. } => {
+                // A replayed write is the key's next version in this database,
+                // whatever version it had in the source
+                let key = record_key(mode, key);
+                let result = db
+                    .get_versioned(&key)
+                    .map(|current| current.map_or(0, |(_, version)| version))
+                    .and_then(|version| db.put_versioned(&key, &value, version))
+                    .map(|_| ());
+                (key, result)
+            }
+            LogRecord::BlobPut { key, .. } => {
+                // `dump-raw` never writes these; the value is in a file of
+                // the source database
+                let key = record_key(mode, key);
+                let result = Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "record refers to an external value in another database",
+                ));
+                (key, result)
+            }
+            LogRecord::Delete { key } => {
+                let key = record_key(mode, key);
+                let result = db.delete(&key);
+                (key, result)
+            }
+        };
+        if let Err(e) = result {
+            mode.fail(
+                ErrorCode::from_io(&e),
+                &format!("Failed to apply record for key '{}': {}", key, e),
+            );
+        }
+    }
+
+    if let Some(db) = db {
+        mode.close_db(db);
+    }
+}
+
+fn handle_follow(mode: OutputMode, db_dir: &PathBuf, source_addr: &str) {
+    let mut db = mode.open_db(db_dir);
+    let stream = match TcpStream::connect(source_addr) {
+        Ok(stream) => stream,
+        Err(e) => mode.fail(
+            ErrorCode::Failed,
+            &format!("Failed to connect to '{}': {}", source_addr, e),
+        ),
+    };
+    let mut stream = io::BufReader::new(stream);
+
+    // The stream carries records in the `dump-raw` format. Each is logged
+    // locally as it arrives, and the last write to a key wins
+    loop {
+        let record = match LogRecord::read_from(&mut stream) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                        ErrorCode::InvalidInput
+                    }
+                    _ => ErrorCode::Failed,
+                };
+                mode.fail(code, &format!("Failed to read record from '{}': {}", source_addr, e));
+            }
+        };
+        if let Err(e) = db.apply_remote_record(&record) {
+            mode.fail(
+                ErrorCode::from_io(&e),
+                &format!(
+                    "Failed to apply record for key '{}': {}",
+                    String::from_utf8_lossy(record.key()),
+                    e
+                ),
+            );
+        }
+    }
+
+    mode.close_db(db);
+}
+
+/// How often `replicate` and `watch` check the log for new records.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn handle_replicate(mode: OutputMode, db_dir: &Path, listen_addr: &str, from: u64) {
+    // Writers are other processes, so the database isn't opened here
+    let log_path = db_dir.join("log");
+
+    let listener = match TcpListener::bind(listen_addr) {
+        Ok(listener) => listener,
+        Err(e) => mode.fail(
+            ErrorCode::Failed,
+            &format!("Failed to listen on '{}': {}", listen_addr, e),
+        ),
+    };
+    // Report the bound address, which differs from `listen_addr` for port 0
+    let local_addr = listener.local_addr().map_or(listen_addr.to_string(), |a| a.to_string());
+    match mode {
+        OutputMode::Human => println!("Listening on {}", local_addr),
+        OutputMode::Machine => println!("{}", serde_json::json!({ "listening": local_addr })),
+    }
+    let _ = io::stdout().flush();
+
+    let mut stream = match listener.accept() {
+        Ok((stream, _)) => io::BufWriter::new(stream),
+        Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to accept follower: {}", e)),
+    };
+
+    // The follower hanging up ends replication
+    tail_log(mode, &log_path, from, |records| {
+        for record in records {
+            // The follower can't reach this database's blob files, so send
+            // the value itself
+            let record = match record {
+                LogRecord::BlobPut { key, blob } => match Db::read_blob(db_dir, blob) {
+                    Ok(value) => LogRecord::Put { key, value },
+                    // Superseded by a later record, which is sent instead
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to read blob: {}", e)),
+                },
+                record => record,
+            };
+            record.write_to(&mut stream)?;
+        }
+        stream.flush()
+    });
+}
+
+fn handle_watch(mode: OutputMode, db_dir: &Path, from: Option<u64>) {
+    let log_path = db_dir.join("log");
+    // By default, start after the records already complete
+    let from = match from {
+        Some(from) => from,
+        None => match read_log_from(&log_path, 0) {
+            Ok(bytes) => Log::scan_complete(&bytes).1 as u64,
+            Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to read log: {}", e)),
+        },
+    };
+
+    // Stops when stdout is closed, such as by the end of a pipe
+    let mut stdout = io::stdout().lock();
+    tail_log(mode, &log_path, from, |records| {
+        for record in records {
+            let op = match record {
+                LogRecord::Delete { .. } => "delete",
+                _ => "put",
+            };
+            let key = String::from_utf8_lossy(record.key());
+            match mode {
+                OutputMode::Human => writeln!(stdout, "{} {}", op.to_uppercase(), key)?,
+                OutputMode::Machine => {
+                    writeln!(stdout, "{}", serde_json::json!({ "op": op, "key": key }))?
+                }
+            }
+        }
+        stdout.flush()
+    });
+}
+
+/// Polls the log at `log_path` for records past `offset`, passing each
+/// batch of new records to `on_records` until it fails.
+/// 
+/// Only complete records are passed; one another process is partway
+/// through appending is left for the next poll. Writers are other
+/// processes, which the log file is the only channel to. Exits once the
+/// unread bytes can't be the start of a record, since the log is corrupt.
+fn tail_log<F>(mode: OutputMode, log_path: &Path, mut offset: u64, mut on_records: F)
+where
+    F: FnMut(Vec<LogRecord>) -> io::Result<()>,
+{
+    loop {
+        let bytes = match read_log_from(log_path, offset) {
+            Ok(bytes) => bytes,
+            Err(e) => mode.fail(ErrorCode::Failed, &format!("Failed to read log: {}", e)),
+        };
+        let (spans, consumed) = Log::scan_complete(&bytes);
+        if !spans.is_empty() {
+            let records = spans.iter().map(|span| span.to_record(&bytes)).collect();
+            if on_records(records).is_err() {
+                return;
+            }
+            offset += consumed as u64;
+        }
+        // Waiting can't fix bytes that aren't the start of a record, and
+        // the records behind them would never be passed on
+        let rest = &bytes[consumed..];
+        if Log::scan(rest).is_err() && !Log::is_partial_entry(rest) {
+            mode.fail(
+                ErrorCode::Failed,
+                &format!("Malformed record at byte {} of the log", offset),
+            );
+        }
+        std::thread::sleep(TAIL_POLL_INTERVAL);
+    }
+}
+
+/// Reads the log at `path` from `offset` to its current end. A log not
+/// created yet reads as empty.
+fn read_log_from(path: &Path, offset: u64) -> io::Result<Vec<u8>> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && offset == 0 => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    if file.metadata()?.len() < offset {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("log is shorter than offset {}; it may have been compacted", offset),
+        ));
+    }
+    file.seek(io::SeekFrom::Start(offset))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Converts a replayed record's key to a string, exiting if it isn't UTF-8.
+fn record_key(mode: OutputMode, key: Vec<u8>) -> String {
+    match String::from_utf8(key) {
+        Ok(key) => key,
+        Err(_) => mode.fail(ErrorCode::InvalidInput, "Record key is not valid UTF-8"),
     }
 }
@@ -0,0 +1,168 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Running operation counters for a `Db`.
+/// 
+/// Atomic so that `get`, which only borrows the database, can count too.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    gets: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    syncs: AtomicU64,
+}
+
+impl Counters {
+    /// Counts `n` successful puts.
+    pub(crate) fn record_puts(&self, n: u64) {
+        self.puts.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Counts a successful delete.
+    pub(crate) fn record_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a get and whether it found the key.
+    pub(crate) fn record_get(&self, hit: bool) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a value cache lookup and whether it found the value.
+    pub(crate) fn record_cache_lookup(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts an fsync of the log.
+    pub(crate) fn record_sync(&self) {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Point-in-time snapshot of a database's metrics.
+/// 
+/// Counters cover operations through this handle since it was opened;
+/// gauges describe the database when the snapshot was taken.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Successful puts, counting each item of a batch.
+    pub puts: u64,
+    /// Successful deletes.
+    pub deletes: u64,
+    /// Gets, whether or not the key was found.
+    pub gets: u64,
+    /// Gets that found the key.
+    pub hits: u64,
+    /// Gets that didn't find the key.
+    pub misses: u64,
+    /// On-disk value reads served from the value cache.
+    pub cache_hits: u64,
+    /// On-disk value reads that missed the value cache and went to disk.
+    pub cache_misses: u64,
+    /// Fsyncs of the log, from `sync`, `shutdown`, or group commit.
+    pub syncs: u64,
+    /// Number of live keys.
+    pub live_keys: u64,
+    /// Size of the log in bytes.
+    pub log_bytes: u64,
+}
+
+impl Metrics {
+    /// Takes a snapshot of `counters` alongside the given gauges.
+    pub(crate) fn new(counters: &Counters, live_keys: u64, log_bytes: u64) -> Self {
+        Metrics {
+            puts: counters.puts.load(Ordering::Relaxed),
+            deletes: counters.deletes.load(Ordering::Relaxed),
+            gets: counters.gets.load(Ordering::Relaxed),
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            cache_hits: counters.cache_hits.load(Ordering::Relaxed),
+            cache_misses: counters.cache_misses.load(Ordering::Relaxed),
+            syncs: counters.syncs.load(Ordering::Relaxed),
+            live_keys,
+            log_bytes,
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    /// 
+    /// Every metric is prefixed with `docdb_`; counters end in `_total`.
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            ("docdb_puts_total", "counter", "Successful put operations.", self.puts),
+            ("docdb_deletes_total", "counter", "Successful delete operations.", self.deletes),
+            ("docdb_gets_total", "counter", "Get operations.", self.gets),
+            ("docdb_get_hits_total", "counter", "Gets that found the key.", self.hits),
+            ("docdb_get_misses_total", "counter", "Gets that did not find the key.", self.misses),
+            ("docdb_value_cache_hits_total", "counter", "Value reads served from the cache.", self.cache_hits),
+            ("docdb_value_cache_misses_total", "counter", "Value reads that missed the cache.", self.cache_misses),
+            ("docdb_syncs_total", "counter", "Fsyncs of the log.", self.syncs),
+            ("docdb_live_keys", "gauge", "Number of live keys.", self.live_keys),
+            ("docdb_log_bytes", "gauge", "Size of the log file in bytes.", self.log_bytes),
+        ];
+        
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            // Writing to a String can't fail
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_prometheus() {
+        let counters = Counters::default();
+        counters.record_puts(2);
+        counters.record_delete();
+        counters.record_get(true);
+        counters.record_get(false);
+        counters.record_get(false);
+        counters.record_cache_lookup(true);
+        counters.record_sync();
+        
+        let metrics = Metrics::new(&counters, 1, 42);
+        assert_eq!(
+            metrics,
+            Metrics {
+                puts: 2,
+                deletes: 1,
+                gets: 3,
+                hits: 1,
+                misses: 2,
+                cache_hits: 1,
+                cache_misses: 0,
+                syncs: 1,
+                live_keys: 1,
+                log_bytes: 42,
+            }
+        );
+        
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE docdb_puts_total counter\ndocdb_puts_total 2\n"));
+        assert!(text.contains("docdb_get_misses_total 2\n"));
+        assert!(text.contains("# TYPE docdb_log_bytes gauge\ndocdb_log_bytes 42\n"));
+        assert!(text.contains("docdb_value_cache_hits_total 1\n"));
+        assert!(text.contains("docdb_syncs_total 1\n"));
+        assert_eq!(text.lines().count(), 10 * 3);
+    }
+}
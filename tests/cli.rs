@@ -0,0 +1,949 @@
+use docdb::db::{Db, Options};
+use docdb::log::{Log, LogRecord};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+/// Runs the compiled `docdb` binary against `db_dir` with the given arguments.
+fn docdb(db_dir: &Path, args: &[&str]) -> Output {
+    docdb_with_stdin(db_dir, args, "")
+}
+
+/// Runs the compiled `docdb` binary, feeding `stdin` to the process.
+fn docdb_with_stdin(db_dir: &Path, args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_docdb"))
+        .arg("--db-dir")
+        .arg(db_dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn docdb");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_ref())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn lines(output: &Output) -> Vec<String> {
+    stdout(output).lines().map(str::to_string).collect()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+/// Parses the JSON error object printed to stderr in `--machine` mode.
+fn machine_error(output: &Output) -> serde_json::Value {
+    serde_json::from_slice(&output.stderr).expect("stderr should be a JSON object")
+}
+
+#[test]
+fn test_put_and_get() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    // Puts print nothing on success
+    let put = docdb(dir, &["put", "user1", r#"{"name": "Alice", "age": 30}"#]);
+    assert!(put.status.success());
+    assert!(put.stdout.is_empty());
+    assert!(put.stderr.is_empty());
+    
+    // Values are pretty-printed for people
+    let get = docdb(dir, &["get", "user1"]);
+    assert!(get.status.success());
+    assert_eq!(stdout(&get), "{\n  \"age\": 30,\n  \"name\": \"Alice\"\n}\n");
+    assert!(get.stderr.is_empty());
+}
+
+#[test]
+fn test_put_from_stdin() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let put = docdb_with_stdin(dir, &["put", "config"], "[1, 2, 3]\n");
+    assert!(put.status.success());
+    assert!(put.stdout.is_empty());
+    
+    let get = docdb(dir, &["--machine", "get", "config"]);
+    assert!(get.status.success());
+    assert_eq!(stdout(&get), "[1,2,3]\n");
+}
+
+#[test]
+fn test_put_from_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let db_dir = dir.join("db");
+    let path = dir.join("value.json");
+    std::fs::write(&path, r#"{"theme": "dark"}"#).unwrap();
+    
+    let put = docdb(&db_dir, &["put", "config", "--file", path.to_str().unwrap()]);
+    assert!(put.status.success());
+    assert!(put.stdout.is_empty());
+    
+    let get = docdb(&db_dir, &["--machine", "get", "config"]);
+    assert_eq!(stdout(&get), "{\"theme\":\"dark\"}\n");
+    
+    // File contents are validated like any other value
+    std::fs::write(&path, "{oops").unwrap();
+    let invalid = docdb(&db_dir, &["put", "bad", "--file", path.to_str().unwrap()]);
+    assert_eq!(invalid.status.code(), Some(4));
+    assert!(stderr(&invalid).contains("Invalid JSON in"));
+    
+    let missing = docdb(&db_dir, &["put", "bad", "--file", "no-such-file.json"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(stderr(&missing).contains("Failed to read no-such-file.json"));
+}
+
+#[test]
+fn test_put_rejects_conflicting_sources() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let path = dir.join("value.json");
+    std::fs::write(&path, "1").unwrap();
+    
+    let put = docdb(dir, &["put", "key", "2", "--file", path.to_str().unwrap()]);
+    assert_eq!(put.status.code(), Some(2));
+    assert!(stderr(&put).contains("cannot be used with"));
+    assert!(!dir.join("log").exists());
+}
+
+#[test]
+fn test_put_rejects_invalid_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let inline = docdb(dir, &["put", "k", "{not json"]);
+    assert_eq!(inline.status.code(), Some(4));
+    assert!(inline.stdout.is_empty());
+    assert!(stderr(&inline).starts_with("Error: Invalid JSON: "));
+    
+    let piped = docdb_with_stdin(dir, &["put", "k"], "not json either");
+    assert_eq!(piped.status.code(), Some(4));
+    assert!(stderr(&piped).starts_with("Error: Invalid JSON from stdin: "));
+    
+    // Nothing was stored
+    let get = docdb(dir, &["get", "k"]);
+    assert_eq!(get.status.code(), Some(3));
+}
+
+#[test]
+fn test_put_with_content_type() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    // Not JSON, but accepted since it's tagged as raw bytes
+    let raw = "not { json\n\tat all";
+    let args = ["put", "blob", "--content-type", "application/octet-stream"];
+    let put = docdb_with_stdin(dir, &args, raw);
+    assert!(put.status.success(), "{}", stderr(&put));
+    
+    // Emitted verbatim, without JSON parsing or a trailing newline
+    for args in [&["get", "blob"][..], &["--machine", "get", "blob"]] {
+        let get = docdb(dir, args);
+        assert!(get.status.success());
+        assert_eq!(stdout(&get), raw);
+    }
+    
+    // An explicit JSON content type is still validated
+    let invalid = docdb(dir, &["put", "k", "{oops", "--content-type", "application/json"]);
+    assert_eq!(invalid.status.code(), Some(4));
+    
+    // Overwriting without a content type goes back to JSON
+    assert!(docdb(dir, &["put", "blob", r#"{"a": 1}"#]).status.success());
+    assert_eq!(stdout(&docdb(dir, &["--machine", "get", "blob"])), "{\"a\":1}\n");
+}
+
+#[test]
+fn test_get_missing_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let get = docdb(dir, &["get", "nope"]);
+    assert_eq!(get.status.code(), Some(3));
+    assert!(get.stdout.is_empty());
+    assert_eq!(stderr(&get), "Error: Key 'nope' not found\n");
+}
+
+#[test]
+fn test_delete() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    assert!(docdb(dir, &["put", "a", "1"]).status.success());
+    assert!(docdb(dir, &["put", "b", "2"]).status.success());
+    
+    let delete = docdb(dir, &["delete", "a"]);
+    assert!(delete.status.success());
+    assert!(delete.stdout.is_empty());
+    assert_eq!(docdb(dir, &["get", "a"]).status.code(), Some(3));
+    assert_eq!(lines(&docdb(dir, &["list"])), vec!["b"]);
+    
+    // Deleting a missing key is not an error
+    assert!(docdb(dir, &["delete", "a"]).status.success());
+}
+
+#[test]
+fn test_touch() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let records = |dir: &Path| {
+        let info = docdb(dir, &["--machine", "info"]);
+        let info: serde_json::Value = serde_json::from_str(&stdout(&info)).unwrap();
+        info["records"].as_u64().unwrap()
+    };
+    
+    assert!(docdb(dir, &["put", "a", r#"{"n": 1}"#]).status.success());
+    let args = ["put", "raw", "--content-type", "text/plain"];
+    assert!(docdb_with_stdin(dir, &args, "plain text").status.success());
+    assert_eq!(records(dir), 2);
+    
+    let touch = docdb(dir, &["touch", "a"]);
+    assert!(touch.status.success(), "{}", stderr(&touch));
+    assert!(touch.stdout.is_empty());
+    assert_eq!(records(dir), 3);
+    assert_eq!(stdout(&docdb(dir, &["--machine", "get", "a"])), "{\"n\":1}\n");
+    
+    // The content type is kept
+    assert!(docdb(dir, &["touch", "raw"]).status.success());
+    assert_eq!(records(dir), 4);
+    assert_eq!(stdout(&docdb(dir, &["get", "raw"])), "plain text");
+    
+    let dry_run = docdb(dir, &["--dry-run", "touch", "a"]);
+    assert_eq!(stdout(&dry_run), "Would touch a\n");
+    assert_eq!(records(dir), 4);
+    
+    assert_eq!(docdb(dir, &["touch", "missing"]).status.code(), Some(3));
+    assert_eq!(records(dir), 4);
+}
+
+#[test]
+fn test_delete_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    for key in ["session:1", "session:2", "sessions", "user1"] {
+        assert!(docdb(dir, &["put", key, "{}"]).status.success());
+    }
+    
+    let delete = docdb(dir, &["delete", "--prefix", "session:"]);
+    assert!(delete.status.success());
+    assert!(delete.stdout.is_empty());
+    assert_eq!(lines(&docdb(dir, &["list"])), vec!["sessions", "user1"]);
+    
+    // Exactly one of a key or --prefix
+    assert_eq!(docdb(dir, &["delete"]).status.code(), Some(2));
+    assert_eq!(docdb(dir, &["delete", "user1", "--prefix", "u"]).status.code(), Some(2));
+}
+
+#[test]
+fn test_dry_run_delete() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    for key in ["session:1", "session:2", "user1"] {
+        assert!(docdb(dir, &["put", key, "{}"]).status.success());
+    }
+    let log_before = std::fs::read(dir.join("log")).unwrap();
+    
+    let dry_run = docdb(dir, &["--dry-run", "delete", "--prefix", "session:"]);
+    assert!(dry_run.status.success());
+    assert_eq!(lines(&dry_run), vec!["Would delete session:1", "Would delete session:2"]);
+    
+    let machine = docdb(dir, &["delete", "user1", "--dry-run", "--machine"]);
+    assert!(machine.status.success());
+    assert_eq!(lines(&machine), vec![r#"{"action":"delete","key":"user1"}"#]);
+    
+    // Missing keys wouldn't change anything
+    let missing = docdb(dir, &["--dry-run", "delete", "nope"]);
+    assert!(missing.status.success());
+    assert!(missing.stdout.is_empty());
+    
+    // Nothing was written
+    assert_eq!(std::fs::read(dir.join("log")).unwrap(), log_before);
+    assert_eq!(lines(&docdb(dir, &["list"])), vec!["session:1", "session:2", "user1"]);
+    
+    // Nor created
+    let fresh = temp_dir.path().join("fresh");
+    assert!(docdb(&fresh, &["--dry-run", "delete", "a"]).status.success());
+    assert!(!fresh.exists());
+}
+
+#[test]
+fn test_dry_run_replay() {
+    let source_dir = TempDir::new().unwrap();
+    let source = source_dir.path();
+    assert!(docdb(source, &["put", "a", "1"]).status.success());
+    assert!(docdb(source, &["put", "b", "2"]).status.success());
+    let dump = docdb(source, &["dump-raw"]);
+    
+    let target_dir = TempDir::new().unwrap();
+    let target = target_dir.path().join("db");
+    let dry_run = docdb_with_stdin(&target, &["--dry-run", "replay"], &dump.stdout);
+    assert!(dry_run.status.success());
+    assert_eq!(lines(&dry_run), vec!["Would put a", "Would put b"]);
+    assert!(!target.exists());
+}
+
+#[test]
+fn test_list() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let empty = docdb(dir, &["list"]);
+    assert!(empty.status.success());
+    assert_eq!(stdout(&empty), "No keys found in database\n");
+    
+    for key in ["banana", "apple", "cherry"] {
+        assert!(docdb(dir, &["put", key, "{}"]).status.success());
+    }
+    let list = docdb(dir, &["list"]);
+    assert!(list.status.success());
+    assert_eq!(lines(&list), vec!["apple", "banana", "cherry"]);
+}
+
+#[test]
+fn test_usage_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let missing_arg = docdb(dir, &["get"]);
+    assert_eq!(missing_arg.status.code(), Some(2));
+    assert!(missing_arg.stdout.is_empty());
+    assert!(stderr(&missing_arg).contains("<KEY>"));
+    
+    let unknown = docdb(dir, &["frobnicate"]);
+    assert_eq!(unknown.status.code(), Some(2));
+}
+
+#[test]
+fn test_list_pagination() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    for key in ["k5", "k1", "k4", "k2", "k3"] {
+        assert!(docdb(dir, &["put", key, "{}"]).status.success());
+    }
+    
+    let first = docdb(dir, &["list", "--limit", "3"]);
+    assert!(first.status.success());
+    assert_eq!(lines(&first), vec!["k1", "k2", "k3"]);
+    
+    let second = docdb(dir, &["list", "--limit", "3", "--after", "k3"]);
+    assert!(second.status.success());
+    assert_eq!(lines(&second), vec!["k4", "k5"]);
+    
+    let past_end = docdb(dir, &["list", "--after", "k5"]);
+    assert!(past_end.status.success());
+    assert!(lines(&past_end).is_empty());
+}
+
+#[test]
+fn test_list_sizes() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let values = [
+        ("small", "1"),
+        ("large", r#""abcdefghij""#),
+        ("medium", "[1, 2]"),
+        ("tie", "2"),
+    ];
+    for (key, value) in values {
+        assert!(docdb(dir, &["put", key, value]).status.success());
+    }
+    
+    let by_key = docdb(dir, &["list", "--sizes"]);
+    assert!(by_key.status.success());
+    assert_eq!(lines(&by_key), vec!["large\t12", "medium\t6", "small\t1", "tie\t1"]);
+    
+    // Largest first; equal sizes stay in key order
+    let by_size = docdb(dir, &["list", "--sizes", "--sort-by-size", "--limit", "3"]);
+    assert!(by_size.status.success());
+    assert_eq!(lines(&by_size), vec!["large\t12", "medium\t6", "small\t1"]);
+    
+    let machine = docdb(dir, &["--machine", "list", "--sizes", "--glob", "m*"]);
+    assert_eq!(lines(&machine), vec![r#"{"bytes":6,"key":"medium"}"#]);
+    
+    // Sorting by size needs --sizes, and can't page by key
+    let no_sizes = docdb(dir, &["list", "--sort-by-size"]);
+    assert_eq!(no_sizes.status.code(), Some(2));
+    let with_after = docdb(dir, &["list", "--sizes", "--sort-by-size", "--after", "a"]);
+    assert_eq!(with_after.status.code(), Some(2));
+}
+
+#[test]
+fn test_list_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let values = [
+        ("b", r#"{"name": "Ada", "tags": [1, 2]}"#),
+        ("a", "42"),
+        ("c", r#""a long string value""#),
+    ];
+    for (key, value) in values {
+        assert!(docdb(dir, &["put", key, value]).status.success());
+    }
+    let raw = docdb(dir, &["put", "d", "plain text", "--content-type", "text/plain"]);
+    assert!(raw.status.success(), "{}", stderr(&raw));
+    
+    // Sorted by key; JSON compacted, other content types verbatim
+    let list = docdb(dir, &["list", "--values"]);
+    assert!(list.status.success(), "{}", stderr(&list));
+    assert_eq!(
+        lines(&list),
+        vec![
+            "a: 42",
+            r#"b: {"name":"Ada","tags":[1,2]}"#,
+            r#"c: "a long string value""#,
+            "d: plain text",
+        ]
+    );
+    
+    let pretty = docdb(dir, &["list", "--values", "--pretty", "--glob", "b"]);
+    assert_eq!(
+        lines(&pretty),
+        vec!["b: {", r#"  "name": "Ada","#, r#"  "tags": ["#, "    1,", "    2", "  ]", "}"]
+    );
+    
+    let truncated = docdb(dir, &["list", "--values", "--max-value-width", "8"]);
+    assert_eq!(
+        lines(&truncated),
+        vec!["a: 42", r#"b: {"name":..."#, r#"c: "a long ..."#, "d: plain te..."]
+    );
+    
+    let machine = docdb(dir, &["--machine", "list", "--values", "--limit", "2"]);
+    assert_eq!(
+        lines(&machine),
+        vec![r#"{"key":"a","value":42}"#, r#"{"key":"b","value":{"name":"Ada","tags":[1,2]}}"#]
+    );
+    
+    let with_sizes = docdb(dir, &["list", "--values", "--sizes"]);
+    assert_eq!(with_sizes.status.code(), Some(2));
+    let without_values = docdb(dir, &["list", "--pretty"]);
+    assert_eq!(without_values.status.code(), Some(2));
+}
+
+#[test]
+fn test_list_sort_by_field() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let values = [
+        ("a", r#"{"created_at": 30}"#),
+        ("b", r#"{"created_at": 5}"#),
+        ("c", r#"{"created_at": "yesterday"}"#),
+        ("d", r#"{"other": 1}"#),
+        ("e", r#"{"created_at": 5.5}"#),
+        ("f", r#"{"created_at": 5}"#),
+        ("g", r#"{"created_at": null}"#),
+    ];
+    for (key, value) in values {
+        assert!(docdb(dir, &["put", key, value]).status.success());
+    }
+    
+    // Numbers in numeric order, then strings, then other values, then keys
+    // without the field; ties stay in key order
+    let sorted = docdb(dir, &["list", "--sort-by-field", "/created_at"]);
+    assert!(sorted.status.success(), "{}", stderr(&sorted));
+    assert_eq!(lines(&sorted), vec!["b", "f", "e", "a", "c", "g", "d"]);
+    
+    let limited = docdb(dir, &["list", "--sort-by-field", "/created_at", "--limit", "2"]);
+    assert_eq!(lines(&limited), vec!["b", "f"]);
+    
+    let with_size = docdb(dir, &["list", "--sizes", "--sort-by-size", "--sort-by-field", "/x"]);
+    assert_eq!(with_size.status.code(), Some(2));
+}
+
+#[test]
+fn test_warns_about_binary_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    {
+        // As written by another tool that allows binary keys
+        let mut log = Log::open(dir.join("log")).unwrap();
+        log.put(b"text", b"1").unwrap();
+        log.put(&[0xff, 0x00], b"2").unwrap();
+    }
+    
+    let list = docdb(dir, &["list"]);
+    assert!(list.status.success());
+    assert_eq!(lines(&list), vec!["text"]);
+    assert!(stderr(&list).contains("Skipped 1 log record(s) whose keys are not valid UTF-8"));
+    
+    let get = docdb(dir, &["get", "missing"]);
+    assert_eq!(get.status.code(), Some(3));
+    assert!(stderr(&get).starts_with("Warning: Skipped 1"));
+    
+    let machine = docdb(dir, &["--machine", "list"]);
+    assert!(machine.status.success());
+    assert!(machine_error(&machine)["warning"].as_str().unwrap().contains("not valid UTF-8"));
+    
+    // Databases without binary keys open quietly
+    let clean_dir = TempDir::new().unwrap();
+    assert!(docdb(clean_dir.path(), &["put", "a", "1"]).status.success());
+    assert!(stderr(&docdb(clean_dir.path(), &["list"])).is_empty());
+}
+
+#[test]
+fn test_verbose_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    for (key, value) in [("a", "1"), ("b", "2"), ("a", "3")] {
+        assert!(docdb(dir, &["put", key, value]).status.success());
+    }
+    
+    let verbose = docdb(dir, &["--verbose-open", "get", "a"]);
+    assert!(verbose.status.success());
+    assert_eq!(stdout(&verbose), "3\n");
+    let stats = stderr(&verbose);
+    assert!(stats.starts_with("Opened database: replayed 3 record(s) in "), "{}", stats);
+    assert!(stats.ends_with(" ms, 2 live key(s)\n"), "{}", stats);
+    
+    let machine = docdb(dir, &["--machine", "list", "--verbose-open"]);
+    assert_eq!(lines(&machine), vec![r#""a""#, r#""b""#]);
+    let stats: serde_json::Value = serde_json::from_slice(&machine.stderr).unwrap();
+    assert_eq!(stats["open_stats"]["records_replayed"], 3);
+    assert_eq!(stats["open_stats"]["live_keys"], 2);
+    assert!(stats["open_stats"]["replay_ms"].is_number());
+    
+    // Without the flag, opening is quiet
+    let quiet = docdb(dir, &["get", "a"]);
+    assert!(quiet.stderr.is_empty());
+}
+
+#[test]
+fn test_shell() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    assert!(docdb(dir, &["put", "existing", "1"]).status.success());
+    
+    let script = "\
+        put user1 {\"name\": \"Ada Lovelace\"}
+        put user2 [1, 2]
+        
+        # Comments and blank lines are ignored
+        get user1
+        list
+        delete user2
+        get user2
+        put bad {oops
+        frobnicate
+        get existing
+    ";
+    let shell = docdb_with_stdin(dir, &["shell"], script);
+    assert_eq!(
+        lines(&shell),
+        vec![
+            "{",
+            r#"  "name": "Ada Lovelace""#,
+            "}",
+            "existing",
+            "user1",
+            "user2",
+            "1",
+        ]
+    );
+    let errors: Vec<&str> = std::str::from_utf8(&shell.stderr).unwrap().lines().collect();
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[0], "Error: Key 'user2' not found");
+    assert!(errors[1].starts_with("Error: Invalid JSON: "));
+    assert!(errors[2].starts_with("Error: Unknown command 'frobnicate'"));
+    // The last failure sets the exit status
+    assert_eq!(shell.status.code(), Some(2));
+    
+    // The session's writes were kept
+    let list = docdb(dir, &["--machine", "list"]);
+    assert_eq!(lines(&list), vec![r#""existing""#, r#""user1""#]);
+    
+    let machine = docdb_with_stdin(dir, &["--machine", "shell"], "get user1\nlist\n");
+    assert!(machine.status.success());
+    assert_eq!(
+        lines(&machine),
+        vec![r#"{"name":"Ada Lovelace"}"#, r#""existing""#, r#""user1""#]
+    );
+    
+    // A dry run reports puts and deletes of live keys but writes nothing
+    let log_before = std::fs::read(dir.join("log")).unwrap();
+    let script = "put user2 {}\ndelete user1\ndelete nope\nget user2\nlist\n";
+    let dry_run = docdb_with_stdin(dir, &["--dry-run", "shell"], script);
+    assert_eq!(dry_run.status.code(), Some(3));
+    assert_eq!(
+        lines(&dry_run),
+        vec!["Would put user2", "Would delete user1", "existing", "user1"]
+    );
+    assert_eq!(std::fs::read(dir.join("log")).unwrap(), log_before);
+    
+    let machine = docdb_with_stdin(dir, &["--machine", "--dry-run", "shell"], "delete user1\n");
+    assert!(machine.status.success());
+    assert_eq!(lines(&machine), vec![r#"{"action":"delete","key":"user1"}"#]);
+}
+
+#[test]
+fn test_creates_db_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("nested").join("db");
+    
+    assert!(docdb(&dir, &["put", "a", "1"]).status.success());
+    assert!(dir.join("log").is_file());
+    
+    let get = docdb(&dir, &["get", "a"]);
+    assert!(get.status.success());
+    assert_eq!(stdout(&get), "1\n");
+}
+
+#[test]
+fn test_no_create_requires_existing_db_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("typo");
+    
+    for args in [&["--no-create", "put", "a", "1"][..], &["--no-create", "info"]] {
+        let output = docdb(&dir, args);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8(output.stderr).unwrap().contains("does not exist"));
+        assert!(!dir.exists());
+    }
+    
+    let machine = docdb(&dir, &["--no-create", "--machine", "list"]);
+    assert_eq!(machine.status.code(), Some(1));
+    assert_eq!(machine_error(&machine)["code"], "failed");
+    
+    // An existing directory is used as usual
+    std::fs::create_dir(&dir).unwrap();
+    assert!(docdb(&dir, &["--no-create", "put", "a", "1"]).status.success());
+    assert_eq!(stdout(&docdb(&dir, &["--no-create", "get", "a"])), "1\n");
+}
+
+#[test]
+fn test_dump_raw_and_replay() {
+    let source_dir = TempDir::new().unwrap();
+    let source = source_dir.path();
+    assert!(docdb(source, &["put", "a", "1"]).status.success());
+    assert!(docdb(source, &["put", "b", r#"{"n": [1, 2]}"#]).status.success());
+    assert!(docdb(source, &["put", "gone", "3"]).status.success());
+    assert!(docdb(source, &["delete", "gone"]).status.success());
+    let args = ["put", "raw", "--content-type", "text/plain"];
+    assert!(docdb_with_stdin(source, &args, "plain text").status.success());
+    
+    let dump = docdb(source, &["dump-raw"]);
+    assert!(dump.status.success());
+    
+    let target_dir = TempDir::new().unwrap();
+    let target = target_dir.path();
+    // Replayed records overwrite what's there
+    assert!(docdb(target, &["put", "a", "\"old\""]).status.success());
+    let replay = docdb_with_stdin(target, &["replay"], &dump.stdout);
+    assert!(replay.status.success(), "{}", stderr(&replay));
+    assert!(replay.stdout.is_empty());
+    
+    assert_eq!(lines(&docdb(target, &["list"])), vec!["a", "b", "raw"]);
+    for key in ["a", "b", "raw"] {
+        assert_eq!(stdout(&docdb(target, &["get", key])), stdout(&docdb(source, &["get", key])));
+    }
+    assert_eq!(stdout(&docdb(target, &["get", "raw"])), "plain text");
+    
+    // A truncated stream is rejected
+    let truncated = &dump.stdout[..dump.stdout.len() - 1];
+    let bad = docdb_with_stdin(target, &["--machine", "replay"], truncated);
+    assert_eq!(bad.status.code(), Some(4));
+    assert_eq!(machine_error(&bad)["code"], "invalid_input");
+}
+
+#[test]
+fn test_follow() {
+    let primary_dir = TempDir::new().unwrap();
+    let primary = primary_dir.path();
+    assert!(docdb(primary, &["put", "a", "1"]).status.success());
+    assert!(docdb(primary, &["put", "b", "[2]"]).status.success());
+    let dump = docdb(primary, &["dump-raw"]);
+    assert!(dump.status.success());
+    
+    // Stand in for the primary: serve the records, then hang up
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let records = dump.stdout.clone();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&records).unwrap();
+    });
+    
+    let follower_dir = TempDir::new().unwrap();
+    let follower = follower_dir.path();
+    let follow = docdb(follower, &["follow", &addr]);
+    server.join().unwrap();
+    assert!(follow.status.success(), "{}", stderr(&follow));
+    assert_eq!(lines(&docdb(follower, &["list"])), vec!["a", "b"]);
+    assert_eq!(stdout(&docdb(follower, &["get", "b"])), stdout(&docdb(primary, &["get", "b"])));
+    
+    // The stand-in has hung up, so nothing is listening any more
+    let refused = docdb(follower, &["follow", &addr]);
+    assert_eq!(refused.status.code(), Some(1));
+}
+
+#[test]
+fn test_replicate() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    assert!(docdb(dir, &["put", "a", "1"]).status.success());
+    assert!(docdb(dir, &["delete", "a"]).status.success());
+    {
+        // Values stored externally, one of them since overwritten
+        let options = Options {
+            external_value_bytes: Some(4),
+            ..Options::default()
+        };
+        let mut db = Db::open_with_options(dir, options).unwrap();
+        db.put("big", b"\"old value\"").unwrap();
+        db.put("big", b"\"new value\"").unwrap();
+        db.close().unwrap();
+    }
+    
+    let mut primary = Command::new(env!("CARGO_BIN_EXE_docdb"))
+        .arg("--db-dir")
+        .arg(dir)
+        .args(["replicate", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut banner = String::new();
+    BufReader::new(primary.stdout.take().unwrap()).read_line(&mut banner).unwrap();
+    let addr = banner.trim().strip_prefix("Listening on ").unwrap();
+    
+    let mut follower = TcpStream::connect(addr).unwrap();
+    follower.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut next = || LogRecord::read_from(&mut follower).unwrap().unwrap();
+    
+    // The backlog, then a record written after the follower connected
+    assert_eq!(next(), LogRecord::Put { key: b"a".to_vec(), value: b"1".to_vec() });
+    assert_eq!(next(), LogRecord::Delete { key: b"a".to_vec() });
+    // Blob values are sent inline; the removed one is skipped
+    let big = LogRecord::Put { key: b"big".to_vec(), value: b"\"new value\"".to_vec() };
+    assert_eq!(next(), big);
+    assert!(docdb(dir, &["put", "b", "2"]).status.success());
+    assert_eq!(next(), LogRecord::Put { key: b"b".to_vec(), value: b"2".to_vec() });
+    
+    primary.kill().unwrap();
+    primary.wait().unwrap();
+}
+
+#[test]
+fn test_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    assert!(docdb(dir, &["put", "before", "1"]).status.success());
+    
+    let mut watch = Command::new(env!("CARGO_BIN_EXE_docdb"))
+        .arg("--db-dir")
+        .arg(dir)
+        .args(["watch", "--from", "0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    assert!(docdb(dir, &["put", "a", "2"]).status.success());
+    assert!(docdb(dir, &["delete", "before"]).status.success());
+    assert!(docdb(dir, &["put", "b", "3"]).status.success());
+    
+    // Changes print in log order, whether written before or while watching
+    let mut events = BufReader::new(watch.stdout.take().unwrap()).lines();
+    let mut next = || events.next().unwrap().unwrap();
+    assert_eq!(next(), "PUT before");
+    assert_eq!(next(), "PUT a");
+    assert_eq!(next(), "DELETE before");
+    assert_eq!(next(), "PUT b");
+    
+    watch.kill().unwrap();
+    watch.wait().unwrap();
+}
+
+#[test]
+fn test_watch_malformed_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    assert!(docdb(dir, &["put", "before", "1"]).status.success());
+    let good_len = std::fs::metadata(dir.join("log")).unwrap().len();
+    let mut log = std::fs::OpenOptions::new().append(true).open(dir.join("log")).unwrap();
+    log.write_all(&[0x42, 0, 0, 0]).unwrap();
+    drop(log);
+    
+    // The records before the corruption are reported, then watch gives up
+    // rather than waiting for bytes that can never complete a record
+    let watch = docdb(dir, &["watch", "--from", "0"]);
+    assert_eq!(lines(&watch), vec!["PUT before"]);
+    assert_eq!(watch.status.code(), Some(1));
+    let expected = format!("Error: Malformed record at byte {} of the log\n", good_len);
+    assert_eq!(stderr(&watch), expected);
+}
+
+#[test]
+fn test_export_csv() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    assert!(docdb(dir, &["put", "quote", r#"{"text": "a, \"b\""}"#]).status.success());
+    assert!(docdb(dir, &["put", "num", "42"]).status.success());
+    
+    let export = docdb(dir, &["export", "--format", "csv"]);
+    assert!(export.status.success(), "{}", stderr(&export));
+    assert_eq!(
+        lines(&export),
+        vec!["key,value", "num,42", r#"quote,"{""text"": ""a, \""b\""""}""#]
+    );
+    
+    let unknown = docdb(dir, &["export", "--format", "xml"]);
+    assert_eq!(unknown.status.code(), Some(2));
+}
+
+#[test]
+fn test_list_glob() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    for key in ["session:1:active", "session:2:idle", "session:3:active", "user1"] {
+        assert!(docdb(dir, &["put", key, "{}"]).status.success());
+    }
+    
+    let active = docdb(dir, &["list", "--glob", "session:*:active"]);
+    assert!(active.status.success());
+    assert_eq!(lines(&active), vec!["session:1:active", "session:3:active"]);
+    
+    // Combines with pagination
+    let page = docdb(dir, &["list", "--glob", "session:?:*", "--after", "session:1:active", "--limit", "1"]);
+    assert_eq!(lines(&page), vec!["session:2:idle"]);
+    
+    let none = docdb(dir, &["list", "--glob", "account:*"]);
+    assert!(none.status.success());
+    assert!(none.stdout.is_empty());
+}
+
+#[test]
+fn test_list_deleted() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    for key in ["a", "b", "c"] {
+        assert!(docdb(dir, &["put", key, "{}"]).status.success());
+    }
+    assert!(docdb(dir, &["delete", "b"]).status.success());
+    
+    let all = docdb(dir, &["list", "--deleted"]);
+    assert!(all.status.success());
+    assert_eq!(lines(&all), vec!["a", "b [deleted]", "c"]);
+    
+    let only = docdb(dir, &["list", "--only-deleted"]);
+    assert_eq!(lines(&only), vec!["b [deleted]"]);
+    
+    // A deleted key that is put again is live
+    assert!(docdb(dir, &["put", "b", "{}"]).status.success());
+    let revived = docdb(dir, &["list", "--deleted"]);
+    assert_eq!(lines(&revived), vec!["a", "b", "c"]);
+    
+    let none = docdb(dir, &["list", "--only-deleted"]);
+    assert_eq!(lines(&none), vec!["No keys found in database"]);
+}
+
+#[test]
+fn test_info_reports_counts() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    assert!(docdb(dir, &["put", "a", "1"]).status.success());
+    assert!(docdb(dir, &["put", "b", "2"]).status.success());
+    assert!(docdb(dir, &["put", "a", "3"]).status.success());
+    assert!(docdb(dir, &["delete", "b"]).status.success());
+    
+    let output = docdb(dir, &["info"]);
+    assert!(output.status.success());
+    let info = lines(&output);
+    assert!(info.contains(&"Format version: 1".to_string()));
+    assert!(info.contains(&"Records: 4".to_string()));
+    assert!(info.contains(&"Live keys: 1".to_string()));
+    assert!(info.contains(&"Log status: clean".to_string()));
+}
+
+#[test]
+fn test_info_reports_damaged_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    assert!(docdb(dir, &["put", "a", "1"]).status.success());
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join("log"))
+        .unwrap();
+    log.write_all(&[0, 9, 0]).unwrap();
+    
+    let output = docdb(dir, &["info"]);
+    assert!(output.status.success());
+    let info = lines(&output);
+    assert!(info.contains(&"Records: 1".to_string()));
+    assert!(info.contains(&"Live keys: 1".to_string()));
+    assert!(info.contains(&"Log status: damaged (1 corrupt region(s), 3 bytes unparsable)".to_string()));
+}
+
+#[test]
+fn test_machine_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let put = docdb(dir, &["--machine", "put", "b", r#"{ "n": [1, 2] }"#]);
+    assert!(put.status.success());
+    assert!(docdb(dir, &["put", "a", r#""text""#]).status.success());
+    
+    // Compact JSON values
+    let get = docdb(dir, &["--machine", "get", "b"]);
+    assert!(get.status.success());
+    assert_eq!(stdout(&get), "{\"n\":[1,2]}\n");
+    
+    // JSON lines for lists, including with the flag after the subcommand
+    let list = docdb(dir, &["list", "--machine"]);
+    assert!(list.status.success());
+    assert_eq!(lines(&list), vec![r#""a""#, r#""b""#]);
+    
+    let empty = docdb(dir, &["--machine", "list", "--after", "b"]);
+    assert!(empty.status.success());
+    assert!(stdout(&empty).is_empty());
+    
+    let info = docdb(dir, &["--machine", "info"]);
+    assert!(info.status.success());
+    let info: serde_json::Value = serde_json::from_str(&stdout(&info)).unwrap();
+    assert_eq!(info["live_keys"], 2);
+    assert_eq!(info["log_status"], "clean");
+}
+
+#[test]
+fn test_machine_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    
+    let missing = docdb(dir, &["--machine", "get", "nope"]);
+    assert_eq!(missing.status.code(), Some(3));
+    assert!(missing.stdout.is_empty());
+    let error = machine_error(&missing);
+    assert_eq!(error["code"], "not_found");
+    assert!(error["message"].as_str().unwrap().contains("nope"));
+    
+    let invalid = docdb(dir, &["--machine", "put", "k", "{not json"]);
+    assert_eq!(invalid.status.code(), Some(4));
+    assert_eq!(machine_error(&invalid)["code"], "invalid_input");
+    
+    let usage = docdb(dir, &["--machine", "frobnicate"]);
+    assert_eq!(usage.status.code(), Some(2));
+    assert_eq!(machine_error(&usage)["code"], "usage");
+    
+    // Exit codes are the same without --machine; only the format differs
+    let human = docdb(dir, &["get", "nope"]);
+    assert_eq!(human.status.code(), Some(3));
+    assert!(String::from_utf8(human.stderr).unwrap().starts_with("Error: Key 'nope' not found"));
+}